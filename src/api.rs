@@ -0,0 +1,372 @@
+//! # Control API Module
+//!
+//! This module serves a small HTTP API to inspect and control the daemon (see [`crate::daemon`]).
+//! Without a token it only listens on loopback addresses such as `127.0.0.1:7878`,
+//! and rejects requests whose `Host` or `Origin` header names another host, so web pages cannot use it.
+//! With a token (`--token-file` or the `FOLDER_SYNC_API_TOKEN` environment variable), every request must send it
+//! as `Authorization: Bearer <token>`, and any address can be used.
+//!
+//! | Method | Path | Action |
+//! |---|---|---|
+//! | `GET` | `/jobs` | Status of every job |
+//! | `GET` | `/jobs/<job>` | Status of a job |
+//! | `POST` | `/jobs/<job>/run` | Start the job now, or after the current run |
+//! | `POST` | `/jobs/<job>/pause` | Skip scheduled runs of the job |
+//! | `POST` | `/jobs/<job>/resume` | Resume scheduled runs of the job |
+//! | `POST` | `/jobs/<job>/cancel` | Cancel the current run of the job |
+//!
+//! `<job>` is the job key of the state file (the job name, or `<source> -> <destination>`), percent-encoded.
+//! Responses are JSON; errors are `{"error": "<message>"}` with a 4xx status (401 without a valid token,
+//! 403 for a foreign `Host` or `Origin` without a token).
+
+use crate::daemon::{lock, Daemon, JobControl};
+use crate::messages::*;
+use crate::storage::percent_decode;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Environment variable holding the API token when no token file is given.
+const TOKEN_VARIABLE: &str = "FOLDER_SYNC_API_TOKEN";
+
+/// Status of a job returned by the API.
+#[derive(Serialize)]
+struct JobStatus {
+    job : String,
+    /// `"running"`, `"paused"` or `"idle"`.
+    status : &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_run : Option<String>,
+    /// Files processed and total of the current run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress : Option<Progress>,
+    paused : bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_run : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_finished : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error : Option<String>,
+}
+
+/// Progress of a running job.
+#[derive(Serialize)]
+struct Progress {
+    done : u64,
+    total : u64,
+}
+
+/// Reads the API token from a file, or from the `FOLDER_SYNC_API_TOKEN` environment variable.
+///
+/// Returns `Ok(None)` if no file is given and the variable is not set.
+///
+/// # Errors
+///
+/// * Returns an error if the file cannot be read or is empty.
+pub fn read_token(file : Option<&Path>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let token = match file {
+        Some(file) => {
+            let token = fs::read_to_string(file)
+                .map_err(|e| ERR_READ_SECRET_FILE.replacen("{}", &file.display().to_string(), 1).replacen("{}", &e.to_string(), 1))?;
+            if token.trim().is_empty() {
+                return Err(ERR_API_TOKEN_EMPTY.replace("{}", &file.display().to_string()).into());
+            }
+            token
+        }
+        None => std::env::var(TOKEN_VARIABLE).unwrap_or_default(),
+    };
+    let token = token.trim();
+    Ok((!token.is_empty()).then(|| token.to_string()))
+}
+
+/// Starts the API server on a background thread.
+///
+/// # Arguments
+///
+/// * `address` - The address to listen on, for example `127.0.0.1:7878`.
+/// * `token` - The token requests must send, or `None` to accept requests without one on a loopback address.
+/// * `daemon` - The runtime state of the daemon.
+///
+/// # Returns
+///
+/// Returns `Ok(SocketAddr)` with the bound address, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if no token is given and the address is not a loopback address.
+/// * Returns an error if the address cannot be bound.
+pub fn serve_api(address : &str, token : Option<String>, daemon : Arc<Mutex<Daemon>>) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let listen_error = |e : &dyn std::fmt::Display| ERR_API_LISTEN.replacen("{}", address, 1).replacen("{}", &e.to_string(), 1);
+    // トークンが無い場合は、他のマシンから操作できないようにループバックのアドレスだけで待ち受ける
+    if token.is_none() {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs().map_err(|e| listen_error(&e))?.collect();
+        if addresses.is_empty() || addresses.iter().any(|address| !address.ip().is_loopback()) {
+            return Err(ERR_API_TOKEN_REQUIRED.replace("{}", address).into());
+        }
+    }
+    let server = Server::http(address).map_err(|e| listen_error(&e))?;
+    let bound = server.server_addr().to_ip().ok_or_else(|| listen_error(&"not an IP address"))?;
+    info!("Control API listening on {}", bound);
+    let token_hash = token.map(|token| Sha256::digest(token.as_bytes()));
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let foreign = if token_hash.is_none() { foreign_host(&request) } else { None };
+            let (status, body) = if token_hash.as_ref().is_some_and(|hash| !authorized(&request, hash)) {
+                warn!("Unauthorized API request: {} {}", request.method(), request.url());
+                (401, json!({ "error": ERR_API_UNAUTHORIZED }))
+            } else if let Some(host) = foreign {
+                // ブラウザで開いたページからのリクエストや DNS リバインディングを拒否する
+                warn!("API request from foreign host {} : {} {}", host, request.method(), request.url());
+                (403, json!({ "error": ERR_API_FOREIGN_ORIGIN.replace("{}", &host) }))
+            } else {
+                handle(&request, &daemon)
+            };
+            let response = Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(Header::from_bytes("Content-Type", "application/json").expect("valid header"));
+            if let Err(e) = request.respond(response) {
+                warn!("Failed to send API response: {}", e);
+            }
+        }
+    });
+
+    Ok(bound)
+}
+
+/// Returns `true` if the request sends the token as `Authorization: Bearer <token>`.
+///
+/// The hashes of the tokens are compared in constant time, so the comparison reveals neither the token nor its length.
+fn authorized(request : &Request, token_hash : &[u8]) -> bool {
+    let Some(header) = request.headers().iter().find(|header| header.field.equiv("Authorization")) else {
+        return false;
+    };
+    let Some(sent) = header.value.as_str().strip_prefix("Bearer ") else {
+        return false;
+    };
+    let sent_hash = Sha256::digest(sent.trim().as_bytes());
+    sent_hash.iter().zip(token_hash).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Returns the `Host` or `Origin` header of the request if it names a host that is not a loopback address.
+fn foreign_host(request : &Request) -> Option<String> {
+    request.headers().iter()
+        .filter_map(|header| {
+            let value = header.value.as_str();
+            if header.field.equiv("Host") {
+                Some((value, value))
+            } else if header.field.equiv("Origin") {
+                // Origin は scheme://host[:port] の形式で、開いたファイルなどからは "null" になる
+                Some((value, value.split_once("://").map_or("", |(_, authority)| authority)))
+            } else {
+                None
+            }
+        })
+        .find(|(_, authority)| !is_loopback_authority(authority))
+        .map(|(value, _)| value.to_string())
+}
+
+/// Returns `true` if the host of `host[:port]` is `localhost` or a loopback address.
+fn is_loopback_authority(authority : &str) -> bool {
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => authority.split([':', '/']).next().unwrap_or_default(),
+    };
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Handles a request and returns the status code and the JSON body of the response.
+fn handle(request : &Request, daemon : &Mutex<Daemon>) -> (u16, Value) {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let method = request.method();
+    info!("API request: {} {}", method, path);
+
+    let mut daemon = lock(daemon);
+    match (method, segments.as_slice()) {
+        (Method::Get, ["jobs"]) => {
+            let statuses: Vec<JobStatus> = daemon.jobs.iter().map(|job| job_status(job, &daemon)).collect();
+            (200, json!(statuses))
+        }
+        (Method::Get, ["jobs", key]) => match find_job(&daemon, key) {
+            Some(index) => (200, json!(job_status(&daemon.jobs[index], &daemon))),
+            None => not_found(key),
+        },
+        (Method::Post, ["jobs", key, action]) => {
+            let Some(index) = find_job(&daemon, key) else {
+                return not_found(key);
+            };
+            let job = &mut daemon.jobs[index];
+            match *action {
+                "run" => job.triggered = true,
+                "pause" => job.paused = true,
+                "resume" => job.paused = false,
+                "cancel" if job.running => job.cancel.store(true, Ordering::Relaxed),
+                "cancel" => return (409, json!({ "error": ERR_API_NOT_RUNNING.replace("{}", key) })),
+                _ => return (404, json!({ "error": ERR_API_NOT_FOUND.replace("{}", path) })),
+            }
+            info!("API {} : {}", action, key);
+            (200, json!({ "ok": true }))
+        }
+        (_, ["jobs", ..]) => (405, json!({ "error": ERR_API_METHOD.replace("{}", method.as_str()) })),
+        _ => (404, json!({ "error": ERR_API_NOT_FOUND.replace("{}", path) })),
+    }
+}
+
+/// Returns the index of the job with the given key.
+fn find_job(daemon : &Daemon, key : &str) -> Option<usize> {
+    daemon.jobs.iter().position(|job| job.key == key)
+}
+
+/// Returns the response for an unknown job.
+fn not_found(key : &str) -> (u16, Value) {
+    (404, json!({ "error": ERR_JOB_NOT_FOUND.replace("{}", key) }))
+}
+
+/// Builds the status of a job.
+fn job_status(job : &JobControl, daemon : &Daemon) -> JobStatus {
+    let state = daemon.state.jobs.get(&job.key).cloned().unwrap_or_default();
+    let status = if job.running {
+        "running"
+    } else if job.paused {
+        "paused"
+    } else {
+        "idle"
+    };
+    JobStatus {
+        job : job.key.clone(),
+        status,
+        schedule : job.schedule.clone(),
+        next_run : job.next_run.map(|time| time.to_rfc3339()),
+        progress : job.progress.as_ref().map(|bar| Progress { done: bar.position(), total: bar.length().unwrap_or(0) }),
+        paused : job.paused,
+        last_run : state.last_run,
+        last_finished : state.last_finished,
+        last_error : state.last_error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::DaemonState;
+    use std::sync::atomic::AtomicBool;
+    use ureq::Agent;
+
+    fn daemon() -> Arc<Mutex<Daemon>> {
+        Arc::new(Mutex::new(Daemon {
+            jobs : vec![JobControl {
+                key : "photos".to_string(),
+                schedule : Some("0 3 * * *".to_string()),
+                next_run : None,
+                running : false,
+                paused : false,
+                triggered : false,
+                cancel : Arc::new(AtomicBool::new(false)),
+                progress : None,
+            }],
+            state : DaemonState::default(),
+        }))
+    }
+
+    /// Sends a request and returns the status code and the JSON body.
+    fn send(method : &str, url : &str, token : Option<&str>) -> (u16, Value) {
+        let authorization = token.map(|token| format!("Bearer {}", token));
+        send_with_headers(method, url, &authorization.iter().map(|value| ("Authorization", value.as_str())).collect::<Vec<_>>())
+    }
+
+    /// Sends a request with extra headers and returns the status code and the JSON body.
+    fn send_with_headers(method : &str, url : &str, headers : &[(&str, &str)]) -> (u16, Value) {
+        let agent: Agent = Agent::config_builder().http_status_as_error(false).build().into();
+        let mut request = ureq::http::Request::builder().method(method).uri(url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut response = agent.run(request.body(()).unwrap()).unwrap();
+        let body = response.body_mut().read_to_string().unwrap();
+        (response.status().as_u16(), serde_json::from_str(&body).unwrap())
+    }
+
+    #[test]
+    fn refuses_non_loopback_address_without_token() {
+        assert!(serve_api("0.0.0.0:0", None, daemon()).is_err());
+    }
+
+    #[test]
+    fn serves_jobs_on_loopback_without_token() {
+        let address = serve_api("127.0.0.1:0", None, daemon()).unwrap();
+        let (status, body) = send("GET", &format!("http://{}/jobs", address), None);
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["job"], "photos");
+        assert_eq!(body[0]["status"], "idle");
+    }
+
+    #[test]
+    fn rejects_foreign_hosts_and_origins_without_token() {
+        let daemon = daemon();
+        let address = serve_api("127.0.0.1:0", None, Arc::clone(&daemon)).unwrap();
+        let url = format!("http://{}/jobs/photos/run", address);
+
+        for headers in [
+            &[("Origin", "https://attacker.example")][..],
+            &[("Origin", "null")],
+            &[("Origin", "http://127.0.0.1.attacker.example")],
+            &[("Host", "attacker.example:7878")],
+        ] {
+            let (status, body) = send_with_headers("POST", &url, headers);
+            assert_eq!(status, 403, "{:?}", headers);
+            assert!(body["error"].as_str().unwrap().contains(headers[0].1), "{:?}", headers);
+        }
+        assert!(!lock(&daemon).jobs[0].triggered);
+
+        for headers in [
+            &[("Origin", "http://localhost:3000")][..],
+            &[("Origin", "http://127.0.0.1")],
+            &[("Origin", "http://[::1]:7878"), ("Host", "localhost:7878")],
+        ] {
+            assert_eq!(send_with_headers("POST", &url, headers).0, 200, "{:?}", headers);
+        }
+        assert!(lock(&daemon).jobs[0].triggered);
+
+        // トークンがあれば、他のページからのリクエストもトークンで判定する
+        let address = serve_api("127.0.0.1:0", Some("s3cret".to_string()), daemon).unwrap();
+        let url = format!("http://{}/jobs/photos/pause", address);
+        assert_eq!(send_with_headers("POST", &url, &[("Origin", "https://attacker.example")]).0, 401);
+        assert_eq!(send_with_headers("POST", &url, &[("Origin", "https://dashboard.example"), ("Authorization", "Bearer s3cret")]).0, 200);
+    }
+
+    #[test]
+    fn requires_token_and_controls_jobs() {
+        let daemon = daemon();
+        let address = serve_api("127.0.0.1:0", Some("s3cret".to_string()), Arc::clone(&daemon)).unwrap();
+        let url = |path : &str| format!("http://{}{}", address, path);
+
+        assert_eq!(send("GET", &url("/jobs"), None).0, 401);
+        assert_eq!(send("GET", &url("/jobs"), Some("wrong")).0, 401);
+        assert_eq!(send("POST", &url("/jobs/photos/run"), Some("s3cre")).0, 401);
+        assert!(!lock(&daemon).jobs[0].triggered);
+
+        let (status, body) = send("GET", &url("/jobs/photos"), Some("s3cret"));
+        assert_eq!((status, body["schedule"].as_str()), (200, Some("0 3 * * *")));
+        assert_eq!(send("POST", &url("/jobs/photos/run"), Some("s3cret")).0, 200);
+        assert_eq!(send("POST", &url("/jobs/photos/pause"), Some("s3cret")).0, 200);
+        {
+            let daemon = lock(&daemon);
+            assert!(daemon.jobs[0].triggered && daemon.jobs[0].paused);
+        }
+        assert_eq!(send("POST", &url("/jobs/photos/cancel"), Some("s3cret")).0, 409);
+        assert_eq!(send("GET", &url("/jobs/missing"), Some("s3cret")).0, 404);
+        assert_eq!(send("DELETE", &url("/jobs/photos"), Some("s3cret")).0, 405);
+    }
+}
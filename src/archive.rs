@@ -0,0 +1,522 @@
+//! # Archive Module
+//!
+//! This module writes the backup of a job into a single archive file instead of a folder tree, and reads it back.
+//! A destination is an archive when its name ends with `.tar.zst` (or `.tzst`) or `.zip`.
+//! With a `{date}` placeholder in the name, each run writes a new dated archive.
+//!
+//! Archives are always written in full: the selected files are streamed into a temporary file,
+//! which replaces the archive once complete. Paths and modification times (in whole seconds) are preserved.
+
+use crate::filter::FileFilter;
+use crate::hash::{hash_file, hash_reader};
+use crate::messages::*;
+use crate::storage::storage_key;
+use crate::sync::SyncObserver;
+use crate::utils::{CopyOptions, CopyOutcome};
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike};
+use log::{info, warn};
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zip::extra_fields::ExtraField;
+use zip::write::FullFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Compression level of `tar.zst` archives (the default level of the `zstd` command).
+const ZSTD_LEVEL: i32 = 3;
+
+/// ID of the extended timestamp extra field of ZIP entries, holding the modification time in UTC.
+const ZIP_EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+/// Suffix of archives being written.
+const TEMPORARY_SUFFIX: &str = ".folder-sync-tmp";
+
+/// Format of an archive destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A tar archive compressed with Zstandard.
+    TarZst,
+    /// A ZIP archive with deflate compression.
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Returns the format of an archive destination from its extension, or `None` for a folder.
+    pub fn of(location: &str) -> Option<Self> {
+        let location = location.to_lowercase();
+        if location.ends_with(".tar.zst") || location.ends_with(".tzst") {
+            Some(ArchiveFormat::TarZst)
+        } else if location.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the precision of the modification times stored in the archive.
+    ///
+    /// ZIP archives written by other programs may only have the two-second precision of the DOS date format.
+    pub fn time_precision(self) -> Duration {
+        match self {
+            ArchiveFormat::TarZst => Duration::from_secs(1),
+            ArchiveFormat::Zip => Duration::from_secs(2),
+        }
+    }
+}
+
+/// A file or folder in an archive.
+#[derive(Clone, Debug)]
+pub struct ArchiveEntry {
+    /// Path relative to the root of the backup.
+    pub path: PathBuf,
+    /// `true` for a folder.
+    pub is_dir: bool,
+    /// File size in bytes, `0` for folders.
+    pub len: u64,
+    /// Modification time, if the archive records one.
+    pub modified: Option<SystemTime>,
+}
+
+/// Visitor called by `read_archive` with each entry and a reader of its contents.
+pub type ArchiveVisitor<'a> = dyn FnMut(&ArchiveEntry, &mut dyn Read) -> Result<(), Box<dyn std::error::Error>> + 'a;
+
+/// Writes the files of a source folder into an archive, replacing an existing archive if overwriting is enabled.
+///
+/// Files and folders are selected by the filter in the same way as when copying them, and each file is reported to the observer.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the source folder.
+/// * `archive` - A reference to the `Path` of the archive file.
+/// * `format` - The `ArchiveFormat` of the archive.
+/// * `options` - A reference to the `CopyOptions` of the backup job.
+/// * `filter` - A reference to the `FileFilter` deciding which files and folders are archived.
+/// * `observer` - The `SyncObserver` notified of each processed file, for example a progress bar.
+///
+/// # Errors
+///
+/// * Returns an error if reading the source or writing the archive fails.
+/// * Returns an error if verification is enabled and an archived file does not match its source.
+/// * Returns an error if the backup is cancelled.
+pub fn write_archive(
+    source: &Path,
+    archive: &Path,
+    format: ArchiveFormat,
+    options: &CopyOptions,
+    filter: &FileFilter,
+    observer: &dyn SyncObserver,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if archive.exists() && !options.overwrite {
+        info!("Skipping existing archive: {}", archive.display());
+        return Ok(());
+    }
+    if options.dry_run {
+        return add_recursive(&mut None, source, Path::new(""), options, filter, observer);
+    }
+
+    if let Some(parent) = archive.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let temporary = PathBuf::from(format!("{}{}", archive.display(), TEMPORARY_SUFFIX));
+    let result = ArchiveWriter::create(&temporary, format).and_then(|writer| {
+        let mut writer = Some(writer);
+        add_recursive(&mut writer, source, Path::new(""), options, filter, observer)?;
+        writer.map_or(Ok(()), ArchiveWriter::finish)
+    });
+    if let Err(e) = result {
+        // 途中までのアーカイブは残さない
+        let _ = fs::remove_file(&temporary);
+        return Err(e);
+    }
+    fs::rename(&temporary, archive)?;
+    info!("Archived {} to {}", source.display(), archive.display());
+
+    if options.verify {
+        verify_archive(source, archive)?;
+    }
+    Ok(())
+}
+
+/// Adds a file or folder and everything below it to the archive, or only reports the files in a dry run (`writer` is `None`).
+fn add_recursive(
+    writer: &mut Option<ArchiveWriter>,
+    source: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    filter: &FileFilter,
+    observer: &dyn SyncObserver,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if options.is_cancelled() {
+        return Err(ERR_CANCELLED.into());
+    }
+    let Ok(metadata) = fs::metadata(source) else {
+        info!("Source path does not exist: {}", source.display());
+        return Ok(());
+    };
+
+    if metadata.is_dir() {
+        if filter.skips_dir(source, &metadata) {
+            info!("Skipping excluded path: {}", source.display());
+            return Ok(());
+        }
+        if let Some(writer) = writer.as_mut().filter(|_| !relative.as_os_str().is_empty()) {
+            writer.add_dir(relative, &metadata)?;
+        }
+        // 毎回同じ順序で書き込む
+        let mut entries = fs::read_dir(source)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            add_recursive(writer, &entry.path(), &relative.join(entry.file_name()), options, filter, observer)?;
+        }
+    } else {
+        if !filter.accepts_file(source, &metadata) {
+            info!("Skipping excluded path: {}", source.display());
+            return Ok(());
+        }
+        if filter.skips_extension(source) {
+            info!("Skipping shortcut file : {}", source.display());
+            observer.file_processed(source, CopyOutcome::SkippedPlaceholder, metadata.len());
+            return Ok(());
+        }
+        if let Some(writer) = writer {
+            writer.add_file(source, relative, &metadata)?;
+        }
+        observer.file_processed(source, CopyOutcome::Copied, metadata.len());
+    }
+    Ok(())
+}
+
+/// An archive being written.
+enum ArchiveWriter {
+    TarZst(tar::Builder<zstd::Encoder<'static, BufWriter<File>>>),
+    Zip(Box<ZipWriter<BufWriter<File>>>),
+}
+
+impl ArchiveWriter {
+    fn create(path: &Path, format: ArchiveFormat) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match format {
+            ArchiveFormat::TarZst => ArchiveWriter::TarZst(tar::Builder::new(zstd::Encoder::new(file, ZSTD_LEVEL)?)),
+            ArchiveFormat::Zip => ArchiveWriter::Zip(Box::new(ZipWriter::new(file))),
+        })
+    }
+
+    fn add_dir(&mut self, relative: &Path, metadata: &Metadata) -> Result<(), Box<dyn std::error::Error>> {
+        let name = storage_key(relative);
+        match self {
+            ArchiveWriter::TarZst(builder) => {
+                let mut header = tar_header(metadata, tar::EntryType::Directory, 0o755)?;
+                builder.append_data(&mut header, &name, io::empty())?;
+            }
+            ArchiveWriter::Zip(zip) => zip.add_directory(format!("{}/", name), zip_options(metadata)?)?,
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, source: &Path, relative: &Path, metadata: &Metadata) -> Result<(), Box<dyn std::error::Error>> {
+        let name = storage_key(relative);
+        // ヘッダーに書いたサイズより長くも短くも書き込まない
+        let mut file = SizedReader { inner: File::open(source)?.take(metadata.len()), remaining: metadata.len(), path: source };
+        match self {
+            ArchiveWriter::TarZst(builder) => {
+                let mut header = tar_header(metadata, tar::EntryType::Regular, 0o644)?;
+                builder.append_data(&mut header, &name, &mut file)?;
+            }
+            ArchiveWriter::Zip(zip) => {
+                zip.start_file(name, zip_options(metadata)?.large_file(metadata.len() >= u32::MAX as u64))?;
+                io::copy(&mut file, zip.as_mut())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = match self {
+            ArchiveWriter::TarZst(builder) => builder.into_inner()?.finish()?,
+            ArchiveWriter::Zip(zip) => zip.finish()?,
+        };
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Reader of the first `remaining` bytes of a file, failing if the file ends sooner because it was truncated while being read.
+struct SizedReader<'a> {
+    inner: io::Take<File>,
+    remaining: u64,
+    path: &'a Path,
+}
+
+impl Read for SizedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && self.remaining > 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, ERR_ARCHIVE_FILE_CHANGED.replace("{}", &self.path.display().to_string())));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Returns the modification time of a file in whole seconds since the Unix epoch.
+fn unix_seconds(metadata: &Metadata) -> io::Result<u64> {
+    Ok(metadata.modified()?.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0))
+}
+
+/// Creates the header of a tar entry.
+fn tar_header(metadata: &Metadata, kind: tar::EntryType, mode: u32) -> io::Result<tar::Header> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_size(if kind.is_dir() { 0 } else { metadata.len() });
+    header.set_mtime(unix_seconds(metadata)?);
+    header.set_mode(mode);
+    Ok(header)
+}
+
+/// Creates the options of a ZIP entry, with its modification time in local time (DOS format) and in UTC (extended timestamp).
+fn zip_options(metadata: &Metadata) -> Result<FullFileOptions<'static, 'static>, Box<dyn std::error::Error>> {
+    let seconds = unix_seconds(metadata)?;
+    let local: DateTime<Local> = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(seconds));
+    let mut options = FullFileOptions::default().compression_method(CompressionMethod::Deflated);
+    // DOS 形式で表せない日時(1980年より前など)は省略する
+    if let Ok(time) = zip::DateTime::from_date_and_time(
+        local.year().try_into().unwrap_or(0),
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    ) {
+        options = options.last_modified_time(time);
+    }
+    let mut timestamp = vec![1];
+    timestamp.extend_from_slice(&(seconds.min(u32::MAX as u64) as u32).to_le_bytes());
+    options.add_extra_field(ZIP_EXTENDED_TIMESTAMP, timestamp, false)?;
+    Ok(options)
+}
+
+/// Reads the entries of an archive in order, calling the visitor with each file and folder.
+///
+/// Entries whose path would leave the folder they are extracted into (absolute paths or `..`) are skipped with a warning,
+/// as are entries that are neither files nor folders.
+///
+/// # Arguments
+///
+/// * `archive` - A reference to the `Path` of the archive file.
+/// * `visit` - The visitor called for each entry.
+///
+/// # Errors
+///
+/// * Returns an error if the archive cannot be read or is not in a supported format.
+/// * Returns an error if the visitor returns an error.
+pub fn read_archive(archive: &Path, visit: &mut ArchiveVisitor) -> Result<(), Box<dyn std::error::Error>> {
+    let format = ArchiveFormat::of(&archive.to_string_lossy())
+        .ok_or_else(|| ERR_ARCHIVE_FORMAT.replace("{}", &archive.display().to_string()))?;
+    match format {
+        ArchiveFormat::TarZst => {
+            let mut tar = tar::Archive::new(zstd::Decoder::new(File::open(archive)?)?);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let kind = entry.header().entry_type();
+                let Some(path) = safe_path(&entry.path()?) else {
+                    warn!("Skipping unsafe archive entry: {}", entry.path()?.display());
+                    continue;
+                };
+                if !kind.is_dir() && !kind.is_file() {
+                    continue;
+                }
+                let archive_entry = ArchiveEntry {
+                    path,
+                    is_dir: kind.is_dir(),
+                    len: if kind.is_dir() { 0 } else { entry.size() },
+                    modified: entry.header().mtime().ok().map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime)),
+                };
+                visit(&archive_entry, &mut entry)?;
+            }
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipArchive::new(File::open(archive)?)?;
+            for index in 0..zip.len() {
+                let mut file = zip.by_index(index)?;
+                let Some(path) = file.enclosed_name().as_deref().and_then(safe_path) else {
+                    warn!("Skipping unsafe archive entry: {}", String::from_utf8_lossy(file.name_raw()));
+                    continue;
+                };
+                let archive_entry = ArchiveEntry {
+                    path,
+                    is_dir: file.is_dir(),
+                    len: if file.is_dir() { 0 } else { file.size() },
+                    modified: zip_modified(&file),
+                };
+                visit(&archive_entry, &mut file)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the modification time of a ZIP entry, from its extended timestamp or else from its DOS date in local time.
+fn zip_modified<R: Read>(file: &zip::read::ZipFile<'_, R>) -> Option<SystemTime> {
+    let extended = file.extra_data_fields().find_map(|field| match field {
+        ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+        _ => None,
+    });
+    if let Some(seconds) = extended {
+        return Some(UNIX_EPOCH + Duration::from_secs(seconds as u64));
+    }
+    let time = file.last_modified()?;
+    Local.with_ymd_and_hms(
+        time.year() as i32,
+        time.month() as u32,
+        time.day() as u32,
+        time.hour() as u32,
+        time.minute() as u32,
+        time.second() as u32,
+    ).earliest().map(SystemTime::from)
+}
+
+/// Returns the relative path of an archive entry, or `None` if it is absolute or contains `..`.
+fn safe_path(path: &Path) -> Option<PathBuf> {
+    let mut safe = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => safe.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!safe.as_os_str().is_empty()).then_some(safe)
+}
+
+/// Lists the entries of an archive.
+///
+/// # Errors
+///
+/// * Returns an error if the archive cannot be read or is not in a supported format.
+pub fn list_archive(archive: &Path) -> Result<Vec<ArchiveEntry>, Box<dyn std::error::Error>> {
+    let mut entries = vec![];
+    read_archive(archive, &mut |entry, _| {
+        entries.push(entry.clone());
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Verifies that every file of an archive has the same contents as its source by hashing both.
+///
+/// # Errors
+///
+/// * Returns an error if the archive or a source file cannot be read.
+/// * Returns an error if the hashes differ.
+fn verify_archive(source: &Path, archive: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    read_archive(archive, &mut |entry, reader| {
+        if entry.is_dir {
+            return Ok(());
+        }
+        if hash_reader(reader)? != hash_file(&source.join(&entry.path))? {
+            return Err(ERR_VERIFY_FAILED.replace("{}", &format!("{}:{}", archive.display(), storage_key(&entry.path))).into());
+        }
+        Ok(())
+    })?;
+    info!("Verified: {}", archive.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BtsConfig, BtsConfigWrapper};
+    use filetime::FileTime;
+    use indicatif::ProgressBar;
+
+    /// Path, folder flag, size, modification time and contents of an archive entry.
+    type Entry = (String, bool, u64, Option<SystemTime>, Vec<u8>);
+
+    /// Archives a small tree with verification and returns the entries and contents read back.
+    fn round_trip(name: &str) -> Vec<Entry> {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        fs::create_dir_all(source.path().join("sub/empty")).unwrap();
+        fs::write(source.path().join("a.txt"), "alpha").unwrap();
+        fs::write(source.path().join("sub/b.bin"), vec![7u8; 100_000]).unwrap();
+        fs::write(source.path().join("sub/c.tmp"), "excluded").unwrap();
+        filetime::set_file_mtime(source.path().join("a.txt"), FileTime::from_unix_time(1_700_000_000, 0)).unwrap();
+
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": source.path(),
+            "destination": destination.path().join(name),
+            "overwrite": true,
+            "verify": true,
+            "exclude": ["*.tmp"],
+        })).unwrap();
+        let archive = destination.path().join(name);
+        let format = ArchiveFormat::of(name).unwrap();
+        let filter = FileFilter::new(&config, &BtsConfigWrapper::default());
+        write_archive(source.path(), &archive, format, &CopyOptions::new(&config), &filter, &ProgressBar::hidden()).unwrap();
+        assert!(!PathBuf::from(format!("{}{}", archive.display(), TEMPORARY_SUFFIX)).exists());
+
+        let mut entries = vec![];
+        read_archive(&archive, &mut |entry, reader| {
+            let mut contents = vec![];
+            reader.read_to_end(&mut contents)?;
+            entries.push((storage_key(&entry.path), entry.is_dir, entry.len, entry.modified, contents));
+            Ok(())
+        }).unwrap();
+        entries
+    }
+
+    fn check_round_trip(name: &str) {
+        let entries = round_trip(name);
+        let names: Vec<_> = entries.iter().map(|(name, is_dir, ..)| (name.as_str(), *is_dir)).collect();
+        assert_eq!(names, [("a.txt", false), ("sub", true), ("sub/b.bin", false), ("sub/empty", true)]);
+        assert_eq!(entries[0].2, 5);
+        assert_eq!(entries[0].3, Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        assert_eq!(entries[0].4, b"alpha");
+        assert_eq!(entries[2].4, vec![7u8; 100_000]);
+    }
+
+    #[test]
+    fn tar_zst_round_trip() {
+        check_round_trip("backup.tar.zst");
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        check_round_trip("backup.zip");
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        assert_eq!(safe_path(Path::new("a/./b.txt")), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(safe_path(Path::new("../escape.txt")), None);
+        assert_eq!(safe_path(Path::new("a/../../escape.txt")), None);
+        assert_eq!(safe_path(Path::new("/etc/passwd")), None);
+        assert_eq!(safe_path(Path::new(".")), None);
+        #[cfg(windows)]
+        assert_eq!(safe_path(Path::new("C:\\escape.txt")), None);
+    }
+
+    #[test]
+    fn truncated_files_fail_instead_of_corrupting_the_archive() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("a.txt");
+        fs::write(&path, "0123456789").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+
+        // 読み込み中にファイルが短くなった
+        fs::write(&path, "01234").unwrap();
+        let mut reader = SizedReader { inner: File::open(&path).unwrap().take(metadata.len()), remaining: metadata.len(), path: &path };
+        let error = io::copy(&mut reader, &mut io::sink()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // 長くなった分は書き込まない
+        fs::write(&path, "0123456789abc").unwrap();
+        let mut reader = SizedReader { inner: File::open(&path).unwrap().take(metadata.len()), remaining: metadata.len(), path: &path };
+        assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap(), 10);
+
+        let mut writer = ArchiveWriter::create(&folder.path().join("a.zip"), ArchiveFormat::Zip).unwrap();
+        fs::write(&path, "01234").unwrap();
+        assert!(writer.add_file(&path, Path::new("a.txt"), &metadata).is_err());
+    }
+}
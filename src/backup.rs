@@ -3,11 +3,18 @@
 //! This module provides functionality for backing up files and directories based on specified configurations.
 //! It utilizes multi-threading for concurrent backups and provides progress tracking.
 
+use crate::archive::{write_archive, ArchiveFormat};
 use crate::config::{BtsConfig,BtsConfigWrapper};
-use crate::utils::{count_files, copy_recursive};
+use crate::filter::FileFilter;
+use crate::manifest::write_manifest;
+use crate::shortcuts::write_shortcut_index;
+use crate::repository::{repository_path, snapshot_job, write_snapshot};
+use crate::storage::{local_path, open_storage, redact_url};
+use crate::utils::{count_files, copy_recursive, CopyOptions};
 use crate::messages::*;
+use crate::sync::SyncObserver;
 use indicatif::{ProgressBar, ProgressStyle};
-use log::{error,info};
+use log::{error, warn};
 use std::sync::Arc;
 use std::thread;
 use std::path::Path;
@@ -32,7 +39,7 @@ use std::path::Path;
 /// * Returns an error if any of the backup threads fail.
 pub fn execute_backup(bts_config_wrapper: &BtsConfigWrapper) -> Result<(), Box<dyn std::error::Error>> {
     let mut handles = vec![];
-    let total_files = count_files(&bts_config_wrapper.configs)?;
+    let total_files = count_files(bts_config_wrapper)?;
     let progress_bar = Arc::new(ProgressBar::new(total_files));
     progress_bar.set_style(
         ProgressStyle::default_bar()
@@ -44,11 +51,11 @@ pub fn execute_backup(bts_config_wrapper: &BtsConfigWrapper) -> Result<(), Box<d
     for bts_config in &bts_config_wrapper.configs {
         let bts_config = Arc::new(bts_config.clone());
         let progress_bar = Arc::clone(&progress_bar);
-        let exclude = bts_config_wrapper.exclude.clone();
+        let filter = FileFilter::new(&bts_config, bts_config_wrapper);
         let handle = thread::spawn({
             let bts_config = Arc::clone(&bts_config);
             move || {
-                if let Err(err) = backup_to_ssd(&bts_config, &exclude,&progress_bar) {
+                if let Err(err) = backup_to_ssd(&bts_config, &filter, &CopyOptions::new(&bts_config), &*progress_bar) {
                     error!("Backup failed: {}", err);
                 }
             }
@@ -65,14 +72,17 @@ pub fn execute_backup(bts_config_wrapper: &BtsConfigWrapper) -> Result<(), Box<d
 
 /// Performs a backup to the specified destination based on the provided configuration.
 ///
-/// This function copies files from the source to the destination, excluding specified files or directories.
-/// It updates the progress bar during the copy process.
+/// This function copies files from the source to the destination, skipping files and directories rejected by the filter.
+/// The destination is opened as a storage (see [`crate::storage`]), so it can be a local folder or a URL of a supported storage,
+/// unless it is an archive file (see `crate::archive`), which is written in full.
+/// It updates the progress bar during the copy process, and writes the shortcut index and the manifest if the job requests them.
 ///
 /// # Arguments
 ///
 /// * `config` - A reference to the `BtsConfig` struct containing backup configuration.
-/// * `exclude` - A slice of strings representing files or directories to exclude from the backup.
-/// * `progress_bar` - An `Arc<ProgressBar>` for updating the backup progress.
+/// * `filter` - A reference to the `FileFilter` of the backup job.
+/// * `options` - A reference to the `CopyOptions` of the backup job.
+/// * `observer` - The `SyncObserver` notified of each processed file, for example a progress bar.
 ///
 /// # Returns
 ///
@@ -81,26 +91,99 @@ pub fn execute_backup(bts_config_wrapper: &BtsConfigWrapper) -> Result<(), Box<d
 /// # Errors
 ///
 /// * Returns an error if the source folder does not exist.
+/// * Returns an error if the destination storage is not supported or cannot be opened.
+/// * Returns an error if the destination is an archive that is not a local file, is encrypted, or writing it fails.
+/// * Returns an error if the destination is a repository that is encrypted, in use by gc, or writing the snapshot fails.
 /// * Returns an error if the recursive copy operation fails.
-fn backup_to_ssd(config: &BtsConfig,exclude : &[String], progress_bar: &Arc<ProgressBar>) -> Result<(), Box<dyn std::error::Error>> {
+/// * Returns an error if writing the shortcut index fails.
+/// * Returns an error if writing the manifest fails.
+pub fn backup_to_ssd(
+    config: &BtsConfig,
+    filter : &FileFilter,
+    options: &CopyOptions,
+    observer: &dyn SyncObserver,
+) -> Result<(), Box<dyn std::error::Error>> {
     let source_path = Path::new(&config.source);
-    let destination_path = Path::new(&config.destination);
 
     // デバッグログを追加して、パス名を確認する
     // info!("Source path: {}", source_path.display());
-    // info!("Destination path: {}", destination_path.display());
+    // info!("Destination path: {}", config.destination);
 
     if !source_path.exists() {
         return Err(format!("Source folder does not exist : {}", config.source).into());
     }
 
-    // destinationのルートディレクトリを先に作成する
-    if !destination_path.exists(){
-        std::fs::create_dir_all(destination_path)?;
+    // repo:// のバックアップ先はリポジトリにスナップショットを書き込む
+    if let Some(repository) = repository_path(&config.destination) {
+        if config.encryption.is_some() {
+            return Err(ERR_ENCRYPTED_REPOSITORY.replace("{}", &config.destination).into());
+        }
+        write_snapshot(source_path, &repository, &snapshot_job(config), options, filter, observer)?;
+    } else if let Some(format) = ArchiveFormat::of(&config.destination) {
+        // 拡張子が .tar.zst や .zip のバックアップ先はアーカイブファイルに書き込む
+        if config.encryption.is_some() {
+            return Err(ERR_ENCRYPTED_ARCHIVE.replace("{}", &config.destination).into());
+        }
+        let archive = local_path(&config.destination)
+            .ok_or_else(|| ERR_ARCHIVE_NOT_LOCAL.replace("{}", &redact_url(&config.destination)))?;
+        write_archive(source_path, &archive, format, options, filter, observer)?;
+    } else {
+        let storage = open_storage(config)?;
+        let root = Path::new("");
+
+        // destinationのルートディレクトリを先に作成する
+        if !options.dry_run {
+            storage.create_dir(root)?;
+        }
+
+        // `copy_recursive`を直接呼び出すように修正
+        let result = copy_recursive(source_path, &*storage, root, options, filter, observer);
+        // 途中で失敗しても、コピー済みのファイルを暗号化の索引に残す
+        if !options.dry_run {
+            storage.flush()?;
+        }
+        result?;
+    }
+
+    if options.dry_run {
+        return Ok(());
+    }
+    write_job_indexes(config, filter)
+}
+
+/// Writes the shortcut index and the manifest of a backup job if the job requests them.
+///
+/// Both are only written into unencrypted local folders; for other storages, archives and encrypted destinations a warning is logged instead.
+///
+/// # Arguments
+///
+/// * `config` - A reference to the `BtsConfig` struct containing backup configuration.
+/// * `filter` - A reference to the `FileFilter` of the backup job.
+///
+/// # Errors
+///
+/// * Returns an error if writing the shortcut index fails.
+/// * Returns an error if writing the manifest fails.
+pub fn write_job_indexes(config: &BtsConfig, filter: &FileFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let source_path = Path::new(&config.source);
+    // 暗号化したバックアップ先に平文のファイル名を書き込まない
+    let folder = local_path(&config.destination)
+        .filter(|_| ArchiveFormat::of(&config.destination).is_none() && config.encryption.is_none());
+    let Some(destination_path) = folder else {
+        if config.shortcut_index.is_some() || config.manifest {
+            warn!("Shortcut index and manifest are only written to unencrypted local folder destinations : {}", config.destination);
+        }
+        return Ok(());
+    };
+    let destination_path = destination_path.as_path();
+
+    if let Some(mode) = config.shortcut_index {
+        write_shortcut_index(source_path, destination_path, filter, mode)?;
     }
 
-    // `copy_recursive`を直接呼び出すように修正
-    copy_recursive(source_path, destination_path, config.overwrite, exclude, progress_bar)?;
+    if config.manifest {
+        write_manifest(destination_path, filter)?;
+    }
 
     Ok(())
 }
@@ -5,7 +5,9 @@
 
 // cspell:ignore PKGNAME
 
-use clap::{Parser,Subcommand};
+use crate::config::{AppConfig, BtsConfig};
+use crate::paths::{expand_path, TemplateContext};
+use clap::{Args,Parser,Subcommand,ValueEnum};
 
 const VERSION :&str         = env!("CARGO_PKG_VERSION");
 const PKGNAME: &str         = env!("CARGO_PKG_NAME");
@@ -34,6 +36,10 @@ pub struct Cli {
     /// Specify a configuration file.
     #[clap(short, long, help = "設定ファイルを指定")]
     pub file: Option<String>,
+
+    /// Override configuration values (`KEY=VALUE`).
+    #[clap(long = "set", value_name = "KEY=VALUE", global = true, help = "設定値を上書き (例: --set cdf.destination=D:\\work)")]
+    pub set: Vec<String>,
 }
 
 /// Available subcommands.
@@ -43,10 +49,299 @@ pub struct Cli {
 pub enum Commands {
     /// Backup to SSD.
     #[command(alias = "-bts",name = "--backup-to-ssd")]
-    BackupToSsd,
+    BackupToSsd(BackupArgs),
     /// Create destination folder structure.
     #[command(alias = "-cdf",name = "--create-destination-folders")]
-    CreateFolders,
+    CreateFolders(CreateFoldersArgs),
+    /// Show the effective configuration and where each value came from.
+    #[command(name = "show-config")]
+    ShowConfig,
+    /// Create a configuration file interactively.
+    #[command(name = "init")]
+    Init,
+    /// Compare the source and destination trees of backup jobs by hashing every file.
+    #[command(name = "verify")]
+    Verify(JobArgs),
+    /// Re-hash backup destinations against their manifests and list corrupted files.
+    #[command(name = "scrub")]
+    Scrub(ScrubArgs),
+    /// Restore files from the destination of a backup job.
+    #[command(name = "restore")]
+    Restore(RestoreArgs),
+    /// List the files in the destination of a backup job.
+    #[command(name = "list")]
+    List(ListArgs),
+    /// Compare two trees, or the source and destination of backup jobs.
+    #[command(name = "diff")]
+    Diff(DiffArgs),
+    /// Back up, then keep copying changed files until stopped.
+    #[command(name = "watch")]
+    Watch(WatchArgs),
+    /// Run backup jobs on their schedules until stopped.
+    #[command(name = "daemon")]
+    Daemon(DaemonArgs),
+    /// Remove old snapshots and unreferenced chunks from repository destinations.
+    #[command(name = "gc")]
+    Gc(GcArgs),
+    /// Serve a folder to the `remote://` destinations of other machines.
+    #[command(name = "serve")]
+    Serve(ServeArgs),
+}
+
+/// Options of commands working on configured backup jobs.
+#[derive(Args,Default)]
+pub struct JobArgs {
+    /// Name of the backup job. Every job is processed when omitted.
+    #[clap(long, help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+}
+
+/// Options of the scrub command.
+#[derive(Args,Default)]
+pub struct ScrubArgs {
+    /// Name of the backup job. Every job is processed when omitted.
+    #[clap(long, conflicts_with = "path", help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+
+    /// Destination folder to scrub instead of the configured jobs.
+    #[clap(long, help = "設定ファイルを使わずに検査するバックアップ先のフォルダ")]
+    pub path: Option<String>,
+}
+
+/// Options of the restore command.
+#[derive(Args,Default)]
+pub struct RestoreArgs {
+    /// Name of the backup job. Every job is restored when omitted.
+    #[clap(long, help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+
+    /// Folder to restore into instead of the job's source.
+    #[clap(long, help = "バックアップ元の代わりに復元するフォルダ")]
+    pub to: Option<String>,
+
+    /// Relative paths of files or folders to restore. Everything is restored when omitted.
+    #[clap(long = "path", value_name = "PATH", help = "復元するファイルやフォルダの相対パス (複数指定可)")]
+    pub paths: Vec<String>,
+
+    /// Restore the version of the given date or time.
+    #[clap(long, value_name = "DATE", help = "指定した日時のバージョンから復元 (例: 2025-01-31)")]
+    pub at: Option<String>,
+
+    /// Only list the files that would be restored.
+    #[clap(long, help = "復元されるファイルを表示するだけで、コピーしない")]
+    pub dry_run: bool,
+
+    /// Do not overwrite existing files.
+    #[clap(long, help = "既存のファイルを上書きしない")]
+    pub no_overwrite: bool,
+}
+
+/// Options of the list command.
+#[derive(Args,Default)]
+pub struct ListArgs {
+    /// Name of the backup job. Every job is listed when omitted.
+    #[clap(long, help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+
+    /// List the version of the given date or time.
+    #[clap(long, value_name = "DATE", help = "指定した日時のバージョンを表示 (例: 2025-01-31)")]
+    pub at: Option<String>,
+}
+
+/// Options of the diff command.
+#[derive(Args,Default)]
+pub struct DiffArgs {
+    /// Name of the backup job. Every job is compared when omitted.
+    #[clap(long, conflicts_with = "left", help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+
+    /// Left tree, compared instead of a job's source.
+    #[clap(requires = "right", help = "比較元のフォルダ (省略時はジョブのバックアップ元)")]
+    pub left: Option<String>,
+
+    /// Right tree, compared instead of a job's destination.
+    #[clap(requires = "left", help = "比較先のフォルダ (省略時はジョブのバックアップ先)")]
+    pub right: Option<String>,
+
+    /// Compare the contents of files of the same size by hash instead of modification time.
+    #[clap(long, help = "更新日時の代わりにハッシュで内容を比較")]
+    pub hash: bool,
+
+    /// Output format.
+    #[clap(long, value_enum, default_value_t, help = "出力形式")]
+    pub format: DiffFormat,
+
+    /// Additional excluded file or directory names.
+    #[clap(long, value_name = "NAME", help = "除外するファイルやフォルダを追加")]
+    pub exclude: Vec<String>,
+}
+
+/// Output format of the diff command.
+#[derive(ValueEnum,Clone,Copy,Default,PartialEq,Eq)]
+pub enum DiffFormat {
+    /// One line per difference.
+    #[default]
+    Text,
+    /// JSON document.
+    Json,
+}
+
+/// Options of the watch command.
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Name of the backup job. Every job is watched when omitted.
+    #[clap(long, help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+
+    /// Seconds without changes before changed files are copied.
+    #[clap(long, value_name = "SECONDS", default_value_t = 2, help = "変更が落ち着いてからコピーするまでの秒数")]
+    pub debounce: u64,
+
+    /// Seconds between rescans of jobs that cannot be watched.
+    #[clap(long, value_name = "SECONDS", default_value_t = 300, help = "監視できないジョブを再走査する間隔の秒数")]
+    pub rescan: u64,
+}
+
+/// Options of the daemon command.
+#[derive(Args,Default)]
+pub struct DaemonArgs {
+    /// State file recording the last run of each job.
+    #[clap(long, value_name = "FILE", help = "各ジョブの前回実行日時を記録するファイル (省略時は <設定ファイル名>.state.json)")]
+    pub state: Option<String>,
+
+    /// Address of the control API, for example `127.0.0.1:7878`. The API is disabled when omitted.
+    #[clap(long, value_name = "ADDRESS", help = "制御APIの待ち受けアドレス (例: 127.0.0.1:7878、省略時は無効)")]
+    pub listen: Option<String>,
+
+    /// File holding the token of the control API. Defaults to the `FOLDER_SYNC_API_TOKEN` environment variable.
+    #[clap(long, value_name = "FILE", help = "制御APIのトークンを記録したファイル (省略時は環境変数 FOLDER_SYNC_API_TOKEN。ループバック以外で待ち受ける場合は必須)")]
+    pub token_file: Option<String>,
+}
+
+/// Options of the gc command.
+#[derive(Args,Default)]
+pub struct GcArgs {
+    /// Name of the backup job. The repositories of every job are processed when omitted.
+    #[clap(long, help = "対象のジョブ名 (省略時は全ジョブ)")]
+    pub job: Option<String>,
+
+    /// Number of the latest snapshots to keep for each job. Every snapshot is kept when omitted.
+    #[clap(long, value_name = "N", help = "ジョブごとに残す最新スナップショットの数 (省略時は全て残す)")]
+    pub keep: Option<usize>,
+
+    /// Only report what would be removed.
+    #[clap(long, help = "削除されるスナップショットとチャンクを表示するだけで、削除しない")]
+    pub dry_run: bool,
+}
+
+/// Options of the serve command.
+#[derive(Args,Default)]
+pub struct ServeArgs {
+    /// Folder receiving the backups. The paths of `remote://` destinations are relative to it.
+    #[clap(long, help = "バックアップを受け取るフォルダ (remote:// のパスはこのフォルダからの相対パス)")]
+    pub root: String,
+
+    /// Address to listen on.
+    #[clap(long, value_name = "ADDRESS", default_value = "127.0.0.1:7879", help = "待ち受けアドレス (他のマシンから接続する場合は 0.0.0.0:7879 など)")]
+    pub listen: String,
+
+    /// File holding the shared secret. The `FOLDER_SYNC_SECRET` environment variable is used when omitted.
+    #[clap(long, value_name = "FILE", help = "共有シークレットを記録したファイル (省略時は環境変数 FOLDER_SYNC_SECRET)")]
+    pub secret_file: Option<String>,
+}
+
+/// Ad-hoc options for the backup command.
+///
+/// When `--source` and `--destination` are given, a transient backup job is built from them
+/// and replaces the jobs of the configuration file (or is added to them with `--append`).
+#[derive(Args,Default)]
+pub struct BackupArgs {
+    /// Source path of a transient backup job.
+    #[clap(long, requires = "destination", help = "一時的なバックアップ元のパス")]
+    pub source: Option<String>,
+
+    /// Destination path of a transient backup job.
+    #[clap(long, requires = "source", help = "一時的なバックアップ先のパス")]
+    pub destination: Option<String>,
+
+    /// Additional excluded file or directory names.
+    #[clap(long, value_name = "NAME", help = "除外するファイルやフォルダを追加")]
+    pub exclude: Vec<String>,
+
+    /// Do not overwrite existing files in the transient job.
+    #[clap(long, requires = "source", help = "一時的なジョブで既存ファイルを上書きしない")]
+    pub no_overwrite: bool,
+
+    /// Run the transient job in addition to the configured jobs.
+    #[clap(long, requires = "source", help = "設定ファイルのジョブに加えて実行")]
+    pub append: bool,
+}
+
+impl BackupArgs {
+    /// Returns `true` if the options describe a complete job, so no configuration file is needed.
+    pub fn is_self_contained(&self) -> bool {
+        self.source.is_some() && !self.append
+    }
+
+    /// Applies the options to the loaded configuration.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if a path template cannot be expanded.
+    pub fn apply(&self, config: &mut AppConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let context = TemplateContext::current();
+        if let (Some(source), Some(destination)) = (&self.source, &self.destination) {
+            let job = BtsConfig {
+                name: None,
+                source: expand_path(source, &context)?,
+                destination: expand_path(destination, &context)?,
+                destination_template: destination.clone(),
+                overwrite: !self.no_overwrite,
+                ..Default::default()
+            };
+            if !self.append {
+                config.bts.configs.clear();
+            }
+            config.bts.configs.push(job);
+        }
+        config.bts.exclude.extend(self.exclude.iter().cloned());
+        Ok(())
+    }
 }
 
+/// Ad-hoc options for the create folders command.
+///
+/// Each option replaces the corresponding value of the configuration file.
+#[derive(Args,Default)]
+pub struct CreateFoldersArgs {
+    /// Source path of the folder structure.
+    #[clap(long, help = "フォルダ構成の展開元のパス")]
+    pub source: Option<String>,
 
+    /// Destination path of the folder structure.
+    #[clap(long, help = "フォルダ構成の展開先のパス")]
+    pub destination: Option<String>,
+}
+
+impl CreateFoldersArgs {
+    /// Returns `true` if the options describe a complete job, so no configuration file is needed.
+    pub fn is_self_contained(&self) -> bool {
+        self.source.is_some() && self.destination.is_some()
+    }
+
+    /// Applies the options to the loaded configuration.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if a path template cannot be expanded.
+    pub fn apply(&self, config: &mut AppConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let context = TemplateContext::current();
+        if let Some(source) = &self.source {
+            config.cdf.source = expand_path(source, &context)?;
+        }
+        if let Some(destination) = &self.destination {
+            config.cdf.destination = expand_path(destination, &context)?;
+        }
+        Ok(())
+    }
+}
@@ -1,51 +1,196 @@
 //! # Configuration Module
 //!
 //! This module defines structures for application configuration and provides functionality
-//! to load configuration from a JSON file.
+//! to load configuration from JSON files.
+//!
+//! The effective configuration is built from layers (see `crate::layers`):
+//! the files named by `include`, the configuration file itself,
+//! the per-machine override file `<stem>.<hostname>.json` next to it, and `--set` overrides.
 
+use crate::layers::LayeredConfig;
 use crate::messages::*;
-use serde::Deserialize;
-use std::{io::Read,fs};
-use std::path::Path;
+use crate::paths::{expand_path, TemplateContext};
+use crate::repository::check_snapshot_jobs;
+use crate::schedule::Schedule;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
 /// Application configuration structure.
 ///
 /// This struct represents the overall application configuration, including backup and folder creation settings.
-#[derive(Deserialize)]
+#[derive(Deserialize,Serialize,Default)]
 pub struct AppConfig {
     /// Backup configuration wrapper.
+    #[serde(default)]
     pub bts : BtsConfigWrapper,
     /// Folder creation configuration.
+    #[serde(default)]
     pub cdf : CdfConfig,
 }
 
 /// Backup configuration wrapper structure.
 ///
-/// This struct wraps a vector of backup configurations and a list of excluded files or directories.
-#[derive(Deserialize)]
+/// This struct wraps a vector of backup configurations and a list of files or directories excluded from every job.
+#[derive(Deserialize,Serialize,Clone)]
 pub struct BtsConfigWrapper {
     /// Vector of backup configurations.
+    #[serde(default)]
     pub configs : Vec<BtsConfig>,
-    /// Vector of excluded file or directory names.
+    /// Vector of excluded file or directory patterns applied to every job (see `crate::filter`).
+    #[serde(default)]
     pub exclude : Vec<String>,
+    /// Vector of file extensions (without the dot) of placeholder files that are skipped, such as Google Drive shortcuts.
+    #[serde(default = "default_skip_extensions")]
+    pub skip_extensions : Vec<String>,
+}
+
+impl BtsConfigWrapper {
+    /// Returns the job with the given name, or every job if no name is given.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if no job has the given name.
+    pub fn select(&self, name : Option<&str>) -> Result<Vec<&BtsConfig>, Box<dyn std::error::Error>> {
+        match name {
+            None => Ok(self.configs.iter().collect()),
+            Some(name) => self.configs.iter()
+                .find(|config| config.name.as_deref() == Some(name))
+                .map(|config| vec![config])
+                .ok_or_else(|| ERR_JOB_NOT_FOUND.replace("{}", name).into()),
+        }
+    }
+}
+
+impl Default for BtsConfigWrapper {
+    fn default() -> Self {
+        BtsConfigWrapper {
+            configs: vec![],
+            exclude: vec![],
+            skip_extensions: default_skip_extensions(),
+        }
+    }
+}
+
+/// Extensions skipped by default: Google Drive shortcut files, which cannot be opened outside of Google Drive.
+fn default_skip_extensions() -> Vec<String> {
+    ["gdoc", "gsheet", "gslides", "gdraw", "gform", "gmap", "gsite"]
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
 }
 
 /// Backup configuration structure.
 ///
 /// This struct represents the configuration for a single backup operation.
-#[derive(Deserialize,Clone)]
+/// `source` and `destination` may contain path templates (see `crate::paths`).
+#[derive(Deserialize,Serialize,Clone,Default)]
 pub struct BtsConfig {
+    /// Name of the backup job. Jobs with a name can be overridden per machine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name : Option<String>,
     /// Source path for the backup.
     pub source : String,
     /// Destination path for the backup.
     pub destination : String,
+    /// Destination path as written before template expansion, used to locate dated versions.
+    #[serde(skip)]
+    pub destination_template : String,
     /// Flag indicating whether to overwrite existing files.
-    pub overwrite: bool
+    pub overwrite: bool,
+    /// Vector of excluded file or directory patterns for this job, merged with the global list.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude : Vec<String>,
+    /// Vector of included file or directory patterns. When empty, every file that is not excluded is backed up.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include : Vec<String>,
+    /// Files smaller than this size are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_size : Option<ByteSize>,
+    /// Files larger than this size are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size : Option<ByteSize>,
+    /// Files last modified before this time are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_after : Option<TimeSpec>,
+    /// Files last modified after this time are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_before : Option<TimeSpec>,
+    /// Flag indicating whether to skip hidden files and directories (dotfiles, and the hidden attribute on Windows).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_hidden : bool,
+    /// Vector of additional skipped file extensions for this job, merged with the global list.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_extensions : Vec<String>,
+    /// Flag indicating whether to copy files with skipped extensions instead of skipping them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub copy_shortcuts : bool,
+    /// Flag indicating whether to hash each copied file and compare it with its source.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verify : bool,
+    /// Existing files in a local destination at least this size are updated in place, rewriting only the changed blocks (see `crate::delta`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_threshold : Option<ByteSize>,
+    /// Flag indicating whether to write a manifest (path, size, modification time and hash) into the destination.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub manifest : bool,
+    /// Writes an index (HTML and CSV) of the Google Drive shortcut files per directory or per job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shortcut_index : Option<ShortcutIndexMode>,
+    /// When the daemon runs the job: an interval (`"6h"`) or a cron expression (`"0 3 * * *"`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule : Option<Schedule>,
+    /// Private key file used to authenticate to `sftp://` destinations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key : Option<String>,
+    /// URL of the S3-compatible server of `s3://` destinations, such as a MinIO instance. Amazon S3 when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3_endpoint : Option<String>,
+    /// Region of the bucket of `s3://` destinations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3_region : Option<String>,
+    /// File holding the shared secret of `remote://` destinations. The `FOLDER_SYNC_SECRET` environment variable is used when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_secret_file : Option<String>,
+    /// Encrypts the files written into the destination (see `crate::crypt`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption : Option<EncryptionConfig>,
+}
+
+/// Encryption settings of a backup job.
+///
+/// The key is derived from the contents of `key_file` if given, otherwise from `passphrase`,
+/// otherwise from the `FOLDER_SYNC_PASSPHRASE` environment variable.
+#[derive(Deserialize,Serialize,Clone,Default)]
+pub struct EncryptionConfig {
+    /// Passphrase from which the key is derived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase : Option<String>,
+    /// File whose contents the key is derived from, for example a file of random bytes on a USB key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file : Option<String>,
+    /// Flag indicating whether file and folder names are also encrypted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt_names : bool,
+}
+
+/// Where the Google Drive shortcut index is written (see `crate::shortcuts`).
+#[derive(Deserialize,Serialize,Clone,Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ShortcutIndexMode {
+    /// One index in each destination directory containing shortcuts.
+    Directory,
+    /// One index in the destination folder of the job.
+    Job,
 }
 
 /// Folder creation configuration structure.
 ///
 /// This struct represents the configuration for creating folder structures.
-#[derive(Deserialize)]
+/// `source` and `destination` may contain path templates (see `crate::paths`).
+#[derive(Deserialize,Serialize,Default)]
 pub struct CdfConfig {
     /// Source path for the folder structure.
     pub source : String,
@@ -53,14 +198,155 @@ pub struct CdfConfig {
     pub destination : String,
 }
 
+/// File size used by size filters.
+///
+/// Written in the configuration file either as a number of bytes or as a string
+/// with a unit (`B`, `KB`, `MB`, `GB`, `TB`, 1024-based), for example `"500MB"` or `"1.5 GB"`.
+#[derive(Deserialize,Serialize,Clone,Copy)]
+#[serde(try_from = "RawByteSize", into = "u64")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = String;
+
+    fn try_from(raw : RawByteSize) -> Result<Self, Self::Error> {
+        let text = match raw {
+            RawByteSize::Bytes(bytes) => return Ok(ByteSize(bytes)),
+            RawByteSize::Text(text) => text,
+        };
+        let invalid = || ERR_INVALID_SIZE.replace("{}", &text);
+
+        let trimmed = text.trim();
+        let split = trimmed.find(|c : char| !(c.is_ascii_digit() || c == '.')).unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let number: f64 = number.parse().map_err(|_| invalid())?;
+        let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            "T" | "TB" | "TIB" => 1 << 40,
+            _ => return Err(invalid()),
+        };
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size : ByteSize) -> Self {
+        size.0
+    }
+}
+
+/// Point in time used by modification time filters.
+///
+/// Written in the configuration file as a date (`"2025-01-31"`), a local date and time
+/// (`"2025-01-31T09:00:00"`), an RFC 3339 timestamp, or a duration before the start of the run
+/// (`"90d"`; units `s`, `m`, `h`, `d`, `w`).
+#[derive(Deserialize,Serialize,Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeSpec {
+    /// The value as written in the configuration file.
+    raw : String,
+    /// The parsed value.
+    kind : TimeSpecKind,
+}
+
+#[derive(Clone)]
+enum TimeSpecKind {
+    At(SystemTime),
+    Ago(Duration),
+}
+
+impl TimeSpec {
+    /// Returns the point in time, resolving durations relative to `now`.
+    pub fn resolve(&self, now : SystemTime) -> SystemTime {
+        match self.kind {
+            TimeSpecKind::At(time) => time,
+            TimeSpecKind::Ago(duration) => now.checked_sub(duration).unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+}
+
+impl TimeSpec {
+    /// Parses a point in time, rejecting durations.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the text is not a date, a local date and time, or an RFC 3339 timestamp.
+    pub fn parse_absolute(text : &str) -> Result<SystemTime, String> {
+        match TimeSpec::try_from(text.to_string())?.kind {
+            TimeSpecKind::At(time) => Ok(time),
+            TimeSpecKind::Ago(_) => Err(ERR_INVALID_TIME.replace("{}", text)),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeSpec {
+    type Error = String;
+
+    fn try_from(raw : String) -> Result<Self, Self::Error> {
+        let text = raw.trim();
+        let invalid = || ERR_INVALID_TIME.replace("{}", &raw);
+
+        // 相対指定 (例: 90d)
+        if let Some(duration) = parse_duration(text) {
+            return Ok(TimeSpec { raw, kind: TimeSpecKind::Ago(duration) });
+        }
+
+        // 絶対指定 (RFC 3339、ローカル日時、日付)
+        let time: DateTime<Local> = if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            time.with_timezone(&Local)
+        } else {
+            let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S")
+                .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S"))
+                .or_else(|_| NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default()))
+                .map_err(|_| invalid())?;
+            Local.from_local_datetime(&naive).earliest().ok_or_else(invalid)?
+        };
+        Ok(TimeSpec { raw, kind: TimeSpecKind::At(time.into()) })
+    }
+}
+
+impl From<TimeSpec> for String {
+    fn from(spec : TimeSpec) -> Self {
+        spec.raw
+    }
+}
+
+/// Parses a duration written as a number followed by a unit (`s`, `m`, `h`, `d` or `w`), for example `"90d"`.
+///
+/// Returns `None` if the text is not a duration.
+pub fn parse_duration(text : &str) -> Option<Duration> {
+    let unit = text.chars().last().filter(|c| c.is_ascii_alphabetic())?;
+    let amount: u64 = text[..text.len() - 1].parse().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(seconds)?))
+}
+
 /// Loads application configuration from a JSON file.
 ///
-/// This function reads the configuration file from the same directory as the executable,
-/// parses it as JSON, and returns an `AppConfig` struct.
+/// This function merges the configuration layers, parses the result,
+/// expands the path templates, and returns an `AppConfig` struct.
 ///
 /// # Arguments
 ///
-/// * `file_name` - The name of the configuration file.
+/// * `path` - A reference to the `Path` of the configuration file.
+/// * `overrides` - A slice of `KEY=VALUE` overrides given on the command line.
 ///
 /// # Returns
 ///
@@ -69,14 +355,341 @@ pub struct CdfConfig {
 ///
 /// # Errors
 ///
-/// * Returns an error if the executable directory cannot be determined.
-/// * Returns an error if the configuration file cannot be opened.
-/// * Returns an error if the configuration file cannot be read.
-/// * Returns an error if the configuration file cannot be parsed as JSON.
-pub fn load_config(path : &Path) -> Result<AppConfig, Box<dyn std::error::Error>> {
-    let mut file = fs::File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let config: AppConfig = serde_json::from_str(&contents)?;
+/// * Returns an error if a configuration file cannot be read or parsed as JSON.
+/// * Returns an error if an include directive or override is invalid.
+/// * Returns an error if the merged configuration does not match `AppConfig`.
+/// * Returns an error if a path template cannot be expanded.
+/// * Returns an error if two jobs store their snapshots under the same name in a repository.
+pub fn load_config(path : &Path, overrides : &[String]) -> Result<AppConfig, Box<dyn std::error::Error>> {
+    let layers = load_layers(path, overrides)?;
+    build_config(&layers)
+}
+
+/// Builds a configuration from command-line overrides only, for jobs given entirely on the command line
+/// when there is no configuration file.
+///
+/// # Arguments
+///
+/// * `overrides` - A slice of `KEY=VALUE` overrides given on the command line.
+///
+/// # Errors
+///
+/// * Returns an error if an override is invalid or does not match `AppConfig`.
+pub fn load_overrides(overrides : &[String]) -> Result<AppConfig, Box<dyn std::error::Error>> {
+    let mut layers = LayeredConfig::new();
+    for assignment in overrides {
+        layers.apply_override(assignment)?;
+    }
+    build_config(&layers)
+}
+
+/// Loads and merges all configuration layers without parsing them into an `AppConfig`.
+///
+/// # Arguments
+///
+/// * `path` - A reference to the `Path` of the configuration file.
+/// * `overrides` - A slice of `KEY=VALUE` overrides given on the command line.
+///
+/// # Returns
+///
+/// Returns `Ok(LayeredConfig)` with the merged value and the origin of each value,
+/// or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if a configuration file cannot be read or parsed as JSON.
+/// * Returns an error if an include directive or override is invalid.
+pub fn load_layers(path : &Path, overrides : &[String]) -> Result<LayeredConfig, Box<dyn std::error::Error>> {
+    let mut layers = LayeredConfig::new();
+    layers.merge_file(path)?;
+
+    if let Some(machine_path) = machine_config_path(path) {
+        info!("Loading machine config: {}", machine_path.display());
+        layers.merge_file(&machine_path)?;
+    }
+
+    for assignment in overrides {
+        layers.apply_override(assignment)?;
+    }
+
+    Ok(layers)
+}
+
+/// Parses merged configuration layers into an `AppConfig` and expands its path templates.
+///
+/// # Errors
+///
+/// * Returns an error if the merged configuration does not match `AppConfig`, or a `--set` override sets an unknown key.
+/// * Returns an error if a path template cannot be expanded.
+/// * Returns an error if two jobs store their snapshots under the same name in a repository.
+pub fn build_config(layers : &LayeredConfig) -> Result<AppConfig, Box<dyn std::error::Error>> {
+    // 設定ファイルにない項目は無視するが、--set で指定した項目の書き間違いはエラーにする
+    let mut ignored = vec![];
+    let result: Result<AppConfig, _> = serde_ignored::deserialize(layers.value.clone(), |path| ignored.push(path_segments(&path)));
+    if let Some(key) = ignored.iter().find_map(|segments| layers.override_key(segments)) {
+        return Err(ERR_UNKNOWN_OVERRIDE.replace("{}", &key).into());
+    }
+    let mut config = result?;
+    expand_config(&mut config, &TemplateContext::current())?;
+    check_snapshot_jobs(&config.bts.configs)?;
     Ok(config)
 }
+
+/// Returns the keys and array indices of a path reported by `serde_ignored`.
+fn path_segments(path : &serde_ignored::Path) -> Vec<String> {
+    match path {
+        serde_ignored::Path::Root => vec![],
+        serde_ignored::Path::Seq { parent, index } => {
+            let mut segments = path_segments(parent);
+            segments.push(index.to_string());
+            segments
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            let mut segments = path_segments(parent);
+            segments.push(key.clone());
+            segments
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => path_segments(parent),
+    }
+}
+
+/// Prints the effective configuration and the origin of each value.
+///
+/// # Arguments
+///
+/// * `path` - A reference to the `Path` of the configuration file.
+/// * `overrides` - A slice of `KEY=VALUE` overrides given on the command line.
+///
+/// # Errors
+///
+/// * Returns an error if the configuration cannot be loaded.
+pub fn execute_show_config(path : &Path, overrides : &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let layers = load_layers(path, overrides)?;
+    let config = build_config(&layers)?;
+
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    println!();
+    println!("{}", MSG_CONFIG_ORIGINS);
+    for (key, origin) in &layers.origins {
+        let value = layers.get(key).map(|v| v.to_string()).unwrap_or_default();
+        println!("  {} = {}  ({})", key, value, origin);
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the per-machine override file if it exists.
+///
+/// For `config.json` on host `PC01` this is `config.PC01.json` (or `config.pc01.json`) in the same directory.
+fn machine_config_path(path : &Path) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let hostname = TemplateContext::current().hostname;
+    [hostname.clone(), hostname.to_lowercase()]
+        .iter()
+        .map(|host| path.with_file_name(format!("{}.{}.json", stem, host)))
+        .find(|candidate| candidate.is_file())
+}
+/// Expands the path templates of every source and destination in the configuration.
+///
+/// # Arguments
+///
+/// * `config` - A mutable reference to the `AppConfig` to expand in place.
+/// * `context` - A reference to the `TemplateContext` holding the substituted values.
+///
+/// # Errors
+///
+/// * Returns an error if any path template cannot be expanded.
+fn expand_config(config : &mut AppConfig, context : &TemplateContext) -> Result<(), Box<dyn std::error::Error>> {
+    for bts_config in &mut config.bts.configs {
+        bts_config.source = expand_path(&bts_config.source, context)?;
+        bts_config.destination_template = bts_config.destination.clone();
+        bts_config.destination = expand_path(&bts_config.destination, context)?;
+        if let Some(ssh_key) = &bts_config.ssh_key {
+            bts_config.ssh_key = Some(expand_path(ssh_key, context)?);
+        }
+        if let Some(secret_file) = &bts_config.remote_secret_file {
+            bts_config.remote_secret_file = Some(expand_path(secret_file, context)?);
+        }
+        if let Some(key_file) = bts_config.encryption.as_mut().and_then(|encryption| encryption.key_file.as_mut()) {
+            *key_file = expand_path(key_file, context)?;
+        }
+    }
+    config.cdf.source = expand_path(&config.cdf.source, context)?;
+    config.cdf.destination = expand_path(&config.cdf.destination, context)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn size(value : serde_json::Value) -> Result<u64, serde_json::Error> {
+        serde_json::from_value::<ByteSize>(value).map(u64::from)
+    }
+
+    #[test]
+    fn parses_sizes() {
+        for (value, expected) in [
+            (json!(0), 0),
+            (json!(1536), 1536),
+            (json!("1536"), 1536),
+            (json!("10B"), 10),
+            (json!("1K"), 1 << 10),
+            (json!("2kb"), 2 << 10),
+            (json!("3KiB"), 3 << 10),
+            (json!("500MB"), 500 << 20),
+            (json!("1.5 GB"), 3 << 29),
+            (json!(" 2TB "), 2 << 40),
+        ] {
+            assert_eq!(size(value.clone()).unwrap(), expected, "{}", value);
+        }
+        for value in [json!(""), json!("MB"), json!("1.2.3MB"), json!("10 PB"), json!("-1KB"), json!(-1), json!(1.5), json!(null)] {
+            assert!(size(value.clone()).is_err(), "{}", value);
+        }
+        assert!(size(json!("10 PB")).unwrap_err().to_string().contains("10 PB"));
+        assert_eq!(serde_json::to_value(ByteSize(1024)).unwrap(), json!(1024));
+    }
+
+    #[test]
+    fn parses_durations() {
+        for (text, expected) in [("30s", 30), ("15m", 15 * 60), ("2h", 2 * 60 * 60), ("90d", 90 * 24 * 60 * 60), ("1w", 7 * 24 * 60 * 60), ("0d", 0)] {
+            assert_eq!(parse_duration(text), Some(Duration::from_secs(expected)), "{}", text);
+        }
+        for text in ["", "d", "10", "10y", "10D", "-1d", "1.5h", " 1d", "99999999999999999w"] {
+            assert_eq!(parse_duration(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn resolves_time_specs() {
+        let now = SystemTime::now();
+        let ago = TimeSpec::try_from("2d".to_string()).unwrap();
+        assert_eq!(ago.resolve(now), now - Duration::from_secs(2 * 24 * 60 * 60));
+        assert_eq!(TimeSpec::try_from(" 90d ".to_string()).unwrap().resolve(now), now - Duration::from_secs(90 * 24 * 60 * 60));
+        assert!(TimeSpec::parse_absolute("2d").is_err());
+
+        let local = |text : &str| -> SystemTime {
+            let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap();
+            Local.from_local_datetime(&naive).earliest().unwrap().into()
+        };
+        for (text, expected) in [
+            ("2025-01-31", local("2025-01-31 00:00:00")),
+            ("2025-01-31T09:30:00", local("2025-01-31 09:30:00")),
+            ("2025-01-31 09:30:00", local("2025-01-31 09:30:00")),
+            ("2025-01-31T09:30:00Z", SystemTime::UNIX_EPOCH + Duration::from_secs(1_738_315_800)),
+            ("2025-01-31T18:30:00+09:00", SystemTime::UNIX_EPOCH + Duration::from_secs(1_738_315_800)),
+        ] {
+            assert_eq!(TimeSpec::parse_absolute(text).unwrap(), expected, "{}", text);
+            // 絶対指定は now に関係なく同じ時刻になる
+            assert_eq!(TimeSpec::try_from(text.to_string()).unwrap().resolve(SystemTime::UNIX_EPOCH), expected, "{}", text);
+        }
+        for text in ["", "yesterday", "2025-13-01", "2025-01-31T25:00:00", "31/01/2025"] {
+            assert!(TimeSpec::try_from(text.to_string()).is_err(), "{}", text);
+        }
+
+        // 設定ファイルには書かれたとおりに出力する
+        let spec: TimeSpec = serde_json::from_value(json!("90d")).unwrap();
+        assert_eq!(serde_json::to_value(spec).unwrap(), json!("90d"));
+    }
+
+    #[test]
+    fn finds_machine_config() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("config.json");
+        fs::write(&path, "{}").unwrap();
+        assert_eq!(machine_config_path(&path), None);
+
+        let hostname = TemplateContext::current().hostname;
+        let machine = folder.path().join(format!("config.{}.json", hostname.to_lowercase()));
+        fs::write(&machine, "{}").unwrap();
+        assert_eq!(machine_config_path(&path), Some(machine));
+        fs::write(folder.path().join("config.other-host.json"), "{}").unwrap();
+        assert_eq!(machine_config_path(&folder.path().join("missing.json")), None);
+    }
+
+    #[test]
+    fn merges_includes_machine_config_and_overrides() {
+        let folder = tempfile::tempdir().unwrap();
+        let job = |source : &str, destination : &str| json!({"name": "docs", "source": source, "destination": destination, "overwrite": true});
+        fs::write(folder.path().join("base.json"), json!({
+            "bts": {"configs": [job("/base", "/base-backup")], "exclude": ["*.base"]},
+            "cdf": {"source": "/cdf-base", "destination": "/cdf-base-out"},
+        }).to_string()).unwrap();
+        let path = folder.path().join("config.json");
+        fs::write(&path, json!({
+            "include": "base.json",
+            "bts": {"configs": [job("/main", "/main-backup")]},
+            "cdf": {"source": "/cdf-main"},
+        }).to_string()).unwrap();
+        let hostname = TemplateContext::current().hostname;
+        fs::write(folder.path().join(format!("config.{}.json", hostname.to_lowercase())), json!({
+            "bts": {"configs": [{"name": "docs", "destination": "/machine-backup"}]},
+            "cdf": {"source": "/cdf-machine"},
+        }).to_string()).unwrap();
+
+        let config = load_config(&path, &[]).unwrap();
+        let docs = &config.bts.configs[0];
+        assert_eq!((docs.source.as_str(), docs.destination.as_str()), ("/main", "/machine-backup"));
+        assert_eq!(config.bts.exclude, ["*.base"]);
+        assert_eq!((config.cdf.source.as_str(), config.cdf.destination.as_str()), ("/cdf-machine", "/cdf-base-out"));
+
+        let overrides = ["bts.configs.docs.destination=/cli-backup".to_string(), "bts.exclude=[]".to_string()];
+        let config = load_config(&path, &overrides).unwrap();
+        assert_eq!(config.bts.configs[0].destination, "/cli-backup");
+        assert_eq!(config.bts.configs[0].source, "/main");
+        assert!(config.bts.exclude.is_empty());
+
+        let layers = load_layers(&path, &overrides).unwrap();
+        assert_eq!(layers.origins["bts.configs.docs.source"], path.display().to_string());
+        assert_eq!(layers.origins["bts.configs.docs.destination"], "--set");
+        assert!(layers.origins["cdf.destination"].ends_with("base.json"));
+    }
+
+    #[test]
+    fn rejects_overrides_of_unknown_keys() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("config.json");
+        // 設定ファイルの未知の項目は無視する
+        fs::write(&path, json!({
+            "comment": "ignored",
+            "bts": {"configs": [{"name": "docs", "source": "/src", "destination": "/dst", "overwrite": true, "note": "ignored"}]},
+        }).to_string()).unwrap();
+        load_config(&path, &[]).unwrap();
+
+        for (assignment, key) in [
+            ("bts.configs.docs.overwirte=false", "bts.configs.docs.overwirte"),
+            ("bts.configs.0.verfy=true", "bts.configs.docs.verfy"),
+            ("cdf.sorce=/src", "cdf.sorce"),
+            ("unknown.key=1", "unknown"),
+        ] {
+            let error = load_config(&path, &[assignment.to_string()]).map(drop).unwrap_err().to_string();
+            assert_eq!(error, ERR_UNKNOWN_OVERRIDE.replace("{}", key), "{}", assignment);
+        }
+        assert!(load_config(&path, &["bts.configs.docs.encryption.passphrse=x".to_string()]).is_err());
+        load_config(&path, &["bts.configs.docs.verify=true".to_string()]).unwrap();
+    }
+
+    #[test]
+    fn loads_overrides_without_config_file() {
+        let overrides = [
+            "bts.configs=[{\"source\": \"/src\", \"destination\": \"/dst\", \"overwrite\": true}]".to_string(),
+            "bts.configs.0.min_size=1KB".to_string(),
+            "bts.configs.0.modified_after=7d".to_string(),
+        ];
+        let config = load_overrides(&overrides).unwrap();
+        assert_eq!(config.bts.configs.len(), 1);
+        assert_eq!(config.bts.configs[0].min_size.map(u64::from), Some(1024));
+        assert!(config.bts.configs[0].modified_after.is_some());
+        assert_eq!(config.bts.skip_extensions, default_skip_extensions());
+
+        assert!(load_overrides(&["bts.configs.0.min_size=1XB".to_string()]).is_err());
+        assert!(load_overrides(&["bts.configs=[{\"source\": \"/src\"}]".to_string()]).is_err());
+        assert!(load_overrides(&["cdf".to_string()]).is_err());
+        assert!(load_overrides(&["cdf.sorce=/src".to_string()]).is_err());
+        assert!(load_overrides(&[]).unwrap().bts.configs.is_empty());
+    }
+}
@@ -0,0 +1,796 @@
+//! # Encryption Module
+//!
+//! This module encrypts the destinations of backup jobs with an `encryption` section, so a lost backup drive does not expose its files.
+//! [`EncryptedStorage`] wraps the storage of the destination (see [`crate::storage`]) and encrypts everything written through it.
+//!
+//! The key is derived with Argon2id from a passphrase or from the contents of a key file. The salt of the derivation is stored
+//! with a check value in `.folder-sync-key` at the root of the destination, so a wrong passphrase is detected before anything is written.
+//! File contents are encrypted with XChaCha20-Poly1305 in chunks of 64 KiB, each authenticated together with the path of the file,
+//! so modified or truncated files, and files moved or swapped within the destination, fail to decrypt.
+//! With `encrypt_names`, each file and folder name is also encrypted, always to the same stored name so files are found again on the next run.
+//! Names too long to be stored encrypted are stored as their MAC, and the real name is kept in the index.
+//!
+//! Encrypted files do not keep the size and modification time of their source, so the size, modification time and SHA-256 hash
+//! of each file are recorded in an encrypted index, `.folder-sync-index`, from which unchanged files are detected.
+
+use crate::config::EncryptionConfig;
+use crate::hash::to_hex;
+use crate::messages::*;
+use crate::storage::{storage_key, Storage, StorageEntry, StorageMetadata, StorageWriter};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// File at the root of the destination holding the salt of the key derivation and the key check value.
+pub const KEY_FILE: &str = ".folder-sync-key";
+
+/// File at the root of the destination holding the encrypted index of the files.
+pub const INDEX_FILE: &str = ".folder-sync-index";
+
+/// Temporary file written before replacing the index.
+const INDEX_TEMPORARY_FILE: &str = ".folder-sync-index.tmp";
+
+/// Environment variable holding the passphrase when the job sets neither `passphrase` nor `key_file`.
+const PASSPHRASE_VARIABLE: &str = "FOLDER_SYNC_PASSPHRASE";
+
+/// First bytes of every encrypted file.
+const MAGIC: &[u8; 8] = b"FSRSENC1";
+
+/// Size of the random nonce prefix following the magic bytes. The nonce of each chunk ends with its number and a last-chunk flag.
+const NONCE_PREFIX_SIZE: usize = 19;
+
+/// Size of the header of an encrypted file.
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_PREFIX_SIZE;
+
+/// Size of the plaintext of each chunk but the last.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of the authentication tag added to each chunk.
+const TAG_SIZE: usize = 16;
+
+/// Longest stored name. Encrypted names longer than this (names of more than about 150 bytes) are stored as their MAC.
+const MAX_STORED_NAME_LEN: usize = 255;
+
+/// Prefix of the stored names of long names. `.` is not used by encrypted names, so the two cannot be confused.
+const LONG_NAME_PREFIX: &str = "long.";
+
+/// Size of the derived key material: content key, name key and name MAC key.
+const KEY_MATERIAL_SIZE: usize = 96;
+
+/// Message authenticated with the derived key to check it.
+const KEY_CHECK: &[u8] = b"folder-sync-rs key check";
+
+/// Contents of the key file.
+#[derive(Deserialize, Serialize)]
+struct KeyRecord {
+    /// Argon2id memory cost in KiB.
+    memory: u32,
+    /// Argon2id number of iterations.
+    iterations: u32,
+    /// Argon2id degree of parallelism.
+    parallelism: u32,
+    /// Salt of the key derivation (Base64).
+    salt: String,
+    /// HMAC-SHA256 of [`KEY_CHECK`] with the derived key (hexadecimal).
+    check: String,
+}
+
+/// Size, modification time and hash of the source of an encrypted file.
+#[derive(Clone, Deserialize, Serialize)]
+struct IndexEntry {
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: Option<String>,
+}
+
+/// Index of the files of an encrypted destination.
+#[derive(Default, Deserialize, Serialize)]
+struct Index {
+    /// Entries of the files by `/`-separated path.
+    files: BTreeMap<String, IndexEntry>,
+    /// Long names by stored name (see [`LONG_NAME_PREFIX`]).
+    #[serde(default)]
+    long_names: BTreeMap<String, String>,
+    /// `true` if the index changed since it was read or written.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+/// Storage encrypting the files written into another storage.
+pub struct EncryptedStorage {
+    inner: Box<dyn Storage>,
+    /// Cipher of the file contents and of the index.
+    content: XChaCha20Poly1305,
+    /// Cipher and MAC key of the names, if names are encrypted.
+    names: Option<(XChaCha20Poly1305, Vec<u8>)>,
+    /// Key file to write before the first file, if the destination does not have one yet.
+    new_key: Mutex<Option<String>>,
+    index: Mutex<Index>,
+}
+
+impl EncryptedStorage {
+    /// Opens an encrypted destination in a storage.
+    ///
+    /// The key is derived from the passphrase or key file of `config` and checked against the key file of the destination.
+    /// A destination without a key file gets a new one when the first file or folder is written.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The storage of the destination, in which the encrypted files are stored.
+    /// * `config` - A reference to the `EncryptionConfig` of the job.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if no passphrase or key file is given, or the key file cannot be read.
+    /// * Returns an error if the key does not match the key file of the destination.
+    /// * Returns an error if the key file or the index cannot be read or decrypted.
+    pub fn open(inner: Box<dyn Storage>, config: &EncryptionConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = read_secret(config, &inner.describe())?;
+
+        let (record, new_key) = match inner.stat(Path::new(KEY_FILE))? {
+            Some(_) => {
+                let mut text = String::new();
+                inner.open_read(Path::new(KEY_FILE))?.read_to_string(&mut text)?;
+                (serde_json::from_str::<KeyRecord>(&text)?, false)
+            }
+            None => {
+                let mut salt = [0; 16];
+                OsRng.fill_bytes(&mut salt);
+                let record = KeyRecord {
+                    memory: Params::DEFAULT_M_COST,
+                    iterations: Params::DEFAULT_T_COST,
+                    parallelism: Params::DEFAULT_P_COST,
+                    salt: STANDARD.encode(salt),
+                    check: String::new(),
+                };
+                (record, true)
+            }
+        };
+
+        let material = derive_key(&secret, &record)?;
+        let check = to_hex(&hmac(&material, KEY_CHECK));
+        if !new_key && check != record.check {
+            return Err(ERR_ENCRYPTION_KEY_MISMATCH.replace("{}", &inner.describe()).into());
+        }
+        let new_key = new_key.then(|| serde_json::to_string_pretty(&KeyRecord { check, ..record })).transpose()?;
+
+        let mut storage = EncryptedStorage {
+            inner,
+            content: XChaCha20Poly1305::new(Key::from_slice(&material[..32])),
+            names: config.encrypt_names.then(|| (XChaCha20Poly1305::new(Key::from_slice(&material[32..64])), material[64..].to_vec())),
+            new_key: Mutex::new(new_key),
+            index: Mutex::new(Index::default()),
+        };
+        if storage.inner.stat(Path::new(INDEX_FILE))?.is_some() {
+            let mut json = vec![];
+            storage.decrypt_reader(storage.inner.open_read(Path::new(INDEX_FILE))?, INDEX_FILE, INDEX_FILE)?.read_to_end(&mut json)?;
+            *storage.index.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()) = serde_json::from_slice(&json)?;
+        }
+        Ok(storage)
+    }
+
+    /// Returns the path of a file in the inner storage, with encrypted names if names are encrypted.
+    fn stored_path(&self, path: &Path) -> PathBuf {
+        let Some((cipher, mac_key)) = &self.names else {
+            return path.to_path_buf();
+        };
+        path.components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(self.stored_name(cipher, mac_key, &name.to_string_lossy())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the stored name of a file or folder name, recording long names in the index.
+    fn stored_name(&self, cipher: &XChaCha20Poly1305, mac_key: &[u8], name: &str) -> String {
+        let encrypted = encrypt_name(cipher, mac_key, name);
+        if encrypted.len() <= MAX_STORED_NAME_LEN {
+            return encrypted;
+        }
+        let stored = format!("{}{}", LONG_NAME_PREFIX, URL_SAFE_NO_PAD.encode(hmac(mac_key, name.as_bytes())));
+        let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !index.long_names.contains_key(&stored) {
+            index.long_names.insert(stored.clone(), name.to_string());
+            index.dirty = true;
+        }
+        stored
+    }
+
+    /// Returns the name of an entry of the inner storage, or `None` for files that do not belong to the backup.
+    fn entry_name(&self, parent: &Path, stored: &str) -> Option<String> {
+        if parent.as_os_str().is_empty() && [KEY_FILE, INDEX_FILE, INDEX_TEMPORARY_FILE].contains(&stored) {
+            return None;
+        }
+        match &self.names {
+            Some(_) if stored.starts_with(LONG_NAME_PREFIX) => {
+                self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).long_names.get(stored).cloned()
+            }
+            Some((cipher, _)) => decrypt_name(cipher, stored),
+            None => Some(stored.to_string()),
+        }
+    }
+
+    /// Converts the metadata of an encrypted file into the metadata of its source, taken from the index.
+    ///
+    /// Files missing from the index, or whose size does not match it, are reported without a modification time,
+    /// so they are copied again.
+    fn source_metadata(&self, path: &Path, metadata: StorageMetadata) -> StorageMetadata {
+        if metadata.is_dir {
+            return StorageMetadata { hash: None, ..metadata };
+        }
+        let index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match index.files.get(&storage_key(path)).filter(|entry| encrypted_len(entry.len) == metadata.len) {
+            Some(entry) => StorageMetadata { is_dir: false, len: entry.len, modified: entry.modified, hash: entry.hash.clone() },
+            None => StorageMetadata { is_dir: false, len: plain_len(metadata.len), modified: None, hash: None },
+        }
+    }
+
+    /// Updates the index entry of a file.
+    fn update_index(&self, path: &Path, update: impl FnOnce(&mut Option<IndexEntry>)) {
+        let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let key = storage_key(path);
+        let mut entry = index.files.remove(&key);
+        update(&mut entry);
+        if let Some(entry) = entry {
+            index.files.insert(key, entry);
+        }
+        index.dirty = true;
+    }
+
+    /// Writes the key file of a new destination, before anything else is written into it.
+    fn store_key(&self) -> io::Result<()> {
+        let mut new_key = self.new_key.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(record) = new_key.as_ref() {
+            self.inner.create_dir(Path::new(""))?;
+            let mut writer = self.inner.create_write(Path::new(KEY_FILE))?;
+            writer.write_all(record.as_bytes())?;
+            writer.finish()?;
+            info!("Created encryption key file: {}", self.inner.display_path(Path::new(KEY_FILE)));
+            *new_key = None;
+        }
+        Ok(())
+    }
+
+    /// Starts writing an encrypted file at a path of the inner storage.
+    ///
+    /// The chunks are authenticated with the path of the file in the encrypted storage, or with the name of the index.
+    fn encrypt_writer(&self, stored: &Path, source: Option<&Path>) -> io::Result<EncryptWriter<'_>> {
+        self.store_key()?;
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        let mut inner = self.inner.create_write(stored)?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&prefix)?;
+        Ok(EncryptWriter {
+            inner,
+            prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            hasher: Sha256::new(),
+            len: 0,
+            storage: self,
+            aad: source.map_or_else(|| INDEX_FILE.to_string(), storage_key),
+            source: source.map(Path::to_path_buf),
+        })
+    }
+
+    /// Starts decrypting a file read from the inner storage, authenticated with `aad` as by `encrypt_writer`.
+    fn decrypt_reader<'a>(&'a self, mut inner: Box<dyn Read + Send + 'a>, name: &str, aad: &str) -> io::Result<DecryptReader<'a>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, ERR_DECRYPT.replace("{}", name));
+        let mut header = [0; HEADER_SIZE];
+        inner.read_exact(&mut header).map_err(|_| invalid())?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid());
+        }
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        prefix.copy_from_slice(&header[MAGIC.len()..]);
+        Ok(DecryptReader {
+            inner,
+            cipher: &self.content,
+            prefix,
+            counter: 0,
+            plain: vec![],
+            position: 0,
+            lookahead: None,
+            finished: false,
+            name: name.to_string(),
+            aad: aad.to_string(),
+        })
+    }
+
+    /// Copies a file, or a folder and everything below it, to another path, encrypting the files for their new path.
+    fn reencrypt(&self, from: &Path, to: &Path, is_dir: bool) -> io::Result<()> {
+        if is_dir {
+            self.create_dir(to)?;
+            for entry in self.list(from)? {
+                self.reencrypt(&from.join(&entry.name), &to.join(&entry.name), entry.metadata.is_dir)?;
+            }
+            return Ok(());
+        }
+        let mut reader = self.open_read(from)?;
+        let mut writer = self.create_write(to)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.finish()
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        let mut entries = vec![];
+        for entry in self.inner.list(&self.stored_path(path))? {
+            let Some(name) = self.entry_name(path, &entry.name) else {
+                continue;
+            };
+            let metadata = self.source_metadata(&path.join(&name), entry.metadata);
+            entries.push(StorageEntry { name, metadata });
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<StorageMetadata>> {
+        Ok(self.inner.stat(&self.stored_path(path))?.map(|metadata| self.source_metadata(path, metadata)))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        let reader = self.inner.open_read(&self.stored_path(path))?;
+        Ok(Box::new(self.decrypt_reader(reader, &self.display_path(path), &storage_key(path))?))
+    }
+
+    fn create_write(&self, path: &Path) -> io::Result<Box<dyn StorageWriter + '_>> {
+        Ok(Box::new(self.encrypt_writer(&self.stored_path(path), Some(path))?))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.store_key()?;
+        self.inner.create_dir(&self.stored_path(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // 内容はパスと合わせて認証しているため、新しいパスで暗号化し直す
+        let metadata = self.inner.stat(&self.stored_path(from))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.display_path(from)))?;
+        self.reencrypt(from, to, metadata.is_dir)?;
+        self.inner.delete(&self.stored_path(from))?;
+        let (from, to) = (storage_key(from), storage_key(to));
+        let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        index.files.remove(&to);
+        let moved: Vec<String> = index.files.keys().filter(|key| is_below(key, &from)).cloned().collect();
+        for key in moved {
+            if let Some(entry) = index.files.remove(&key) {
+                index.files.insert(format!("{}{}", to, &key[from.len()..]), entry);
+            }
+        }
+        index.dirty = true;
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.inner.delete(&self.stored_path(path))?;
+        let key = storage_key(path);
+        let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        index.files.retain(|file, _| !is_below(file, &key));
+        index.dirty = true;
+        Ok(())
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        // 暗号化したファイルの更新日時は使わず、索引に記録する
+        let stored = self.inner.stat(&self.stored_path(path))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.display_path(path)))?;
+        self.update_index(path, |entry| {
+            let entry = entry.get_or_insert_with(|| IndexEntry { len: plain_len(stored.len), modified: None, hash: None });
+            entry.modified = Some(modified);
+        });
+        Ok(())
+    }
+
+    fn display_path(&self, path: &Path) -> String {
+        match self.names {
+            Some(_) => format!("{}/{}", self.describe().trim_end_matches(['/', '\\']), storage_key(path)),
+            None => self.inner.display_path(path),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let json = {
+            let mut index = self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !index.dirty {
+                return Ok(());
+            }
+            index.dirty = false;
+            serde_json::to_vec(&*index)?
+        };
+        let temporary = Path::new(INDEX_TEMPORARY_FILE);
+        let mut writer = self.encrypt_writer(temporary, None)?;
+        writer.write_all(&json)?;
+        Box::new(writer).finish()?;
+        self.inner.rename(temporary, Path::new(INDEX_FILE))?;
+        info!("Wrote encrypted index: {}", self.inner.display_path(Path::new(INDEX_FILE)));
+        Ok(())
+    }
+}
+
+impl Drop for EncryptedStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to write the encrypted index of {} : {}", self.inner.describe(), e);
+        }
+    }
+}
+
+/// A file being encrypted into the inner storage.
+///
+/// The plaintext is buffered until a full chunk is available, so the last chunk is only written by `finish`.
+struct EncryptWriter<'a> {
+    inner: Box<dyn StorageWriter + 'a>,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+    hasher: Sha256,
+    len: u64,
+    storage: &'a EncryptedStorage,
+    /// Associated data authenticated with each chunk.
+    aad: String,
+    /// Path of the file in the encrypted storage, recorded in the index once written. `None` for the index itself.
+    source: Option<PathBuf>,
+}
+
+impl EncryptWriter<'_> {
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let payload = Payload { msg: self.buffer.as_slice(), aad: self.aad.as_bytes() };
+        let encrypted = self.storage.content.encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::other(ERR_ENCRYPT))?;
+        self.inner.write_all(&encrypted)?;
+        self.buffer.clear();
+        self.counter = self.counter.checked_add(1).ok_or_else(|| io::Error::other(ERR_ENCRYPT))?;
+        Ok(())
+    }
+}
+
+impl Write for EncryptWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // 最後のチャンクかどうかは次の書き込みまで分からないため、満杯のチャンクは次の書き込みで暗号化する
+        if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.write_chunk(false)?;
+        }
+        let take = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        self.hasher.update(&buf[..take]);
+        self.len += take as u64;
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for EncryptWriter<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.write_chunk(true)?;
+        let EncryptWriter { inner, hasher, len, storage, source, .. } = *self;
+        inner.finish()?;
+        if let Some(source) = source {
+            let hash = to_hex(&hasher.finalize());
+            storage.update_index(&source, |entry| *entry = Some(IndexEntry { len, modified: None, hash: Some(hash) }));
+        }
+        Ok(())
+    }
+}
+
+/// A file being decrypted from the inner storage.
+struct DecryptReader<'a> {
+    inner: Box<dyn Read + Send + 'a>,
+    cipher: &'a XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    /// Plaintext of the current chunk and position of the next byte to return.
+    plain: Vec<u8>,
+    position: usize,
+    /// First byte of the next chunk, read to find out whether the current chunk is the last.
+    lookahead: Option<u8>,
+    finished: bool,
+    name: String,
+    aad: String,
+}
+
+impl DecryptReader<'_> {
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        chunk.extend(self.lookahead.take());
+        let wanted = (CHUNK_SIZE + TAG_SIZE - chunk.len()) as u64;
+        self.inner.by_ref().take(wanted).read_to_end(&mut chunk)?;
+
+        let mut last = chunk.len() < CHUNK_SIZE + TAG_SIZE;
+        if !last {
+            let mut byte = [0];
+            last = loop {
+                match self.inner.read(&mut byte) {
+                    Ok(0) => break true,
+                    Ok(_) => {
+                        self.lookahead = Some(byte[0]);
+                        break false;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+        }
+        self.decrypt_chunk(chunk, last)
+    }
+
+    fn decrypt_chunk(&mut self, chunk: Vec<u8>, last: bool) -> io::Result<()> {
+        // 途中で切れたファイルは、最後のチャンクの印がないため復号に失敗する
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let payload = Payload { msg: chunk.as_slice(), aad: self.aad.as_bytes() };
+        self.plain = self.cipher.decrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, ERR_DECRYPT.replace("{}", &self.name)))?;
+        self.position = 0;
+        self.counter = self.counter.wrapping_add(1);
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl Read for DecryptReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let read = buf.len().min(self.plain.len() - self.position);
+        buf[..read].copy_from_slice(&self.plain[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Returns the passphrase or the contents of the key file of a job.
+fn read_secret(config: &EncryptionConfig, destination: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let secret = match (&config.key_file, &config.passphrase) {
+        (Some(key_file), _) => fs::read(key_file).map_err(|e| ERR_READ_KEY_FILE.replacen("{}", key_file, 1).replacen("{}", &e.to_string(), 1))?,
+        (None, Some(passphrase)) => passphrase.clone().into_bytes(),
+        (None, None) => std::env::var(PASSPHRASE_VARIABLE).unwrap_or_default().into_bytes(),
+    };
+    if secret.is_empty() {
+        return Err(ERR_ENCRYPTION_SECRET.replace("{}", destination).into());
+    }
+    Ok(secret)
+}
+
+/// Derives the key material from a secret with the parameters of a key file.
+fn derive_key(secret: &[u8], record: &KeyRecord) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let salt = STANDARD.decode(&record.salt)?;
+    let params = Params::new(record.memory, record.iterations, record.parallelism, Some(KEY_MATERIAL_SIZE))
+        .map_err(|e| e.to_string())?;
+    let mut material = vec![0; KEY_MATERIAL_SIZE];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(secret, &salt, &mut material)
+        .map_err(|e| e.to_string())?;
+    Ok(material)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns the nonce of a chunk: the random prefix of the file, the chunk number and a last-chunk flag.
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> [u8; 24] {
+    let mut nonce = [0; 24];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = u8::from(last);
+    nonce
+}
+
+/// Encrypts a file or folder name.
+///
+/// The nonce is the MAC of the name, so a name is always encrypted to the same text, which can be looked up.
+/// The result is Base64 without padding, using `-` and `_`, so it is a valid name on every file system.
+fn encrypt_name(cipher: &XChaCha20Poly1305, mac_key: &[u8], name: &str) -> String {
+    let nonce = hmac(mac_key, name.as_bytes());
+    let nonce = XNonce::from_slice(&nonce[..24]);
+    let encrypted = cipher.encrypt(nonce, name.as_bytes()).expect("names are shorter than the cipher limit");
+    URL_SAFE_NO_PAD.encode([nonce.as_slice(), &encrypted].concat())
+}
+
+/// Decrypts a name written by `encrypt_name`, or returns `None` if it is not one.
+fn decrypt_name(cipher: &XChaCha20Poly1305, stored: &str) -> Option<String> {
+    let bytes = URL_SAFE_NO_PAD.decode(stored).ok()?;
+    if bytes.len() < 24 + TAG_SIZE {
+        return None;
+    }
+    let (nonce, encrypted) = bytes.split_at(24);
+    let name = cipher.decrypt(XNonce::from_slice(nonce), encrypted).ok()?;
+    String::from_utf8(name).ok()
+}
+
+/// Returns `true` if `key` is `parent` or a path below it.
+fn is_below(key: &str, parent: &str) -> bool {
+    parent.is_empty() || key == parent || key.strip_prefix(parent).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Returns the size of the encrypted file of a plaintext of `len` bytes.
+fn encrypted_len(len: u64) -> u64 {
+    let chunks = len.div_ceil(CHUNK_SIZE as u64).max(1);
+    HEADER_SIZE as u64 + len + chunks * TAG_SIZE as u64
+}
+
+/// Returns the size of the plaintext of an encrypted file of `len` bytes.
+fn plain_len(len: u64) -> u64 {
+    let body = len.saturating_sub(HEADER_SIZE as u64);
+    let chunks = body.div_ceil((CHUNK_SIZE + TAG_SIZE) as u64).max(1);
+    body.saturating_sub(chunks * TAG_SIZE as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{exercise_storage, write_and_read};
+    use crate::storage::LocalStorage;
+
+    /// Creates an encrypted storage in a local folder with a fixed key, without the cost of the key derivation.
+    fn test_storage(root: &Path, encrypt_names: bool) -> EncryptedStorage {
+        let material: Vec<u8> = (0..KEY_MATERIAL_SIZE as u8).collect();
+        EncryptedStorage {
+            inner: Box::new(LocalStorage::new(root)),
+            content: XChaCha20Poly1305::new(Key::from_slice(&material[..32])),
+            names: encrypt_names.then(|| (XChaCha20Poly1305::new(Key::from_slice(&material[32..64])), material[64..].to_vec())),
+            new_key: Mutex::new(None),
+            index: Mutex::new(Index::default()),
+        }
+    }
+
+    fn read(storage: &EncryptedStorage, path: &str) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        storage.open_read(Path::new(path))?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn round_trips_chunk_boundaries() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = test_storage(root.path(), false);
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let path = Path::new("file.bin");
+            assert_eq!(write_and_read(&storage, path, &contents), contents, "{} bytes", len);
+            let stored = fs::metadata(root.path().join(path)).unwrap().len();
+            assert_eq!(stored, encrypted_len(len as u64), "{} bytes", len);
+            assert_eq!(storage.stat(path).unwrap().map(|metadata| metadata.len), Some(len as u64));
+        }
+    }
+
+    #[test]
+    fn encrypted_len_and_plain_len_are_inverse() {
+        let chunk = CHUNK_SIZE as u64;
+        for len in [0, 1, 100, chunk - 1, chunk, chunk + 1, 2 * chunk, 10 * chunk + 7] {
+            assert_eq!(plain_len(encrypted_len(len)), len, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn truncated_or_modified_files_fail_to_decrypt() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = test_storage(root.path(), false);
+        let contents = vec![1u8; 2 * CHUNK_SIZE + 10];
+        write_and_read(&storage, Path::new("file.bin"), &contents);
+        let stored = root.path().join("file.bin");
+        let encrypted = fs::read(&stored).unwrap();
+
+        // 最後のチャンクをまるごと失った場合も、チャンクの途中で切れた場合も失敗する
+        for len in [HEADER_SIZE + 2 * (CHUNK_SIZE + TAG_SIZE), encrypted.len() - 1, HEADER_SIZE + 5, 3] {
+            fs::write(&stored, &encrypted[..len]).unwrap();
+            assert_eq!(read(&storage, "file.bin").unwrap_err().kind(), io::ErrorKind::InvalidData, "{} bytes", len);
+        }
+
+        let mut modified = encrypted.clone();
+        modified[HEADER_SIZE + 100] ^= 1;
+        fs::write(&stored, &modified).unwrap();
+        assert!(read(&storage, "file.bin").is_err());
+
+        fs::write(&stored, &encrypted).unwrap();
+        assert_eq!(read(&storage, "file.bin").unwrap(), contents);
+    }
+
+    #[test]
+    fn swapped_files_fail_to_decrypt() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = test_storage(root.path(), false);
+        write_and_read(&storage, Path::new("a.txt"), b"alpha");
+        write_and_read(&storage, Path::new("b.txt"), b"bravo");
+        fs::rename(root.path().join("a.txt"), root.path().join("c.txt")).unwrap();
+        fs::rename(root.path().join("b.txt"), root.path().join("a.txt")).unwrap();
+        assert_eq!(read(&storage, "a.txt").unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::rename(root.path().join("a.txt"), root.path().join("b.txt")).unwrap();
+        fs::rename(root.path().join("c.txt"), root.path().join("a.txt")).unwrap();
+        assert_eq!(read(&storage, "a.txt").unwrap(), b"alpha");
+
+        // 名前の変更では新しいパスで暗号化し直すので読める
+        storage.rename(Path::new("b.txt"), Path::new("d.txt")).unwrap();
+        assert_eq!(read(&storage, "d.txt").unwrap(), b"bravo");
+        assert!(!root.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn encrypts_names() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = test_storage(root.path(), true);
+        let (cipher, mac_key) = storage.names.as_ref().unwrap();
+
+        for name in ["a.txt", "日本語のファイル名.docx", ""] {
+            let encrypted = encrypt_name(cipher, mac_key, name);
+            assert_eq!(encrypted, encrypt_name(cipher, mac_key, name));
+            assert!(encrypted.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+            assert_eq!(decrypt_name(cipher, &encrypted).as_deref(), Some(name));
+        }
+        assert_ne!(encrypt_name(cipher, mac_key, "a.txt"), encrypt_name(cipher, mac_key, "b.txt"));
+        assert_eq!(decrypt_name(cipher, "not-an-encrypted-name"), None);
+
+        exercise_storage(&storage);
+    }
+
+    #[test]
+    fn stores_long_names_in_index() {
+        let root = tempfile::tempdir().unwrap();
+        let long = format!("{}.txt", "長".repeat(80));
+        {
+            let storage = test_storage(root.path(), true);
+            storage.create_dir(Path::new(&long)).unwrap();
+            write_and_read(&storage, &Path::new(&long).join(&long), b"long");
+            write_and_read(&storage, Path::new("short.txt"), b"short");
+        }
+        for entry in fs::read_dir(root.path()).unwrap() {
+            assert!(entry.unwrap().file_name().len() <= MAX_STORED_NAME_LEN);
+        }
+
+        // 索引を読み直しても元の名前に戻る
+        let storage = test_storage(root.path(), true);
+        let mut json = vec![];
+        storage.decrypt_reader(storage.inner.open_read(Path::new(INDEX_FILE)).unwrap(), INDEX_FILE, INDEX_FILE).unwrap()
+            .read_to_end(&mut json).unwrap();
+        *storage.index.lock().unwrap() = serde_json::from_slice(&json).unwrap();
+        let mut names: Vec<String> = storage.list(Path::new("")).unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        assert_eq!(names, ["short.txt".to_string(), long.clone()]);
+        assert_eq!(read(&storage, &format!("{}/{}", long, long)).unwrap(), b"long");
+    }
+
+    #[test]
+    fn detects_wrong_passphrase() {
+        let root = tempfile::tempdir().unwrap();
+        let config = |passphrase: &str| EncryptionConfig { passphrase: Some(passphrase.to_string()), ..Default::default() };
+        {
+            let storage = EncryptedStorage::open(Box::new(LocalStorage::new(root.path())), &config("correct")).unwrap();
+            write_and_read(&storage, Path::new("a.txt"), b"alpha");
+        }
+        assert!(EncryptedStorage::open(Box::new(LocalStorage::new(root.path())), &config("wrong")).is_err());
+        let storage = EncryptedStorage::open(Box::new(LocalStorage::new(root.path())), &config("correct")).unwrap();
+        assert_eq!(read(&storage, "a.txt").unwrap(), b"alpha");
+        assert_eq!(storage.stat(Path::new("a.txt")).unwrap().and_then(|metadata| metadata.hash), Some(to_hex(&Sha256::digest(b"alpha"))));
+    }
+}
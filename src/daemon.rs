@@ -0,0 +1,295 @@
+//! # Daemon Module
+//!
+//! This module runs backup jobs on their `schedule` (see `crate::schedule`) until the program is stopped.
+//! A job whose previous run is still in progress when it is due again is skipped.
+//!
+//! The start time, end time and error of the last run of each job are stored in a state file
+//! (`<config stem>.state.json` next to the configuration file), so runs missed while the daemon was not running,
+//! for example during a reboot, are caught up once when it starts again.
+//!
+//! With `--listen`, the daemon also serves a control API (see `crate::api`) to inspect, run, pause and cancel jobs.
+
+use crate::api::{read_token, serve_api};
+use crate::backup::backup_to_ssd;
+use crate::commands::DaemonArgs;
+use crate::config::{BtsConfig, BtsConfigWrapper};
+use crate::filter::FileFilter;
+use crate::messages::*;
+use crate::utils::{count_files_recursive, CopyOptions};
+use chrono::{DateTime, Local};
+use indicatif::ProgressBar;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::any::Any;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Interval at which the daemon checks whether jobs are due.
+const TICK: Duration = Duration::from_secs(1);
+
+/// State of the daemon saved between runs.
+#[derive(Deserialize,Serialize,Default)]
+pub struct DaemonState {
+    /// State of each job, keyed by `job_key`.
+    #[serde(default)]
+    pub jobs : BTreeMap<String, JobState>,
+}
+
+/// State of the last run of a job.
+#[derive(Deserialize,Serialize,Default,Clone)]
+pub struct JobState {
+    /// Start time of the last run (RFC 3339).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run : Option<String>,
+    /// End time of the last finished run (RFC 3339).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_finished : Option<String>,
+    /// Error of the last finished run, `None` if it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error : Option<String>,
+}
+
+/// Runtime state of a job, shared with the control API.
+pub struct JobControl {
+    /// Key of the job in the state file.
+    pub key : String,
+    /// Schedule of the job as written in the configuration file.
+    pub schedule : Option<String>,
+    /// Next time the job is due, `None` if it has no schedule.
+    pub next_run : Option<DateTime<Local>>,
+    /// `true` while the job is running.
+    pub running : bool,
+    /// `true` if scheduled runs are skipped.
+    pub paused : bool,
+    /// `true` if the job should start as soon as possible.
+    pub triggered : bool,
+    /// Flag cancelling the current run.
+    pub cancel : Arc<AtomicBool>,
+    /// Progress of the current run: files processed and total.
+    pub progress : Option<Arc<ProgressBar>>,
+}
+
+/// Runtime state of the daemon, shared with the control API.
+pub struct Daemon {
+    /// Jobs, in the order of the configuration file.
+    pub jobs : Vec<JobControl>,
+    /// Results of the last runs, saved to the state file.
+    pub state : DaemonState,
+}
+
+/// Returns the path of the daemon state file for a configuration file.
+pub fn default_state_path(config_path : &Path) -> PathBuf {
+    config_path.with_extension("state.json")
+}
+
+/// Returns the key identifying a job in the state file: its name, or its source and destination.
+///
+/// The destination is taken before template expansion, so dated destinations keep the same key.
+pub fn job_key(config : &BtsConfig) -> String {
+    let destination = if config.destination_template.is_empty() { &config.destination } else { &config.destination_template };
+    match &config.name {
+        Some(name) => name.clone(),
+        None => format!("{} -> {}", config.source, destination),
+    }
+}
+
+/// Runs the scheduled backup jobs until the program is stopped.
+///
+/// Jobs without a schedule only run when started through the control API.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `args` - A reference to the `DaemonArgs` given on the command line.
+/// * `state_path` - A reference to the `Path` of the state file.
+///
+/// # Errors
+///
+/// * Returns an error if no job has a schedule and the control API is disabled.
+/// * Returns an error if the control API cannot listen on the given address, or needs a token to listen on it.
+/// * Returns an error if the state file cannot be written.
+pub fn execute_daemon(
+    bts_config_wrapper : &BtsConfigWrapper,
+    args : &DaemonArgs,
+    state_path : &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let state = load_state(state_path);
+    let now = Local::now();
+
+    let mut jobs: Vec<JobControl> = vec![];
+    for config in &bts_config_wrapper.configs {
+        let key = job_key(config);
+        // 前回の実行から次の予定時刻を求める。停止中に過ぎていれば起動直後に一度だけ実行する
+        let last_run = state.jobs.get(&key)
+            .and_then(|job| job.last_run.as_deref())
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Local));
+        let next_run = config.schedule.as_ref().and_then(|schedule| match last_run {
+            Some(last_run) => schedule.next_after(last_run),
+            None => Some(now),
+        });
+        if let Some(next_run) = next_run {
+            println!("{}", MSG_DAEMON_SCHEDULED.replacen("{}", &key, 1).replacen("{}", &next_run.to_rfc3339(), 1));
+        } else if config.schedule.is_some() {
+            warn!("Job {} never runs", key);
+        }
+        jobs.push(JobControl {
+            key,
+            schedule: config.schedule.clone().map(String::from),
+            next_run,
+            running: false,
+            paused: false,
+            triggered: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+            progress: None,
+        });
+    }
+    if args.listen.is_none() && jobs.iter().all(|job| job.next_run.is_none()) {
+        return Err(ERR_NO_SCHEDULED_JOBS.into());
+    }
+
+    let daemon = Arc::new(Mutex::new(Daemon { jobs, state }));
+    if let Some(address) = &args.listen {
+        let token = read_token(args.token_file.as_deref().map(Path::new))?;
+        let bound = serve_api(address, token, Arc::clone(&daemon))?;
+        println!("{}", MSG_API_LISTENING.replace("{}", &bound.to_string()));
+    }
+
+    let (tx, rx) = mpsc::channel::<(usize, Result<(), String>)>();
+    loop {
+        start_due_jobs(bts_config_wrapper, &daemon, state_path, &tx)?;
+
+        match rx.recv_timeout(TICK) {
+            Ok((index, result)) => {
+                let mut daemon = lock(&daemon);
+                let Daemon { jobs, state } = &mut *daemon;
+                let job = &mut jobs[index];
+                job.running = false;
+                job.progress = None;
+                let job_state = state.jobs.entry(job.key.clone()).or_default();
+                job_state.last_finished = Some(Local::now().to_rfc3339());
+                match &result {
+                    Ok(()) => {
+                        info!("Finished scheduled job: {}", job.key);
+                        println!("{}", MSG_DAEMON_FINISHED.replace("{}", &job.key));
+                    }
+                    Err(e) => {
+                        error!("Scheduled job {} failed: {}", job.key, e);
+                        println!("{}", MSG_DAEMON_FAILED.replacen("{}", &job.key, 1).replacen("{}", e, 1));
+                    }
+                }
+                job_state.last_error = result.err();
+                save_state(state_path, state)?;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // 送信側はこのループが保持しているため通常は起こらない
+            Err(RecvTimeoutError::Disconnected) => return Err(ERR_DAEMON_CHANNEL_CLOSED.into()),
+        }
+    }
+}
+
+/// Starts the jobs that are due or were triggered through the control API.
+fn start_due_jobs(
+    bts_config_wrapper : &BtsConfigWrapper,
+    daemon : &Mutex<Daemon>,
+    state_path : &Path,
+    tx : &mpsc::Sender<(usize, Result<(), String>)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = Local::now();
+    let mut daemon = lock(daemon);
+    let Daemon { jobs, state } = &mut *daemon;
+
+    for (index, job) in jobs.iter_mut().enumerate() {
+        let config = &bts_config_wrapper.configs[index];
+        let due = job.next_run.is_some_and(|next_run| next_run <= now);
+        if due {
+            job.next_run = config.schedule.as_ref().and_then(|schedule| schedule.next_after(now));
+        }
+        if !(job.triggered || due && !job.paused) {
+            continue;
+        }
+        if job.running {
+            // 手動実行の要求は、実行中の回が終わった後に持ち越す
+            if due {
+                warn!("Skipping {} : previous run is still in progress", job.key);
+                println!("{}", MSG_DAEMON_SKIPPED.replace("{}", &job.key));
+            }
+            continue;
+        }
+
+        info!("Starting scheduled job: {}", job.key);
+        println!("{}", MSG_DAEMON_STARTED.replace("{}", &job.key));
+        job.running = true;
+        job.triggered = false;
+        job.cancel.store(false, Ordering::Relaxed);
+        let progress_bar = Arc::new(ProgressBar::hidden());
+        job.progress = Some(Arc::clone(&progress_bar));
+        state.jobs.entry(job.key.clone()).or_default().last_run = Some(now.to_rfc3339());
+        save_state(state_path, state)?;
+
+        let config = config.clone();
+        let filter = FileFilter::new(&config, bts_config_wrapper);
+        let options = CopyOptions {
+            cancel: Some(Arc::clone(&job.cancel)),
+            ..CopyOptions::new(&config)
+        };
+        let tx = tx.clone();
+        thread::spawn(move || {
+            progress_bar.set_length(count_files_recursive(Path::new(&config.source), &filter).unwrap_or(0));
+            // パニックしても完了を通知し、実行中のままにならないようにする
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                backup_to_ssd(&config, &filter, &options, &*progress_bar).map_err(|e| e.to_string())
+            }))
+            .unwrap_or_else(|payload| Err(ERR_JOB_PANICKED.replace("{}", &panic_message(&*payload))));
+            tx.send((index, result)).ok();
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the message of a panic payload.
+fn panic_message(payload : &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Locks the daemon state, recovering it if a thread panicked while holding the lock.
+pub fn lock(daemon : &Mutex<Daemon>) -> std::sync::MutexGuard<'_, Daemon> {
+    daemon.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Loads the state file, or returns an empty state if it does not exist or cannot be read.
+pub fn load_state(path : &Path) -> DaemonState {
+    if !path.exists() {
+        return DaemonState::default();
+    }
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            warn!("Ignoring unreadable state file {} : {}", path.display(), e);
+            DaemonState::default()
+        })
+}
+
+/// Writes the state file.
+///
+/// # Errors
+///
+/// * Returns an error if the file cannot be written.
+fn save_state(path : &Path, state : &DaemonState) -> Result<(), Box<dyn std::error::Error>> {
+    // 書き込み中に停止しても既存の状態が壊れないように一時ファイル経由で置き換える
+    let temporary_path = path.with_extension("tmp");
+    fs::write(&temporary_path, serde_json::to_string_pretty(state)?)?;
+    fs::rename(&temporary_path, path)?;
+    Ok(())
+}
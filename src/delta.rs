@@ -0,0 +1,187 @@
+//! # Delta Module
+//!
+//! This module updates a large file in place when only parts of it changed, such as a virtual machine image.
+//! The source and the existing destination are read block by block, and only the blocks that differ are written,
+//! so a few changed blocks of a 4 GB image do not rewrite the whole file.
+//!
+//! The destination is truncated or extended to the size of the source, then read back and its SHA-256 hash
+//! compared with the hash of the source computed while reading it.
+//! The update is not atomic: if it is interrupted, the destination keeps its old modification time,
+//! so the next run sees it as changed and updates it again.
+//!
+//! For destinations on another machine (see [`crate::remote`]), the server sends the hashes of the blocks of its copy
+//! ([`block_hashes`]) and only the blocks whose hashes differ are sent over the network.
+
+use crate::hash::{hash_reader, to_hex};
+use crate::messages::*;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the blocks compared and rewritten.
+pub const BLOCK_SIZE: usize = 256 * 1024;
+
+/// Result of a delta update.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeltaReport {
+    /// Number of blocks of the source.
+    pub blocks: u64,
+    /// Number of blocks written into the destination.
+    pub changed_blocks: u64,
+    /// Number of bytes written into the destination.
+    pub bytes_written: u64,
+}
+
+/// Updates an existing file to the contents of a source file, writing only the blocks that differ.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the source file.
+/// * `destination` - A reference to the `Path` of the existing destination file.
+///
+/// # Returns
+///
+/// Returns `Ok(DeltaReport)` with the number of blocks written, or `Err(io::Error)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if either file cannot be read, or the destination cannot be written.
+/// * Returns an error of kind `InvalidData` if the updated destination does not have the hash of the source.
+pub fn update_file(source: &Path, destination: &Path) -> io::Result<DeltaReport> {
+    let mut source_file = File::open(source)?;
+    let mut file = OpenOptions::new().read(true).write(true).open(destination)?;
+    let mut hasher = Sha256::new();
+    let mut source_block = vec![0; BLOCK_SIZE];
+    let mut destination_block = vec![0; BLOCK_SIZE];
+    let mut report = DeltaReport::default();
+    let mut offset = 0;
+
+    loop {
+        let read = read_block(&mut source_file, &mut source_block)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&source_block[..read]);
+
+        // 読み込んだ位置が次のブロックの先頭になるため、書き込む時だけ位置を戻す
+        let existing = read_block(&mut file, &mut destination_block[..read])?;
+        if existing != read || source_block[..read] != destination_block[..read] {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&source_block[..read])?;
+            report.changed_blocks += 1;
+            report.bytes_written += read as u64;
+        }
+        report.blocks += 1;
+        offset += read as u64;
+        if read < BLOCK_SIZE {
+            break;
+        }
+    }
+    file.set_len(offset)?;
+    file.sync_all()?;
+
+    // 書き換えた結果をバックアップ元のハッシュと比較する
+    file.seek(SeekFrom::Start(0))?;
+    if hash_reader(&mut file)? != to_hex(&hasher.finalize()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, ERR_DELTA_CHECKSUM.replace("{}", &destination.display().to_string())));
+    }
+    Ok(report)
+}
+
+/// Returns the SHA-256 hash of each block of a file, in order. The last block may be shorter than `BLOCK_SIZE`.
+///
+/// # Errors
+///
+/// * Returns an error if reading fails.
+pub fn block_hashes(mut reader: impl Read) -> io::Result<Vec<String>> {
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut hashes = vec![];
+    loop {
+        let read = read_block(&mut reader, &mut block)?;
+        if read == 0 {
+            break;
+        }
+        hashes.push(to_hex(&Sha256::digest(&block[..read])));
+        if read < BLOCK_SIZE {
+            break;
+        }
+    }
+    Ok(hashes)
+}
+
+/// Reads until the buffer is full or the end of the file. Returns the number of bytes read.
+pub fn read_block(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Updates a destination holding `old` to `new` and checks that the destination then holds `new`.
+    fn update(old: &[u8], new: &[u8]) -> DeltaReport {
+        let folder = tempfile::tempdir().unwrap();
+        let (source, destination) = (folder.path().join("source.bin"), folder.path().join("destination.bin"));
+        fs::write(&source, new).unwrap();
+        fs::write(&destination, old).unwrap();
+        let report = update_file(&source, &destination).unwrap();
+        assert!(fs::read(&destination).unwrap() == new, "destination differs from source");
+        report
+    }
+
+    #[test]
+    fn rewrites_only_changed_middle_block() {
+        let old = contents(BLOCK_SIZE * 3 + 10);
+        let mut new = old.clone();
+        new[BLOCK_SIZE + 5] ^= 0xff;
+        let report = update(&old, &new);
+        assert_eq!((report.blocks, report.changed_blocks, report.bytes_written), (4, 1, BLOCK_SIZE as u64));
+    }
+
+    #[test]
+    fn truncates_file_that_shrank() {
+        let old = contents(BLOCK_SIZE * 3);
+        let report = update(&old, &old[..BLOCK_SIZE + 100]);
+        // 残る部分は同じため何も書かず、切り詰めるだけになる
+        assert_eq!((report.blocks, report.changed_blocks), (2, 0));
+        assert_eq!(update(&old, b"").blocks, 0);
+    }
+
+    #[test]
+    fn extends_file_that_grew() {
+        let old = contents(BLOCK_SIZE + 100);
+        let new = contents(BLOCK_SIZE * 3 + 7);
+        let report = update(&old, &new);
+        assert_eq!((report.blocks, report.changed_blocks), (4, 3));
+    }
+
+    #[test]
+    fn writes_nothing_for_unchanged_file() {
+        let old = contents(BLOCK_SIZE * 2 + 1);
+        let report = update(&old, &old);
+        assert_eq!((report.blocks, report.changed_blocks, report.bytes_written), (3, 0, 0));
+    }
+
+    #[test]
+    fn hashes_blocks() {
+        let data = contents(BLOCK_SIZE + 1);
+        let hashes = block_hashes(data.as_slice()).unwrap();
+        assert_eq!(hashes, [to_hex(&Sha256::digest(&data[..BLOCK_SIZE])), to_hex(&Sha256::digest(&data[BLOCK_SIZE..]))]);
+        assert!(block_hashes(io::empty()).unwrap().is_empty());
+    }
+}
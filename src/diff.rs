@@ -0,0 +1,298 @@
+//! # Diff Module
+//!
+//! This module compares two directory trees (or the source and destination of backup jobs) and reports
+//! added, removed, modified and type-changed entries, as text or JSON.
+//! Both trees are walked with the same filtering as the backup (see `crate::utils::walk_tree`).
+
+use crate::commands::{DiffArgs, DiffFormat};
+use crate::config::{BtsConfig, BtsConfigWrapper};
+use crate::filter::FileFilter;
+use crate::hash::hash_file;
+use crate::messages::*;
+use crate::repository::repository_path;
+use crate::storage::same_time;
+use crate::utils::walk_tree;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Precision at which modification times are compared, that of FAT and exFAT file systems.
+const TIME_PRECISION: Duration = Duration::from_secs(2);
+
+/// Kind of a tree entry.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// Kind of difference.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffStatus {
+    /// Only in the right tree.
+    Added,
+    /// Only in the left tree.
+    Removed,
+    /// A file in both trees with different contents.
+    Modified,
+    /// A file in one tree and a directory in the other.
+    TypeChanged,
+}
+
+/// A difference between the two trees.
+#[derive(Serialize)]
+pub struct DiffEntry {
+    pub status: DiffStatus,
+    /// Path relative to the roots of the trees.
+    pub path: PathBuf,
+    /// Kind of the entry in the right tree, or in the left tree if removed.
+    pub kind: EntryKind,
+}
+
+/// Differences between two trees.
+#[derive(Serialize)]
+pub struct TreeDiff {
+    pub left: PathBuf,
+    pub right: PathBuf,
+    pub entries: Vec<DiffEntry>,
+}
+
+/// An entry collected from a tree.
+struct EntryInfo {
+    kind: EntryKind,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Compares the trees given on the command line, or the source and destination of each selected job, and prints the differences.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `args` - A reference to the `DiffArgs` given on the command line.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found, or its destination is encrypted.
+/// * Returns an error if a tree does not exist or cannot be walked.
+/// * Returns an error if the JSON output cannot be written.
+pub fn execute_diff(bts_config_wrapper: &BtsConfigWrapper, args: &DiffArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let pairs: Vec<(PathBuf, PathBuf, FileFilter)> = match (&args.left, &args.right) {
+        (Some(left), Some(right)) => {
+            let config = BtsConfig {
+                source: left.clone(),
+                exclude: args.exclude.clone(),
+                ..Default::default()
+            };
+            vec![(PathBuf::from(left), PathBuf::from(right), FileFilter::new(&config, bts_config_wrapper))]
+        }
+        _ => bts_config_wrapper.select(args.job.as_deref())?
+            .into_iter()
+            .map(|config| {
+                // 暗号化したバックアップ先はファイル名も内容も元と比較できない
+                if config.encryption.is_some() {
+                    return Err(ERR_ENCRYPTED_UNSUPPORTED.replace("{}", &config.destination));
+                }
+                // リポジトリはフォルダとして比較できない
+                if repository_path(&config.destination).is_some() {
+                    return Err(ERR_REPOSITORY_UNSUPPORTED.replace("{}", &config.destination));
+                }
+                let mut config = config.clone();
+                config.exclude.extend(args.exclude.iter().cloned());
+                let filter = FileFilter::new(&config, bts_config_wrapper);
+                Ok((PathBuf::from(&config.source), PathBuf::from(&config.destination), filter))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut diffs = vec![];
+    for (left, right, filter) in pairs {
+        diffs.push(diff_trees(&left, &right, &filter, args.hash)?);
+    }
+
+    match args.format {
+        DiffFormat::Json => println!("{}", serde_json::to_string_pretty(&diffs)?),
+        DiffFormat::Text => {
+            for diff in &diffs {
+                print_diff(diff);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Compares two trees.
+///
+/// Files are modified when their sizes differ, or, when `by_hash` is `false`, their modification times differ
+/// at a precision of 2 seconds (so copies on FAT or exFAT drives are not reported);
+/// with `by_hash`, files of the same size are compared by their SHA-256 hash.
+/// Entries below an added or removed directory are not listed separately.
+///
+/// # Arguments
+///
+/// * `left` - A reference to the `Path` of the left tree.
+/// * `right` - A reference to the `Path` of the right tree.
+/// * `filter` - A reference to the `FileFilter` whose root is `left`. Only its path patterns are applied to `right` (see `FileFilter::for_destination`).
+/// * `by_hash` - Compare file contents by hash instead of modification time.
+///
+/// # Errors
+///
+/// * Returns an error if the left tree does not exist.
+/// * Returns an error if a tree cannot be walked or a file cannot be hashed.
+pub fn diff_trees(left: &Path, right: &Path, filter: &FileFilter, by_hash: bool) -> Result<TreeDiff, Box<dyn std::error::Error>> {
+    if !left.exists() {
+        return Err(ERR_SOURCE_FOLDER_NOT_EXIST.replace("{}", &left.display().to_string()).into());
+    }
+    let left_entries = collect_entries(left, filter)?;
+    let right_entries = if right.exists() {
+        collect_entries(right, &filter.for_destination(right))?
+    } else {
+        BTreeMap::new()
+    };
+
+    let mut entries: Vec<DiffEntry> = vec![];
+    let mut covering: Option<PathBuf> = None;
+    // パスを整列すると親フォルダは常に子より先に、子はまとめて続けて処理される
+    let mut paths: Vec<&PathBuf> = left_entries.keys().chain(right_entries.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    for path in paths {
+        match (left_entries.get(path), right_entries.get(path)) {
+            (Some(l), None) => push(&mut entries, &mut covering, DiffStatus::Removed, path, l.kind),
+            (None, Some(r)) => push(&mut entries, &mut covering, DiffStatus::Added, path, r.kind),
+            (Some(l), Some(r)) if l.kind != r.kind => push(&mut entries, &mut covering, DiffStatus::TypeChanged, path, r.kind),
+            (Some(l), Some(r)) if l.kind == EntryKind::File => {
+                let modified = if l.size != r.size {
+                    true
+                } else if by_hash {
+                    hash_file(&left.join(path))? != hash_file(&right.join(path))?
+                } else {
+                    match (l.modified, r.modified) {
+                        (Some(a), Some(b)) => !same_time(a, b, TIME_PRECISION),
+                        (a, b) => a != b,
+                    }
+                };
+                if modified {
+                    push(&mut entries, &mut covering, DiffStatus::Modified, path, EntryKind::File);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(TreeDiff {
+        left: left.to_path_buf(),
+        right: right.to_path_buf(),
+        entries,
+    })
+}
+
+/// Adds a difference, unless it is inside a directory that was already reported as added, removed or type-changed.
+///
+/// Paths must be pushed in sorted order, so the entries below a directory directly follow it
+/// and only the last such directory (`covering`) needs to be checked.
+fn push(entries: &mut Vec<DiffEntry>, covering: &mut Option<PathBuf>, status: DiffStatus, path: &Path, kind: EntryKind) {
+    if covering.as_deref().is_some_and(|directory| path.starts_with(directory)) {
+        return;
+    }
+    *covering = (kind == EntryKind::Directory).then(|| path.to_path_buf());
+    entries.push(DiffEntry { status, path: path.to_path_buf(), kind });
+}
+
+/// Collects the entries of a tree, keyed by their relative path.
+fn collect_entries(root: &Path, filter: &FileFilter) -> Result<BTreeMap<PathBuf, EntryInfo>, Box<dyn std::error::Error>> {
+    let mut entries = BTreeMap::new();
+    walk_tree(root, filter, &mut |relative, metadata: &Metadata| {
+        let kind = if metadata.is_dir() { EntryKind::Directory } else { EntryKind::File };
+        entries.insert(relative.to_path_buf(), EntryInfo {
+            kind,
+            size: if kind == EntryKind::File { metadata.len() } else { 0 },
+            modified: metadata.modified().ok(),
+        });
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Prints a diff as text.
+fn print_diff(diff: &TreeDiff) {
+    println!("--- {}", diff.left.display());
+    println!("+++ {}", diff.right.display());
+    for entry in &diff.entries {
+        let status = match entry.status {
+            DiffStatus::Added => "A",
+            DiffStatus::Removed => "D",
+            DiffStatus::Modified => "M",
+            DiffStatus::TypeChanged => "T",
+        };
+        let suffix = if entry.kind == EntryKind::Directory { "/" } else { "" };
+        println!("{} {}{}", status, entry.path.display(), suffix);
+    }
+    println!("{}", MSG_DIFF_SUMMARY.replace("{}", &diff.entries.len().to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::FileTime;
+    use std::fs;
+
+    fn statuses(diff: &TreeDiff) -> Vec<(&'static str, String)> {
+        diff.entries.iter()
+            .map(|e| {
+                let status = match e.status {
+                    DiffStatus::Added => "A",
+                    DiffStatus::Removed => "D",
+                    DiffStatus::Modified => "M",
+                    DiffStatus::TypeChanged => "T",
+                };
+                (status, e.path.to_string_lossy().replace('\\', "/"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_top_level_changes_only() {
+        let left = tempfile::tempdir().unwrap();
+        let right = tempfile::tempdir().unwrap();
+        fs::create_dir_all(left.path().join("gone/sub")).unwrap();
+        fs::write(left.path().join("gone/sub/a.txt"), "a").unwrap();
+        fs::write(left.path().join("gone b.txt"), "b").unwrap();
+        fs::create_dir_all(right.path().join("new")).unwrap();
+        fs::write(right.path().join("new/c.txt"), "c").unwrap();
+        fs::write(right.path().join("gone b.txt"), "bb").unwrap();
+        fs::write(left.path().join("kind"), "file").unwrap();
+        fs::create_dir(right.path().join("kind")).unwrap();
+        fs::write(right.path().join("kind/d.txt"), "d").unwrap();
+
+        let diff = diff_trees(left.path(), right.path(), &FileFilter::all(left.path()), false).unwrap();
+        assert_eq!(statuses(&diff), [
+            ("D", "gone".to_string()),
+            ("M", "gone b.txt".to_string()),
+            ("T", "kind".to_string()),
+            ("A", "new".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn ignores_time_differences_below_fat_precision() {
+        let left = tempfile::tempdir().unwrap();
+        let right = tempfile::tempdir().unwrap();
+        for (name, left_time, right_time) in [("same.txt", 1_000_000_000, 1_000_000_001), ("older.txt", 1_000_000_000, 1_000_000_004)] {
+            fs::write(left.path().join(name), "x").unwrap();
+            fs::write(right.path().join(name), "x").unwrap();
+            filetime::set_file_mtime(left.path().join(name), FileTime::from_unix_time(left_time, 0)).unwrap();
+            filetime::set_file_mtime(right.path().join(name), FileTime::from_unix_time(right_time, 0)).unwrap();
+        }
+
+        let diff = diff_trees(left.path(), right.path(), &FileFilter::all(left.path()), false).unwrap();
+        assert_eq!(statuses(&diff), [("M", "older.txt".to_string())]);
+        let diff = diff_trees(left.path(), right.path(), &FileFilter::all(left.path()), true).unwrap();
+        assert!(diff.entries.is_empty());
+    }
+}
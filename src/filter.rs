@@ -0,0 +1,505 @@
+//! # File Filter Module
+//!
+//! This module decides which files and directories of a backup job are processed.
+//! The same filter is used when counting files and when copying them so that progress totals stay accurate.
+//!
+//! Patterns are matched against the path relative to the job's source folder:
+//!
+//! * `name` matches a file or directory with that name anywhere in the tree.
+//! * `dir/name` matches the trailing components of the path, `/dir/name` matches from the source folder.
+//! * `name/` matches directories only.
+//! * `*` and `?` can be used within a component (for example `*.tmp`).
+//! * `**` as a whole component matches any number of components (for example `/src/**/target`).
+//!
+//! Files can also be filtered by size, modification time and the hidden attribute.
+//! Placeholder files such as Google Drive shortcuts are recognized by their extension.
+
+use crate::config::{BtsConfig, BtsConfigWrapper};
+use crate::shortcuts::{INDEX_CSV, INDEX_HTML};
+use std::fs::Metadata;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// A parsed exclude or include pattern.
+#[derive(Clone)]
+struct Pattern {
+    /// Components of the pattern.
+    components: Vec<String>,
+    /// The pattern must match from the root of the tree.
+    anchored: bool,
+    /// The pattern matches directories only.
+    dir_only: bool,
+}
+
+impl Pattern {
+    /// Parses a pattern. Both `/` and `\` are accepted as separators.
+    fn parse(pattern: &str) -> Self {
+        let anchored = pattern.starts_with(['/', '\\']);
+        let dir_only = pattern.ends_with(['/', '\\']);
+        let components = pattern
+            .split(['/', '\\'])
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+            .collect();
+        Pattern { components, anchored, dir_only }
+    }
+
+    /// Returns `true` if the pattern matches the relative path.
+    fn matches(&self, relative: &[String], is_dir: bool) -> bool {
+        if self.components.is_empty() || (self.dir_only && !is_dir) {
+            return false;
+        }
+        if self.anchored {
+            return components_match(&self.components, relative);
+        }
+        (0..relative.len()).any(|start| components_match(&self.components, &relative[start..]))
+    }
+}
+
+/// Matches all of `names` against pattern components, where a `**` component matches any number of names.
+fn components_match(patterns: &[String], names: &[String]) -> bool {
+    match patterns.split_first() {
+        None => names.is_empty(),
+        Some((pattern, rest)) if pattern == "**" => (0..=names.len()).any(|skip| components_match(rest, &names[skip..])),
+        Some((pattern, rest)) => names.split_first().is_some_and(|(name, names)| glob_match(pattern, name) && components_match(rest, names)),
+    }
+}
+
+/// File filter of a backup job.
+#[derive(Clone)]
+pub struct FileFilter {
+    /// Root the patterns are relative to.
+    root: PathBuf,
+    /// Patterns of excluded files and directories.
+    exclude: Vec<Pattern>,
+    /// Patterns of included files. Empty means every file is included.
+    include: Vec<Pattern>,
+    /// Minimum file size in bytes.
+    min_size: Option<u64>,
+    /// Maximum file size in bytes.
+    max_size: Option<u64>,
+    /// Files modified before this time are skipped.
+    modified_after: Option<SystemTime>,
+    /// Files modified after this time are skipped.
+    modified_before: Option<SystemTime>,
+    /// Skip hidden files and directories.
+    skip_hidden: bool,
+    /// Lowercase extensions of skipped placeholder files.
+    skip_extensions: Vec<String>,
+}
+
+impl FileFilter {
+    /// Creates the filter of a backup job.
+    ///
+    /// The job's `exclude` and `skip_extensions` lists are merged with the global lists.
+    /// Relative modification times are resolved against the current time.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to the `BtsConfig` of the job.
+    /// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` holding the global lists.
+    pub fn new(config: &BtsConfig, bts_config_wrapper: &BtsConfigWrapper) -> Self {
+        let skip_extensions = if config.copy_shortcuts {
+            vec![]
+        } else {
+            bts_config_wrapper.skip_extensions.iter()
+                .chain(&config.skip_extensions)
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect()
+        };
+
+        FileFilter {
+            root: PathBuf::from(&config.source),
+            exclude: bts_config_wrapper.exclude.iter().chain(&config.exclude).map(|p| Pattern::parse(p)).collect(),
+            include: config.include.iter().map(|p| Pattern::parse(p)).collect(),
+            min_size: config.min_size.map(u64::from),
+            max_size: config.max_size.map(u64::from),
+            modified_after: config.modified_after.as_ref().map(|t| t.resolve(SystemTime::now())),
+            modified_before: config.modified_before.as_ref().map(|t| t.resolve(SystemTime::now())),
+            skip_hidden: config.skip_hidden,
+            skip_extensions,
+        }
+    }
+
+    /// Creates a filter accepting every file and directory under `root`.
+    pub fn all(root: &Path) -> Self {
+        FileFilter {
+            root: root.to_path_buf(),
+            exclude: vec![],
+            include: vec![],
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+            skip_hidden: false,
+            skip_extensions: vec![],
+        }
+    }
+
+    /// Returns a copy of the filter whose patterns are relative to another root,
+    /// used to apply a job's filter to its destination tree.
+    pub fn rebase(&self, root: &Path) -> Self {
+        FileFilter {
+            root: root.to_path_buf(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the filter for the destination tree of the job, rooted at `root`.
+    ///
+    /// Only the path patterns, the extensions and the hidden filter are kept: the size and modification time
+    /// filters select files by their source metadata, and a destination copy can differ from it,
+    /// so applying them to the destination would report selected files as missing or extra.
+    pub fn for_destination(&self, root: &Path) -> Self {
+        FileFilter {
+            root: root.to_path_buf(),
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+            ..self.clone()
+        }
+    }
+
+    /// Returns `true` if the file is a placeholder whose extension is skipped.
+    ///
+    /// Skipped placeholders are still counted, so callers advance the progress when skipping them.
+    pub fn skips_extension(&self, path: &Path) -> bool {
+        path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .is_some_and(|e| self.skip_extensions.contains(&e))
+    }
+
+    /// Returns `true` if the directory and everything below it is skipped.
+    pub fn skips_dir(&self, path: &Path, metadata: &Metadata) -> bool {
+        let relative = self.relative_components(path);
+        if relative.is_empty() {
+            return false;
+        }
+        (self.skip_hidden && is_hidden(path, metadata))
+            || self.exclude.iter().any(|p| p.matches(&relative, true))
+    }
+
+    /// Returns `true` if the file is processed.
+    ///
+    /// A file is processed when it is not excluded, passes the size, time and hidden filters and,
+    /// if include patterns are given, either the file or one of its parent directories matches an include pattern.
+    pub fn accepts_file(&self, path: &Path, metadata: &Metadata) -> bool {
+        if !self.accepts_metadata(path, metadata) {
+            return false;
+        }
+        let relative = self.relative_components(path);
+        if self.exclude.iter().any(|p| p.matches(&relative, false)) {
+            return false;
+        }
+        if self.include.is_empty() {
+            return true;
+        }
+        self.include.iter().any(|p| {
+            p.matches(&relative, false)
+                || (1..relative.len()).any(|len| p.matches(&relative[..len], true))
+        })
+    }
+
+    /// Returns `true` if the file passes the size, time and hidden filters.
+    fn accepts_metadata(&self, path: &Path, metadata: &Metadata) -> bool {
+        let size = metadata.len();
+        if self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            let Ok(modified) = metadata.modified() else {
+                return false;
+            };
+            if self.modified_after.is_some_and(|after| modified < after)
+                || self.modified_before.is_some_and(|before| modified > before)
+            {
+                return false;
+            }
+        }
+        !(self.skip_hidden && is_hidden(path, metadata))
+    }
+
+    /// Returns the components of `path` relative to the root.
+    fn relative_components(&self, path: &Path) -> Vec<String> {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Prefix of the names of files written by this tool into destinations.
+pub const ARTIFACT_PREFIX: &str = ".folder-sync";
+
+/// Returns `true` if the file was written by this tool into a destination (for example a shortcut index),
+/// so it has no counterpart in the source.
+pub fn is_artifact(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name.starts_with(ARTIFACT_PREFIX) || name == INDEX_HTML || name == INDEX_CSV
+    })
+}
+
+/// Returns `true` if the file or directory is hidden.
+///
+/// Names starting with `.` are hidden on every platform; on Windows the hidden attribute is also checked.
+fn is_hidden(path: &Path, metadata: &Metadata) -> bool {
+    let dotfile = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        dotfile || metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = metadata;
+        dotfile
+    }
+}
+
+/// Matches a name against a glob with `*` and `?`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // 直前の * に1文字多く割り当てて再試行
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn components(path: &str) -> Vec<String> {
+        path.split('/').map(str::to_string).collect()
+    }
+
+    fn filter(source: &Path, exclude: &[&str], include: &[&str]) -> FileFilter {
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": source.display().to_string(),
+            "destination": "/backup",
+            "overwrite": true,
+            "exclude": exclude,
+            "include": include,
+        })).unwrap();
+        FileFilter::new(&config, &BtsConfigWrapper::default())
+    }
+
+    /// Creates the files and folders (ending with `/`) below `root`.
+    fn create(root: &Path, paths: &[&str]) {
+        for path in paths {
+            let path = root.join(path);
+            if path.to_string_lossy().ends_with('/') {
+                fs::create_dir_all(&path).unwrap();
+            } else {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, b"data").unwrap();
+            }
+        }
+    }
+
+    /// Returns `true` if the filter processes the file or folder (ending with `/`) and every folder above it.
+    fn accepts(filter: &FileFilter, root: &Path, path: &str) -> bool {
+        let target = root.join(path.trim_end_matches('/'));
+        let mut folder = target.parent().unwrap();
+        while folder != root {
+            if filter.skips_dir(folder, &fs::metadata(folder).unwrap()) {
+                return false;
+            }
+            folder = folder.parent().unwrap();
+        }
+        let metadata = fs::metadata(&target).unwrap();
+        if path.ends_with('/') { !filter.skips_dir(&target, &metadata) } else { filter.accepts_file(&target, &metadata) }
+    }
+
+    #[test]
+    fn matches_globs() {
+        for (pattern, name, expected) in [
+            ("*.tmp", "a.tmp", true),
+            ("*.tmp", ".tmp", true),
+            ("*.tmp", "a.tmp.txt", false),
+            ("file?.txt", "file1.txt", true),
+            ("file?.txt", "file.txt", false),
+            ("file?.txt", "file12.txt", false),
+            ("*", "", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYc_", false),
+            ("**", "name", true),
+            ("日本*", "日本語.txt", true),
+            ("name", "Name", false),
+        ] {
+            assert_eq!(glob_match(pattern, name), expected, "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn matches_patterns() {
+        for (pattern, path, is_dir, expected) in [
+            // 先頭が / でなければ任意の階層に一致する
+            ("name", "name", false, true),
+            ("name", "a/b/name", false, true),
+            ("name", "name/file", false, false),
+            ("dir/name", "a/dir/name", false, true),
+            ("dir/name", "dir/a/name", false, false),
+            ("/dir/name", "dir/name", false, true),
+            ("/dir/name", "a/dir/name", false, false),
+            (r"\dir\name", "dir/name", false, true),
+            // 末尾が / ならフォルダにだけ一致する
+            ("build/", "a/build", true, true),
+            ("build/", "a/build", false, false),
+            ("*.log", "logs/app.log", false, true),
+            ("/*.log", "logs/app.log", false, false),
+            // ** は 0 個以上の階層に一致する
+            ("/src/**/target", "src/target", true, true),
+            ("/src/**/target", "src/a/b/target", true, true),
+            ("/src/**/target", "lib/src/a/target", true, false),
+            ("src/**/target", "lib/src/a/target", true, true),
+            ("src/**", "src/a/b", false, true),
+            ("**/*.tmp", "a/b.tmp", false, true),
+            ("", "name", false, false),
+            ("/", "name", true, false),
+        ] {
+            assert_eq!(Pattern::parse(pattern).matches(&components(path), is_dir), expected, "{} {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn filters_files_and_folders() {
+        let folder = tempfile::tempdir().unwrap();
+        let root = folder.path();
+        create(root, &[
+            "keep.txt", "drop.tmp", "build/out.bin", "src/build", "src/main.rs", "src/target/out.bin",
+            "docs/a.md", "docs/a.tmp", "docs/private/b.md", "photos/", ".hidden",
+        ]);
+
+        for (exclude, include, path, expected) in [
+            (&[][..], &[][..], "keep.txt", true),
+            (&[][..], &[][..], ".hidden", true),
+            (&["*.tmp"], &[], "drop.tmp", false),
+            (&["*.tmp"], &[], "docs/a.tmp", false),
+            (&["/*.tmp"], &[], "docs/a.tmp", true),
+            // build/ はフォルダだけを除外し、同じ名前のファイルは残す
+            (&["build/"], &[], "build/out.bin", false),
+            (&["build/"], &[], "build/", false),
+            (&["build/"], &[], "src/build", true),
+            (&["src/**/out.bin"], &[], "src/target/out.bin", false),
+            (&["src/**/out.bin"], &[], "build/out.bin", true),
+            // include を指定すると、一致するファイルと一致するフォルダの中身だけを処理する
+            (&[], &["docs"], "docs/private/b.md", true),
+            (&[], &["docs"], "keep.txt", false),
+            (&[], &["*.md"], "docs/a.md", true),
+            (&[], &["*.md"], "docs/a.tmp", false),
+            (&[], &["*.md"], "photos/", true),
+            // exclude は include より優先される
+            (&["*.tmp"], &["docs"], "docs/a.tmp", false),
+            (&["*.tmp"], &["docs"], "docs/a.md", true),
+            (&["private/"], &["*.md"], "docs/private/b.md", false),
+            (&["docs/a.md"], &["docs/a.md"], "docs/a.md", false),
+        ] {
+            assert_eq!(accepts(&filter(root, exclude, include), root, path), expected, "{:?} {:?} {}", exclude, include, path);
+        }
+    }
+
+    #[test]
+    fn filters_by_metadata() {
+        let folder = tempfile::tempdir().unwrap();
+        let root = folder.path();
+        create(root, &[".hidden", ".hidden-dir/file.txt", "visible.txt"]);
+        fs::write(root.join("large.bin"), [0; 2048]).unwrap();
+        let old = SystemTime::now() - std::time::Duration::from_secs(10 * 24 * 60 * 60);
+        filetime::set_file_mtime(root.join("visible.txt"), filetime::FileTime::from_system_time(old)).unwrap();
+
+        for (settings, path, expected) in [
+            (serde_json::json!({"min_size": "1KB"}), "large.bin", true),
+            (serde_json::json!({"min_size": "1KB"}), "visible.txt", false),
+            (serde_json::json!({"max_size": 1024}), "large.bin", false),
+            (serde_json::json!({"max_size": 1024}), "visible.txt", true),
+            (serde_json::json!({"modified_after": "7d"}), "visible.txt", false),
+            (serde_json::json!({"modified_after": "7d"}), "large.bin", true),
+            (serde_json::json!({"modified_before": "7d"}), "visible.txt", true),
+            (serde_json::json!({"modified_before": "7d"}), "large.bin", false),
+            (serde_json::json!({"skip_hidden": true}), ".hidden", false),
+            (serde_json::json!({"skip_hidden": true}), ".hidden-dir/file.txt", false),
+            (serde_json::json!({"skip_hidden": true}), "visible.txt", true),
+            (serde_json::json!({"skip_hidden": false}), ".hidden-dir/file.txt", true),
+        ] {
+            let mut value = serde_json::json!({"source": root.display().to_string(), "destination": "/backup", "overwrite": true});
+            value.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+            let config: BtsConfig = serde_json::from_value(value).unwrap();
+            let filter = FileFilter::new(&config, &BtsConfigWrapper::default());
+            assert_eq!(accepts(&filter, root, path), expected, "{} {}", settings, path);
+        }
+    }
+
+    #[test]
+    fn merges_global_excludes() {
+        let folder = tempfile::tempdir().unwrap();
+        create(folder.path(), &["a.tmp", "b.bak", "c.txt"]);
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": folder.path().display().to_string(), "destination": "/backup", "overwrite": true, "exclude": ["*.bak"],
+        })).unwrap();
+        let wrapper = BtsConfigWrapper { exclude: vec!["*.tmp".to_string()], ..Default::default() };
+        let filter = FileFilter::new(&config, &wrapper);
+        for (name, expected) in [("a.tmp", false), ("b.bak", false), ("c.txt", true)] {
+            assert_eq!(accepts(&filter, folder.path(), name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rebases_patterns_onto_destination() {
+        let folder = tempfile::tempdir().unwrap();
+        let (source, destination) = (folder.path().join("source"), folder.path().join("backup/nested/source"));
+        let paths = ["small.txt", "drop.tmp", "cache/", "sub/cache/", "sub/small.txt"];
+        for root in [&source, &destination] {
+            create(root, &paths);
+            fs::write(root.join("sub/cache/large.bin"), [0; 200]).unwrap();
+        }
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": source.display().to_string(),
+            "destination": destination.display().to_string(),
+            "overwrite": true,
+            "exclude": ["*.tmp", "/cache/"],
+            "min_size": 100,
+        })).unwrap();
+        let filter = FileFilter::new(&config, &BtsConfigWrapper::default());
+
+        // 相対パスで判定するので、入れ子になったバックアップ先でも同じファイルを選ぶ
+        let rebased = filter.rebase(&destination);
+        let for_destination = filter.for_destination(&destination);
+        for (path, expected) in [("drop.tmp", false), ("cache/", false), ("sub/cache/large.bin", true)] {
+            assert_eq!(accepts(&filter.rebase(&source), &source, path), expected, "{}", path);
+            assert_eq!(accepts(&rebased, &destination, path), expected, "{}", path);
+            assert_eq!(accepts(&for_destination, &destination, path), expected, "{}", path);
+        }
+
+        // サイズの条件は rebase では残り、for_destination では外れる
+        assert!(!accepts(&filter, &source, "sub/small.txt"));
+        assert!(!accepts(&rebased, &destination, "sub/small.txt"));
+        assert!(accepts(&for_destination, &destination, "sub/small.txt"));
+    }
+}
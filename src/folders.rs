@@ -30,7 +30,7 @@ pub fn execute_create_folders(config : &CdfConfig) -> Result<(),Box<dyn std::err
 
     // Check if the source folder exists
     if !source_path.exists(){
-        return Err(format!("{}",ERR_SOURCE_FOLDER_NOT_EXIST.replace("{}",&config.source)).into());
+        return Err(ERR_SOURCE_FOLDER_NOT_EXIST.replace("{}",&config.source).into());
     }
     
     // Recursively create the folder structure
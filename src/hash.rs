@@ -0,0 +1,47 @@
+//! # Hash Module
+//!
+//! This module computes SHA-256 hashes of files, used to verify copies and to detect corrupted backups.
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Size of the buffer used when reading files.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Computes the SHA-256 hash of a file.
+///
+/// # Arguments
+///
+/// * `path` - A reference to the `Path` of the file.
+///
+/// # Returns
+///
+/// Returns `Ok(String)` with the lowercase hexadecimal hash, or `Err(io::Error)` if the file cannot be read.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    hash_reader(File::open(path)?)
+}
+
+/// Computes the SHA-256 hash of everything read from `reader`.
+///
+/// # Returns
+///
+/// Returns `Ok(String)` with the lowercase hexadecimal hash, or `Err(io::Error)` if reading fails.
+pub fn hash_reader(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Encodes bytes as lowercase hexadecimal.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
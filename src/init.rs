@@ -0,0 +1,208 @@
+//! # Init Wizard Module
+//!
+//! This module provides an interactive wizard that asks for backup jobs and folder creation settings,
+//! validates the entered paths, and writes a commented configuration file.
+
+use crate::messages::*;
+use crate::paths::{expand_path, TemplateContext};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Excludes offered by the wizard.
+const COMMON_EXCLUDES: [&str; 7] = [
+    ".git",
+    "node_modules",
+    "target",
+    "Thumbs.db",
+    "desktop.ini",
+    ".DS_Store",
+    "__pycache__",
+];
+
+/// A backup job entered in the wizard.
+struct JobAnswer {
+    name: String,
+    source: String,
+    destination: String,
+    overwrite: bool,
+}
+
+/// Runs the interactive configuration wizard and writes the configuration file.
+///
+/// # Arguments
+///
+/// * `path` - A reference to the `Path` of the configuration file to write.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the file is written or the user cancels,
+/// or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if reading from standard input fails.
+/// * Returns an error if the configuration file cannot be written.
+pub fn execute_init(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", MSG_INIT_WELCOME.replace("{}", &path.display().to_string()));
+
+    if path.exists() && !ask_yes_no(&format!("{} already exists. Overwrite?", path.display()), false)? {
+        println!("{}", MSG_INIT_CANCELLED);
+        return Ok(());
+    }
+
+    let context = TemplateContext::current();
+
+    // バックアップジョブの入力
+    let mut jobs = vec![];
+    while ask_yes_no(if jobs.is_empty() { "Add a backup job?" } else { "Add another backup job?" }, jobs.is_empty())? {
+        let name = ask("Job name (optional)")?;
+        let source = ask_path("Source folder", &context, true)?;
+        let destination = ask_path("Destination folder", &context, false)?;
+        let overwrite = ask_yes_no("Overwrite existing files when they change?", true)?;
+        jobs.push(JobAnswer { name, source, destination, overwrite });
+    }
+
+    // 除外するファイルやフォルダの選択
+    println!("Common excludes:");
+    for (i, exclude) in COMMON_EXCLUDES.iter().enumerate() {
+        println!("  {} > {}", i + 1, exclude);
+    }
+    let mut excludes: Vec<String> = loop {
+        let input = ask("Numbers to exclude (comma-separated, empty for none)")?;
+        let selected: Result<Vec<String>, _> = split_list(&input)
+            .map(|n| match n.parse::<usize>() {
+                Ok(n) if (1..=COMMON_EXCLUDES.len()).contains(&n) => Ok(COMMON_EXCLUDES[n - 1].to_string()),
+                _ => Err(n.to_string()),
+            })
+            .collect();
+        match selected {
+            Ok(selected) => break selected,
+            Err(n) => println!("Invalid number : {}", n),
+        }
+    };
+    excludes.extend(split_list(&ask("Other excludes (comma-separated, empty for none)")?).map(str::to_string));
+
+    // フォルダ構成作成モードの設定
+    let (cdf_source, cdf_destination) = if ask_yes_no("Configure folder creation mode (-cdf)?", false)? {
+        (ask_path("Folder structure source", &context, true)?, ask_path("Folder structure destination", &context, false)?)
+    } else {
+        (String::new(), String::new())
+    };
+
+    fs::write(path, render_config(&jobs, &excludes, &cdf_source, &cdf_destination)?)?;
+    println!("{}", MSG_INIT_WRITTEN.replace("{}", &path.display().to_string()));
+
+    Ok(())
+}
+
+/// Renders the configuration file with comments explaining each value.
+fn render_config(
+    jobs: &[JobAnswer],
+    excludes: &[String],
+    cdf_source: &str,
+    cdf_destination: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut out = String::new();
+    out.push_str("// folder-sync-rs configuration (generated by `folder-sync-rs init`)\n");
+    out.push_str("// Paths may use ~, ${ENV_VAR}, {date}, {date:%Y%m%d} and {hostname}.\n");
+    out.push_str("{\n");
+    out.push_str("    // Backup mode (-bts)\n");
+    out.push_str("    \"bts\": {\n");
+    out.push_str("        // Backup jobs, run in parallel\n");
+    out.push_str("        \"configs\": [");
+    for (i, job) in jobs.iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        out.push_str("            {\n");
+        if !job.name.is_empty() {
+            out.push_str("                // Job name, used by per-machine overrides and --set\n");
+            out.push_str(&format!("                \"name\": {},\n", serde_json::to_string(&job.name)?));
+        }
+        out.push_str("                // Folder to back up\n");
+        out.push_str(&format!("                \"source\": {},\n", serde_json::to_string(&job.source)?));
+        out.push_str("                // Folder to back up to (created if missing)\n");
+        out.push_str(&format!("                \"destination\": {},\n", serde_json::to_string(&job.destination)?));
+        out.push_str("                // true: overwrite files whose size or timestamp changed\n");
+        out.push_str(&format!("                \"overwrite\": {}\n", job.overwrite));
+        out.push_str("            }");
+    }
+    out.push_str(if jobs.is_empty() { "],\n" } else { "\n        ],\n" });
+    out.push_str("        // File or folder names excluded from every job\n");
+    out.push_str(&format!("        \"exclude\": {}\n", serde_json::to_string(excludes)?));
+    out.push_str("    },\n");
+    out.push_str("    // Create folders mode (-cdf): copies the folder structure without files\n");
+    out.push_str("    \"cdf\": {\n");
+    out.push_str(&format!("        \"source\": {},\n", serde_json::to_string(cdf_source)?));
+    out.push_str(&format!("        \"destination\": {}\n", serde_json::to_string(cdf_destination)?));
+    out.push_str("    }\n");
+    out.push_str("}\n");
+    Ok(out)
+}
+
+/// Asks for a path until a valid one is entered.
+///
+/// Templates are expanded for validation only; the path is returned as entered.
+/// A source must be an existing folder. A missing destination is accepted after confirmation.
+fn ask_path(label: &str, context: &TemplateContext, must_exist: bool) -> Result<String, Box<dyn std::error::Error>> {
+    loop {
+        let input = ask(label)?;
+        if input.is_empty() {
+            println!("Please enter a path.");
+            continue;
+        }
+
+        let expanded = match expand_path(&input, context) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        let path = Path::new(&expanded);
+
+        if path.is_dir() {
+            return Ok(input);
+        }
+        if path.exists() {
+            println!("Not a folder : {}", expanded);
+        } else if must_exist {
+            println!("{}", ERR_SOURCE_FOLDER_NOT_EXIST.replace("{}", &expanded));
+        } else if ask_yes_no(&format!("{} does not exist and will be created. Use it?", expanded), true)? {
+            return Ok(input);
+        }
+    }
+}
+
+/// Asks a yes/no question. An empty answer selects `default`.
+fn ask_yes_no(question: &str, default: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    loop {
+        match ask(&format!("{} {}", question, hint))?.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n."),
+        }
+    }
+}
+
+/// Prints a prompt and reads one trimmed line from standard input.
+///
+/// # Errors
+///
+/// * Returns an error if standard input is closed or cannot be read.
+fn ask(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    print!("{} : ", prompt);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(input.trim().to_string())
+}
+
+/// Splits a comma-separated answer into trimmed, non-empty items.
+fn split_list(input: &str) -> impl Iterator<Item = &str> {
+    input.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
@@ -0,0 +1,439 @@
+//! # Configuration Layer Module
+//!
+//! This module merges configuration layers (included files, the main configuration file,
+//! the per-machine override file and command-line overrides) into a single JSON value,
+//! remembering which layer each value came from.
+//!
+//! Configuration files may contain `//` and `/* */` comments.
+//!
+//! Objects are merged key by key. Non-empty arrays whose elements are all objects with a `"name"`
+//! are merged element by element using that name; any other array (including an empty one) or value is replaced as a whole.
+
+use crate::messages::*;
+use crate::paths::{expand_path, TemplateContext};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Key of the include directive in a configuration file.
+const INCLUDE_KEY: &str = "include";
+
+/// Origin recorded for values given with `--set`.
+const CLI_ORIGIN: &str = "--set";
+
+/// Merged configuration layers.
+///
+/// `origins` maps the dotted key of every leaf value (for example `bts.configs.photos.destination`)
+/// to the layer that set it. Array elements are addressed by their `"name"` if they have one,
+/// otherwise by their index.
+pub struct LayeredConfig {
+    /// The merged configuration value.
+    pub value : Value,
+    /// Origin of every leaf value, keyed by its dotted key.
+    pub origins : BTreeMap<String, String>,
+}
+
+impl Default for LayeredConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LayeredConfig {
+    /// Creates an empty layered configuration.
+    pub fn new() -> Self {
+        LayeredConfig {
+            value: Value::Object(Map::new()),
+            origins: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value at a dotted key, if any.
+    pub fn get(&self, key : &str) -> Option<&Value> {
+        key.split('.').try_fold(&self.value, |value, segment| match value {
+            Value::Array(items) => items.iter()
+                .find(|item| element_name(item) == Some(segment))
+                .or_else(|| segment.parse::<usize>().ok().and_then(|index| items.get(index))),
+            _ => value.get(segment),
+        })
+    }
+
+    /// Returns the dotted key of the value at `segments` if it, or a value below it, was set with `--set`.
+    ///
+    /// `segments` address array elements by index; the returned key uses their `"name"` if they have one.
+    pub fn override_key(&self, segments : &[String]) -> Option<String> {
+        let mut key = String::new();
+        let mut value = Some(&self.value);
+        for segment in segments {
+            let child = match value {
+                Some(Value::Array(items)) => segment.parse::<usize>().ok().and_then(|index| items.get(index)),
+                Some(value) => value.get(segment),
+                None => None,
+            };
+            key = join_key(&key, child.and_then(element_name).filter(|_| value.is_some_and(Value::is_array)).unwrap_or(segment));
+            value = child;
+        }
+        let prefix = format!("{}.", key);
+        self.origins.iter()
+            .any(|(path, origin)| origin == CLI_ORIGIN && (*path == key || path.starts_with(&prefix)))
+            .then_some(key)
+    }
+
+    /// Merges a layer on top of the current value.
+    ///
+    /// # Arguments
+    ///
+    /// * `layer` - The JSON value of the layer.
+    /// * `origin` - A description of the layer recorded as the origin of its values.
+    pub fn merge(&mut self, layer : Value, origin : &str) {
+        merge_value(&mut self.value, layer, "", &mut self.origins, origin);
+    }
+
+    /// Loads a configuration file and its includes, and merges them on top of the current value.
+    ///
+    /// Included files are merged first, in order, so the including file overrides them.
+    /// Relative include paths are resolved against the directory of the including file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the `Path` of the configuration file.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if a file cannot be read or parsed as JSON.
+    /// * Returns an error if a file is not a JSON object.
+    /// * Returns an error if the include directive is invalid or includes form a cycle.
+    pub fn merge_file(&mut self, path : &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.merge_file_inner(path, &mut Vec::new())
+    }
+
+    fn merge_file_inner(&mut self, path : &Path, stack : &mut Vec<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+        let canonical = fs::canonicalize(path)
+            .map_err(|e| format!("{} : {}", ERR_FAILED_TO_READ_CONFIG.replace("{}", &path.display().to_string()), e))?;
+        if stack.contains(&canonical) {
+            return Err(ERR_INCLUDE_CYCLE.replace("{}", &path.display().to_string()).into());
+        }
+
+        let contents = strip_comments(&fs::read_to_string(path)?);
+        let mut value: Value = serde_json::from_str(&contents)
+            .map_err(|e| format!("{} : {}", path.display(), e))?;
+        let object = value.as_object_mut()
+            .ok_or_else(|| ERR_CONFIG_NOT_OBJECT.replace("{}", &path.display().to_string()))?;
+
+        // include を先にマージし、このファイルの値で上書きする
+        let includes = match object.remove(INCLUDE_KEY) {
+            None => vec![],
+            Some(Value::String(include)) => vec![include],
+            Some(Value::Array(items)) => items.into_iter()
+                .map(|item| match item {
+                    Value::String(include) => Ok(include),
+                    _ => Err(ERR_INVALID_INCLUDE.replace("{}", &path.display().to_string())),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => return Err(ERR_INVALID_INCLUDE.replace("{}", &path.display().to_string()).into()),
+        };
+
+        stack.push(canonical);
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let context = TemplateContext::current();
+        for include in includes {
+            let include_path = base_dir.join(expand_path(&include, &context)?);
+            self.merge_file_inner(&include_path, stack)?;
+        }
+        stack.pop();
+
+        self.merge(value, &path.display().to_string());
+        Ok(())
+    }
+
+    /// Applies a `KEY=VALUE` override given on the command line.
+    ///
+    /// `KEY` is a dotted key such as `cdf.destination` or `bts.configs.photos.overwrite`.
+    /// `VALUE` is parsed as JSON when possible and used as a plain string otherwise.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the override is not in the `KEY=VALUE` form.
+    /// * Returns an error if the key goes through a value that is not an object or array.
+    pub fn apply_override(&mut self, assignment : &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (key, raw) = assignment.split_once('=')
+            .ok_or_else(|| ERR_INVALID_OVERRIDE.replace("{}", assignment))?;
+        let value = serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()));
+
+        // キーをたどり、途中のオブジェクトを作成しながら値を設定する
+        let segments: Vec<&str> = key.split('.').filter(|s| !s.is_empty()).collect();
+        if segments.is_empty() {
+            return Err(ERR_INVALID_OVERRIDE.replace("{}", assignment).into());
+        }
+        let mut target = &mut self.value;
+        let mut path = String::new();
+        for (i, segment) in segments.iter().enumerate() {
+            // 名前のある配列要素は、番号で指定されても名前で記録する
+            let name = match &*target {
+                Value::Array(items) => segment.parse::<usize>().ok().and_then(|index| items.get(index)).and_then(element_name),
+                _ => None,
+            };
+            path = join_key(&path, name.unwrap_or(segment));
+            if i == segments.len() - 1 {
+                let slot = child_mut(target, segment)
+                    .ok_or_else(|| ERR_INVALID_OVERRIDE.replace("{}", assignment))?;
+                remove_origins(&mut self.origins, &path);
+                record_origins(&value, &path, &mut self.origins, CLI_ORIGIN);
+                *slot = value;
+                break;
+            }
+            target = child_mut(target, segment)
+                .ok_or_else(|| ERR_INVALID_OVERRIDE.replace("{}", assignment))?;
+            if target.is_null() {
+                *target = Value::Object(Map::new());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes `//` and `/* */` comments outside of string literals.
+///
+/// Comments are replaced with spaces (line breaks are kept) so that parse errors still point to the right line and column.
+pub fn strip_comments(contents : &str) -> String {
+    let mut result = String::with_capacity(contents.len());
+    let mut chars = contents.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            result.push(c);
+            match c {
+                '\\' => {
+                    if let Some(escaped) = chars.next() {
+                        result.push(escaped);
+                    }
+                }
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                result.push(c);
+            }
+            ('/', Some('/')) => {
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    result.push(' ');
+                    chars.next();
+                }
+                result.push(' ');
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                result.push_str("  ");
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    result.push(if next == '\n' { '\n' } else { ' ' });
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    previous = next;
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Returns the child of an object or array addressed by a key segment, creating object members as needed.
+///
+/// Array elements are addressed by their `"name"` or by their index.
+fn child_mut<'a>(value : &'a mut Value, segment : &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Object(map) => Some(map.entry(segment.to_string()).or_insert(Value::Null)),
+        Value::Array(items) => {
+            let by_name = items.iter().position(|item| element_name(item) == Some(segment));
+            let index = by_name.or_else(|| segment.parse::<usize>().ok())?;
+            items.get_mut(index)
+        }
+        _ => None,
+    }
+}
+
+/// Merges `layer` into `base`, recording the origin of every value set by the layer.
+fn merge_value(base : &mut Value, layer : Value, path : &str, origins : &mut BTreeMap<String, String>, origin : &str) {
+    match (base, layer) {
+        (Value::Object(base_map), Value::Object(layer_map)) => {
+            for (key, layer_value) in layer_map {
+                let child_path = join_key(path, &key);
+                match base_map.get_mut(&key) {
+                    Some(base_value) => merge_value(base_value, layer_value, &child_path, origins, origin),
+                    None => {
+                        record_origins(&layer_value, &child_path, origins, origin);
+                        base_map.insert(key, layer_value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base_items), Value::Array(layer_items))
+            if is_named_array(base_items) && is_named_array(&layer_items) =>
+        {
+            for layer_item in layer_items {
+                let name = element_name(&layer_item).unwrap_or_default().to_string();
+                let child_path = join_key(path, &name);
+                match base_items.iter_mut().find(|item| element_name(item) == Some(name.as_str())) {
+                    Some(base_item) => merge_value(base_item, layer_item, &child_path, origins, origin),
+                    None => {
+                        record_origins(&layer_item, &child_path, origins, origin);
+                        base_items.push(layer_item);
+                    }
+                }
+            }
+        }
+        (base, layer) => {
+            remove_origins(origins, path);
+            record_origins(&layer, path, origins, origin);
+            *base = layer;
+        }
+    }
+}
+
+/// Records `origin` for every leaf of `value` under `path`.
+fn record_origins(value : &Value, path : &str, origins : &mut BTreeMap<String, String>, origin : &str) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, child) in map {
+                record_origins(child, &join_key(path, key), origins, origin);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (index, item) in items.iter().enumerate() {
+                let segment = element_name(item).map(str::to_string).unwrap_or_else(|| index.to_string());
+                record_origins(item, &join_key(path, &segment), origins, origin);
+            }
+        }
+        _ => {
+            origins.insert(path.to_string(), origin.to_string());
+        }
+    }
+}
+
+/// Removes the origins recorded for `path` and everything below it.
+fn remove_origins(origins : &mut BTreeMap<String, String>, path : &str) {
+    let prefix = format!("{}.", path);
+    origins.retain(|key, _| key != path && !key.starts_with(&prefix));
+}
+
+/// Returns `true` if the array is not empty and every element is an object with a `"name"`.
+///
+/// An empty array is not named, so a layer can clear an inherited list by setting it to `[]`.
+fn is_named_array(items : &[Value]) -> bool {
+    !items.is_empty() && items.iter().all(|item| element_name(item).is_some())
+}
+
+/// Returns the `"name"` of an array element, if any.
+fn element_name(item : &Value) -> Option<&str> {
+    item.get("name").and_then(Value::as_str)
+}
+
+/// Joins a dotted key and a segment.
+fn join_key(path : &str, segment : &str) -> String {
+    if path.is_empty() {
+        segment.to_string()
+    } else {
+        format!("{}.{}", path, segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_objects_by_key_and_records_origins() {
+        let mut config = LayeredConfig::new();
+        config.merge(json!({"cdf": {"source": "a", "destination": "b"}, "exclude": ["x"]}), "base.json");
+        config.merge(json!({"cdf": {"destination": "c"}, "exclude": ["y", "z"]}), "pc01.json");
+
+        assert_eq!(config.value, json!({"cdf": {"source": "a", "destination": "c"}, "exclude": ["y", "z"]}));
+        assert_eq!(config.origins["cdf.source"], "base.json");
+        assert_eq!(config.origins["cdf.destination"], "pc01.json");
+        assert_eq!(config.origins["exclude.1"], "pc01.json");
+        assert!(!config.origins.contains_key("exclude.2"));
+    }
+
+    #[test]
+    fn merges_named_arrays_by_name() {
+        let mut config = LayeredConfig::new();
+        config.merge(json!({"configs": [{"name": "photos", "source": "a", "overwrite": true}]}), "base.json");
+        config.merge(json!({"configs": [{"name": "photos", "overwrite": false}, {"name": "work", "source": "w"}]}), "pc01.json");
+
+        assert_eq!(config.value["configs"], json!([
+            {"name": "photos", "source": "a", "overwrite": false},
+            {"name": "work", "source": "w"},
+        ]));
+        assert_eq!(config.get("configs.photos.source"), Some(&json!("a")));
+        assert_eq!(config.origins["configs.photos.overwrite"], "pc01.json");
+        assert_eq!(config.origins["configs.work.source"], "pc01.json");
+    }
+
+    #[test]
+    fn empty_array_replaces_named_array() {
+        let mut config = LayeredConfig::new();
+        config.merge(json!({"configs": [{"name": "photos", "source": "a"}]}), "base.json");
+        config.merge(json!({"configs": []}), "pc01.json");
+
+        assert_eq!(config.value["configs"], json!([]));
+        assert!(!config.origins.contains_key("configs.photos.source"));
+        assert_eq!(config.origins["configs"], "pc01.json");
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let mut config = LayeredConfig::new();
+        config.merge(json!({"bts": {"configs": [{"name": "photos", "overwrite": true}, {"source": "b"}]}}), "base.json");
+
+        config.apply_override("bts.configs.photos.overwrite=false").unwrap();
+        config.apply_override("bts.configs.1.source=C:\\work").unwrap();
+        config.apply_override("cdf.destination=D:\\work").unwrap();
+        config.apply_override("exclude=[\"tmp\"]").unwrap();
+
+        assert_eq!(config.get("bts.configs.photos.overwrite"), Some(&json!(false)));
+        assert_eq!(config.get("bts.configs.1.source"), Some(&json!("C:\\work")));
+        assert_eq!(config.get("cdf.destination"), Some(&json!("D:\\work")));
+        assert_eq!(config.get("exclude"), Some(&json!(["tmp"])));
+        assert_eq!(config.origins["bts.configs.photos.overwrite"], CLI_ORIGIN);
+        assert_eq!(config.origins["exclude.0"], CLI_ORIGIN);
+
+        config.apply_override("bts.configs.0.source=D:\\photos").unwrap();
+        assert_eq!(config.origins["bts.configs.photos.source"], CLI_ORIGIN);
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        let mut config = LayeredConfig::new();
+        config.merge(json!({"cdf": {"source": "a"}, "configs": [{"name": "photos"}]}), "base.json");
+
+        assert!(config.apply_override("cdf.source").is_err());
+        assert!(config.apply_override("=1").is_err());
+        assert!(config.apply_override("cdf.source.deeper=1").is_err());
+        assert!(config.apply_override("configs.missing.source=a").is_err());
+    }
+
+    #[test]
+    fn strips_comments_outside_strings() {
+        let contents = "{\n  // line comment\n  \"url\": \"http://host/*x*/\", /* block\n comment */ \"a\": \"\\\"//\"\n}";
+        let stripped = strip_comments(contents);
+
+        assert_eq!(stripped.len(), contents.len());
+        assert_eq!(stripped.lines().count(), contents.lines().count());
+        let value: Value = serde_json::from_str(&stripped).unwrap();
+        assert_eq!(value, json!({"url": "http://host/*x*/", "a": "\"//"}));
+    }
+}
@@ -0,0 +1,66 @@
+//! # folder-sync-rs
+//!
+//! Library of the `folder-sync-rs` backup tool. The command-line program in `main.rs` is a thin wrapper around it.
+//!
+//! To run a backup from another program, use [`sync::SyncJob`]:
+//!
+//! ```no_run
+//! use folder_sync_rs::sync::{SyncJob, SyncOptions};
+//!
+//! let report = SyncJob::new("C:\\work", "D:\\backup\\work")
+//!     .options(SyncOptions::new().overwrite(true))
+//!     .run()?;
+//! println!("{} copied, {} unchanged", report.copied, report.skipped_unchanged);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The public API is made of [`sync`], [`config`] (for example [`config::load_config`] to read a configuration file
+//! and pass its jobs to [`sync::SyncJob::from_config`]) and [`storage`], the trait implemented by destination backends.
+//! The modules implementing the commands of the program are hidden and may change without notice.
+
+pub(crate) mod api;
+pub(crate) mod archive;
+#[doc(hidden)]
+pub mod backup;
+#[doc(hidden)]
+pub mod commands;
+pub mod config;
+pub(crate) mod crypt;
+#[doc(hidden)]
+pub mod daemon;
+pub(crate) mod delta;
+#[doc(hidden)]
+pub mod diff;
+pub(crate) mod filter;
+#[doc(hidden)]
+pub mod folders;
+pub(crate) mod hash;
+#[doc(hidden)]
+pub mod init;
+pub(crate) mod layers;
+#[doc(hidden)]
+pub mod list;
+#[doc(hidden)]
+pub mod manifest;
+#[doc(hidden)]
+pub mod messages;
+pub(crate) mod paths;
+#[doc(hidden)]
+pub mod remote;
+#[doc(hidden)]
+pub mod repository;
+#[doc(hidden)]
+pub mod restore;
+pub(crate) mod s3;
+pub(crate) mod schedule;
+pub(crate) mod sftp;
+pub(crate) mod shortcuts;
+pub mod storage;
+pub mod sync;
+pub(crate) mod utils;
+#[doc(hidden)]
+pub mod verify;
+#[doc(hidden)]
+pub mod watch;
+pub(crate) mod webdav;
+pub(crate) mod xml;
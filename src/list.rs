@@ -0,0 +1,104 @@
+//! # List Module
+//!
+//! This module prints the files in the destination of backup jobs, from a backup folder, a backup archive,
+//! an encrypted backup or a snapshot of a repository, optionally for the version written on a given date.
+
+use crate::archive::{list_archive, ArchiveEntry, ArchiveFormat};
+use crate::commands::ListArgs;
+use crate::config::BtsConfigWrapper;
+use crate::filter::FileFilter;
+use crate::messages::*;
+use crate::repository::{open_snapshot, repository_path};
+use crate::restore::{backup_location, open_encrypted_backup};
+use crate::storage::{storage_key, walk_storage, Storage};
+use crate::utils::walk_tree;
+use chrono::{DateTime, Local};
+use std::path::Path;
+
+/// Lists the backups of the selected jobs.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `args` - A reference to the `ListArgs` given on the command line.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if every backup is listed, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found.
+/// * Returns an error if the requested version or the destination does not exist.
+/// * Returns an error if the backup cannot be read, or is encrypted and its key does not match.
+/// * Returns an error if the destination is a repository without a matching snapshot.
+pub fn execute_list(bts_config_wrapper: &BtsConfigWrapper, args: &ListArgs) -> Result<(), Box<dyn std::error::Error>> {
+    for config in bts_config_wrapper.select(args.job.as_deref())? {
+        let entries = if repository_path(&config.destination).is_some() {
+            let snapshot = open_snapshot(config, args.at.as_deref())?;
+            println!("{}", MSG_LISTING.replace("{}", &snapshot.describe()));
+            list_storage(&snapshot)?
+        } else {
+            let backup = backup_location(config, args.at.as_deref())?;
+            println!("{}", MSG_LISTING.replace("{}", &backup.display().to_string()));
+            match ArchiveFormat::of(&backup.to_string_lossy()) {
+                Some(_) => list_archive(&backup)?,
+                None if config.encryption.is_some() => list_storage(&*open_encrypted_backup(config, &backup)?)?,
+                None => list_tree(&backup)?,
+            }
+        };
+        for entry in &entries {
+            print_entry(entry);
+        }
+
+        let files = entries.iter().filter(|entry| !entry.is_dir).count();
+        let bytes: u64 = entries.iter().map(|entry| entry.len).sum();
+        println!("  {}", MSG_LIST_SUMMARY
+            .replacen("{}", &files.to_string(), 1)
+            .replacen("{}", &(entries.len() - files).to_string(), 1)
+            .replacen("{}", &bytes.to_string(), 1));
+    }
+    Ok(())
+}
+
+/// Lists the files and folders of a backup folder, in the same form as the entries of an archive.
+fn list_tree(backup: &Path) -> Result<Vec<ArchiveEntry>, Box<dyn std::error::Error>> {
+    let mut entries = vec![];
+    walk_tree(backup, &FileFilter::all(backup), &mut |relative, metadata| {
+        entries.push(ArchiveEntry {
+            path: relative.to_path_buf(),
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Lists the files and folders of an encrypted backup or a snapshot, with the sizes and modification times of their sources.
+fn list_storage(storage: &dyn Storage) -> Result<Vec<ArchiveEntry>, Box<dyn std::error::Error>> {
+    let mut entries = vec![];
+    walk_storage(storage, Path::new(""), &mut |relative, metadata| {
+        entries.push(ArchiveEntry {
+            path: relative.to_path_buf(),
+            is_dir: metadata.is_dir,
+            len: metadata.len,
+            modified: metadata.modified,
+        });
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Prints one entry: modification time, size and path (folders end with `/`).
+fn print_entry(entry: &ArchiveEntry) {
+    let modified = entry.modified
+        .map(|time| DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "-".repeat(19));
+    if entry.is_dir {
+        println!("  {}  {:>12}  {}/", modified, "", storage_key(&entry.path));
+    } else {
+        println!("  {}  {:>12}  {}", modified, entry.len, storage_key(&entry.path));
+    }
+}
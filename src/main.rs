@@ -1,21 +1,15 @@
 // cspell:ignore simplelog PKGNAME indifatif
-mod config;
-mod commands;
-mod backup;
-mod folders;
-mod utils;
-mod messages;
 
 use clap::Parser;
 use log::info;
 use std::io::{self, Write};
-use log4rs;
 use std::sync::Arc;
 use std::time::Instant;
 
-use crate::config::load_config;
-use crate::commands::{Cli,Commands};
-use crate::messages::*;
+use folder_sync_rs::{backup, config, daemon, diff, folders, init, list, manifest, remote, repository, restore, verify, watch};
+use folder_sync_rs::config::{load_config,load_overrides};
+use folder_sync_rs::commands::{BackupArgs,Cli,Commands,CreateFoldersArgs,DiffFormat};
+use folder_sync_rs::messages::*;
 use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -48,7 +42,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
 
-    let config = load_config(&config_path).expect(ERR_FAILED_TO_LOAD_CONFIG);
+    match &cli.command {
+        Some(Commands::ShowConfig) => return config::execute_show_config(&config_path, &cli.set),
+        Some(Commands::Init) => return init::execute_init(&config_path),
+        Some(Commands::Serve(args)) => {
+            info!("{}", LOG_SERVE_MODE);
+            return remote::execute_serve(args);
+        }
+        _ => {}
+    }
+
+    // コマンドラインだけでジョブが完結し、設定ファイルが無い場合は --set だけを適用した空の設定を使用
+    let self_contained = match &cli.command {
+        Some(Commands::BackupToSsd(args)) => args.is_self_contained(),
+        Some(Commands::CreateFolders(args)) => args.is_self_contained(),
+        Some(Commands::Scrub(args)) => args.path.is_some(),
+        Some(Commands::Diff(args)) => args.left.is_some(),
+        _ => false,
+    };
+    let mut config = if self_contained && !config_path.exists() {
+        load_overrides(&cli.set)?
+    } else {
+        load_config(&config_path, &cli.set).map_err(|e| format!("{} : {}", ERR_FAILED_TO_LOAD_CONFIG, e))?
+    };
+    match &cli.command {
+        Some(Commands::BackupToSsd(args)) => args.apply(&mut config)?,
+        Some(Commands::CreateFolders(args)) => args.apply(&mut config)?,
+        _ => {}
+    }
     let config = Arc::new(config);
 
     // コマンドライン引数のparse
@@ -66,11 +87,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let mode = input.trim().parse::<u32>();
                     match mode {
                         Ok(1) => {
-                            cli.command = Some(Commands::BackupToSsd);
+                            cli.command = Some(Commands::BackupToSsd(BackupArgs::default()));
                             break;
                         }
                         Ok(2) => {
-                            cli.command = Some(Commands::CreateFolders);
+                            cli.command = Some(Commands::CreateFolders(CreateFoldersArgs::default()));
                             break;
                         }
                         _ => {
@@ -100,19 +121,60 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 
     match &cli.command {
-        Some(Commands::BackupToSsd) => {
+        Some(Commands::BackupToSsd(_)) => {
             info!("{}", LOG_BACKUP_MODE);
             backup::execute_backup(&config.bts)?;
         }
-        Some(Commands::CreateFolders) => {
+        Some(Commands::CreateFolders(_)) => {
             info!("{}", LOG_CREATE_FOLDERS_MODE);
             folders::execute_create_folders(&config.cdf)?;
         }
-        None => unreachable!(),
+        Some(Commands::Verify(args)) => {
+            info!("{}", LOG_VERIFY_MODE);
+            verify::execute_verify(&config.bts, args.job.as_deref())?;
+        }
+        Some(Commands::Scrub(args)) => {
+            info!("{}", LOG_SCRUB_MODE);
+            manifest::execute_scrub(&config.bts, args.job.as_deref(), args.path.as_deref())?;
+        }
+        Some(Commands::Restore(args)) => {
+            info!("{}", LOG_RESTORE_MODE);
+            restore::execute_restore(&config.bts, args)?;
+        }
+        Some(Commands::List(args)) => {
+            info!("{}", LOG_LIST_MODE);
+            list::execute_list(&config.bts, args)?;
+        }
+        Some(Commands::Diff(args)) => {
+            info!("{}", LOG_DIFF_MODE);
+            diff::execute_diff(&config.bts, args)?;
+        }
+        Some(Commands::Watch(args)) => {
+            info!("{}", LOG_WATCH_MODE);
+            watch::execute_watch(&config.bts, args)?;
+        }
+        Some(Commands::Daemon(args)) => {
+            info!("{}", LOG_DAEMON_MODE);
+            let state_path = match &args.state {
+                Some(state) => PathBuf::from(state),
+                None => daemon::default_state_path(&config_path),
+            };
+            daemon::execute_daemon(&config.bts, args, &state_path)?;
+        }
+        Some(Commands::Gc(args)) => {
+            info!("{}", LOG_GC_MODE);
+            repository::execute_gc(&config.bts, args)?;
+        }
+        Some(Commands::ShowConfig) | Some(Commands::Init) | Some(Commands::Serve(_)) | None => unreachable!(),
     }
     
     info!("{}", LOG_FINISH);
 
+    // JSON 出力は他のプログラムで読み込めるように余計な表示をしない
+    if matches!(&cli.command, Some(Commands::Diff(args)) if args.format == DiffFormat::Json) {
+        return Ok(());
+    }
+
     // 処理時間計測終了
     let end = Instant::now();
     let duration = end.duration_since(start);
@@ -0,0 +1,304 @@
+//! # Manifest Module
+//!
+//! This module writes a manifest of the files in a backup destination (path, size, modification time and SHA-256 hash)
+//! and checks a destination against its manifest to find corrupted files, even when the source is no longer available.
+//!
+//! The manifest is a tab-separated text file named `.folder-sync-manifest.tsv` at the root of the destination:
+//!
+//! ```text
+//! # folder-sync-rs manifest v1
+//! <sha256>\t<size>\t<mtime (RFC 3339, UTC)>\t<path relative to the destination, `/`-separated>
+//! ```
+//!
+//! Backslashes, tabs and line breaks in paths are escaped as `\\`, `\t`, `\n` and `\r`.
+
+use crate::config::BtsConfigWrapper;
+use crate::filter::FileFilter;
+use crate::hash::hash_file;
+use crate::messages::*;
+use crate::repository::repository_path;
+use crate::utils::walk_tree;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::path::{Component, Path, PathBuf};
+
+/// File name of the manifest.
+pub const MANIFEST_FILE: &str = ".folder-sync-manifest.tsv";
+
+/// First line of the manifest.
+const MANIFEST_HEADER: &str = "# folder-sync-rs manifest v1";
+
+/// A file recorded in the manifest.
+#[derive(Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// File size in bytes.
+    pub size: u64,
+    /// Modification time (RFC 3339, UTC).
+    pub mtime: String,
+    /// SHA-256 hash in lowercase hexadecimal.
+    pub hash: String,
+}
+
+impl ManifestEntry {
+    /// Returns `true` if the size and modification time match the metadata.
+    fn matches_metadata(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.len() && Some(&self.mtime) == format_mtime(metadata).as_ref()
+    }
+}
+
+/// Manifest entries keyed by their `/`-separated relative path.
+pub type Manifest = BTreeMap<String, ManifestEntry>;
+
+/// Writes the manifest of a backup destination.
+///
+/// Files whose size and modification time match the previous manifest are not hashed again.
+///
+/// # Arguments
+///
+/// * `destination` - A reference to the `Path` of the destination folder.
+/// * `filter` - A reference to the `FileFilter` of the job. Only its path patterns are applied to the destination (see `FileFilter::for_destination`).
+///
+/// # Errors
+///
+/// * Returns an error if the destination cannot be walked.
+/// * Returns an error if a file cannot be hashed.
+/// * Returns an error if the manifest cannot be written.
+pub fn write_manifest(destination: &Path, filter: &FileFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest_path = destination.join(MANIFEST_FILE);
+    let previous = if manifest_path.exists() {
+        read_manifest(&manifest_path).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable manifest {} : {}", manifest_path.display(), e);
+            Manifest::new()
+        })
+    } else {
+        Manifest::new()
+    };
+
+    let mut manifest = Manifest::new();
+    walk_tree(destination, &filter.for_destination(destination), &mut |relative, metadata| {
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        let key = manifest_key(relative);
+        let entry = match previous.get(&key) {
+            Some(entry) if entry.matches_metadata(metadata) => entry.clone(),
+            _ => ManifestEntry {
+                size: metadata.len(),
+                mtime: format_mtime(metadata).unwrap_or_default(),
+                hash: hash_file(&destination.join(relative))?,
+            },
+        };
+        manifest.insert(key, entry);
+        Ok(())
+    })?;
+
+    let mut contents = format!("{}\n", MANIFEST_HEADER);
+    for (key, entry) in &manifest {
+        contents.push_str(&format!("{}\t{}\t{}\t{}\n", entry.hash, entry.size, entry.mtime, escape(key)));
+    }
+
+    // 途中で中断しても既存のマニフェストが壊れないように一時ファイル経由で置き換える
+    let temporary_path = destination.join(format!("{}.tmp", MANIFEST_FILE));
+    fs::write(&temporary_path, contents)?;
+    fs::rename(&temporary_path, &manifest_path)?;
+    info!("Wrote manifest: {} ({} files)", manifest_path.display(), manifest.len());
+
+    Ok(())
+}
+
+/// Reads a manifest file.
+///
+/// # Errors
+///
+/// * Returns an error if the file cannot be read.
+/// * Returns an error if a line is malformed.
+pub fn read_manifest(path: &Path) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let mut manifest = Manifest::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || ERR_INVALID_MANIFEST.replace("{}", &format!("{}:{}", path.display(), number + 1));
+        let mut fields = line.splitn(4, '\t');
+        let (Some(hash), Some(size), Some(mtime), Some(key)) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid().into());
+        };
+        let entry = ManifestEntry {
+            size: size.parse().map_err(|_| invalid())?,
+            mtime: mtime.to_string(),
+            hash: hash.to_string(),
+        };
+        manifest.insert(unescape(key), entry);
+    }
+    Ok(manifest)
+}
+
+/// Re-hashes backup destinations against their manifests and prints corrupted, changed and missing files.
+///
+/// A corrupted file has the size and modification time recorded in the manifest but a different hash.
+/// A changed file has a different size or modification time, so it was modified after the manifest was written.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `job` - The name of the job to scrub, or `None` for every job.
+/// * `path` - A destination folder to scrub instead of the configured jobs.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found, or its destination is encrypted.
+/// * Returns an error if a manifest cannot be read.
+/// * Returns an error if corrupted or missing files are found.
+pub fn execute_scrub(
+    bts_config_wrapper: &BtsConfigWrapper,
+    job: Option<&str>,
+    path: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let destinations: Vec<PathBuf> = match path {
+        Some(path) => vec![PathBuf::from(path)],
+        None => bts_config_wrapper.select(job)?.iter()
+            .map(|config| {
+                if config.encryption.is_some() {
+                    Err(ERR_ENCRYPTED_UNSUPPORTED.replace("{}", &config.destination))
+                } else if repository_path(&config.destination).is_some() {
+                    Err(ERR_REPOSITORY_UNSUPPORTED.replace("{}", &config.destination))
+                } else {
+                    Ok(PathBuf::from(&config.destination))
+                }
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut clean = true;
+    for destination in destinations {
+        println!("{}", MSG_SCRUBBING.replace("{}", &destination.display().to_string()));
+        clean &= scrub_destination(&destination)?;
+    }
+
+    if clean {
+        Ok(())
+    } else {
+        Err(ERR_SCRUB_PROBLEMS.into())
+    }
+}
+
+/// Scrubs a single destination. Returns `Ok(true)` if no corrupted or missing file is found.
+fn scrub_destination(destination: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let manifest_path = destination.join(MANIFEST_FILE);
+    if !manifest_path.exists() {
+        return Err(ERR_MANIFEST_NOT_FOUND.replace("{}", &manifest_path.display().to_string()).into());
+    }
+    let manifest = read_manifest(&manifest_path)?;
+
+    let (mut corrupted, mut changed, mut missing, mut ok) = (0, 0, 0, 0);
+    for (key, entry) in &manifest {
+        let path = destination.join(key);
+        let Ok(metadata) = fs::metadata(&path) else {
+            println!("  MISSING   {}", key);
+            missing += 1;
+            continue;
+        };
+        if !entry.matches_metadata(&metadata) {
+            println!("  CHANGED   {}", key);
+            changed += 1;
+            continue;
+        }
+        match hash_file(&path) {
+            Ok(hash) if hash == entry.hash => ok += 1,
+            Ok(_) => {
+                println!("  CORRUPTED {}", key);
+                corrupted += 1;
+            }
+            Err(e) => {
+                println!("  CORRUPTED {} ({})", key, e);
+                corrupted += 1;
+            }
+        }
+    }
+
+    let summary = MSG_SCRUB_SUMMARY
+        .replacen("{}", &ok.to_string(), 1)
+        .replacen("{}", &corrupted.to_string(), 1)
+        .replacen("{}", &changed.to_string(), 1)
+        .replacen("{}", &missing.to_string(), 1);
+    println!("  {}", summary);
+    info!("Scrubbed {} : {}", destination.display(), summary);
+
+    Ok(corrupted == 0 && missing == 0)
+}
+
+/// Returns the manifest key of a relative path (`/`-separated).
+pub fn manifest_key(relative: &Path) -> String {
+    relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Formats the modification time of a file for the manifest.
+pub fn format_mtime(metadata: &Metadata) -> Option<String> {
+    let modified: DateTime<Utc> = metadata.modified().ok()?.into();
+    Some(modified.to_rfc3339_opts(SecondsFormat::Nanos, true))
+}
+
+/// Escapes a path for the manifest.
+fn escape(key: &str) -> String {
+    key.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// Reverses `escape`.
+fn unescape(key: &str) -> String {
+    let mut result = String::with_capacity(key.len());
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BtsConfig, BtsConfigWrapper};
+    use filetime::FileTime;
+
+    #[test]
+    fn manifest_lists_destination_files_selected_by_patterns() {
+        let destination = tempfile::tempdir().unwrap();
+        fs::write(destination.path().join("old.txt"), "old").unwrap();
+        fs::write(destination.path().join("tab\tname.txt"), "tab").unwrap();
+        fs::write(destination.path().join("b.tmp"), "b").unwrap();
+        filetime::set_file_mtime(destination.path().join("old.txt"), FileTime::from_unix_time(946_684_800, 0)).unwrap();
+
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": "/source",
+            "destination": destination.path(),
+            "overwrite": true,
+            "exclude": ["*.tmp"],
+            "modified_after": "7d",
+        })).unwrap();
+        let filter = FileFilter::new(&config, &BtsConfigWrapper::default());
+        write_manifest(destination.path(), &filter).unwrap();
+
+        let manifest = read_manifest(&destination.path().join(MANIFEST_FILE)).unwrap();
+        assert_eq!(manifest.keys().collect::<Vec<_>>(), ["old.txt", "tab\tname.txt"]);
+        assert_eq!(manifest["old.txt"].size, 3);
+        assert_eq!(manifest["old.txt"].hash, hash_file(&destination.path().join("old.txt")).unwrap());
+    }
+}
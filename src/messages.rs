@@ -2,12 +2,118 @@ pub const LOG_START: &str                       = "Start folder sync app";
 pub const LOG_FINISH: &str                      = "Finish folder sync app";
 pub const LOG_BACKUP_MODE: &str                 = "Backup mode";
 pub const LOG_CREATE_FOLDERS_MODE: &str         = "Create folders mode";
+pub const LOG_VERIFY_MODE: &str                 = "Verify mode";
+pub const LOG_SCRUB_MODE: &str                  = "Scrub mode";
+pub const LOG_RESTORE_MODE: &str                = "Restore mode";
+pub const LOG_DIFF_MODE: &str                   = "Diff mode";
+pub const LOG_WATCH_MODE: &str                  = "Watch mode";
+pub const LOG_DAEMON_MODE: &str                 = "Daemon mode";
+pub const LOG_LIST_MODE: &str                   = "List mode";
+pub const LOG_GC_MODE: &str                     = "Gc mode";
+pub const LOG_SERVE_MODE: &str                  = "Serve mode";
 
 pub const MSG_PRESS_ENTER_TO_EXIT: &str         = "Press Enter to exit...";
 pub const MSG_BACKUP_COMPLETE: &str             = "Backup complete";
 pub const MSG_BACKING_UP: &str                  = "Backing up";
 pub const MSG_EXECUTE_TIME: &str                = "Execution time: {} s";
+pub const MSG_CONFIG_ORIGINS: &str              = "Value origins:";
+pub const MSG_VERIFYING: &str                   = "Verifying : {}";
+pub const MSG_VERIFY_OK: &str                   = "OK : {} files match";
+pub const MSG_VERIFY_SUMMARY: &str              = "{} mismatched, {} missing, {} extra";
+pub const MSG_SCRUBBING: &str                   = "Scrubbing : {}";
+pub const MSG_SCRUB_SUMMARY: &str               = "{} ok, {} corrupted, {} changed, {} missing";
+pub const MSG_RESTORING: &str                   = "Restoring : {}";
+pub const MSG_RESTORE_SUMMARY: &str             = "{} restored, {} skipped";
+pub const MSG_LISTING: &str                     = "Listing : {}";
+pub const MSG_LIST_SUMMARY: &str                = "{} files, {} folders, {} bytes";
+pub const MSG_DIFF_SUMMARY: &str                = "{} differences";
+pub const MSG_WATCHING: &str                    = "Watching : {}";
+pub const MSG_WATCH_POLLING: &str               = "Cannot watch, rescanning periodically : {}";
+pub const MSG_WATCH_SYNCED: &str                = "Synced {} : {} changes";
+pub const MSG_DAEMON_SCHEDULED: &str            = "Scheduled {} : next run at {}";
+pub const MSG_DAEMON_STARTED: &str              = "Started : {}";
+pub const MSG_DAEMON_FINISHED: &str             = "Finished : {}";
+pub const MSG_DAEMON_FAILED: &str               = "Failed {} : {}";
+pub const MSG_DAEMON_SKIPPED: &str              = "Skipped, previous run still in progress : {}";
+pub const MSG_API_LISTENING: &str               = "Control API listening on http://{}";
+pub const MSG_INIT_WELCOME: &str                = "Creating config file : {}";
+pub const MSG_INIT_CANCELLED: &str              = "Init cancelled";
+pub const MSG_INIT_WRITTEN: &str                = "Config file written : {}";
+pub const MSG_GC: &str                          = "Collecting garbage : {}";
+pub const MSG_GC_SUMMARY: &str                  = "{} snapshots removed, {} chunks removed, {} bytes freed";
+pub const MSG_SERVING: &str                     = "Serving {} on {} (Ctrl + C to stop)";
 
 pub const ERR_SOURCE_FOLDER_NOT_EXIST: &str     = "Source folder does not exist : {}";
 pub const ERR_FAILED_TO_LOAD_CONFIG: &str       = "Failed to load config.json";
 pub const ERR_FAILED_TO_GET_DIRECTORY: &str     = "Failed to get executable directory";
+pub const ERR_UNDEFINED_ENV_VAR: &str           = "Environment variable is not defined : {}";
+pub const ERR_UNCLOSED_ENV_VAR: &str            = "Unclosed environment variable reference : {}";
+pub const ERR_INVALID_DATE_FORMAT: &str         = "Invalid date format : {}";
+pub const ERR_HOME_NOT_FOUND: &str              = "Failed to get home directory";
+pub const ERR_FAILED_TO_READ_CONFIG: &str       = "Failed to read config file : {}";
+pub const ERR_CONFIG_NOT_OBJECT: &str           = "Config file must contain a JSON object : {}";
+pub const ERR_INVALID_INCLUDE: &str             = "\"include\" must be a string or a list of strings : {}";
+pub const ERR_INCLUDE_CYCLE: &str               = "Config include cycle detected : {}";
+pub const ERR_INVALID_OVERRIDE: &str            = "Invalid override (expected KEY=VALUE) : {}";
+pub const ERR_UNKNOWN_OVERRIDE: &str            = "Unknown config key in override : {}";
+pub const ERR_INVALID_SIZE: &str                = "Invalid size (expected bytes or e.g. \"500MB\") : {}";
+pub const ERR_INVALID_TIME: &str                = "Invalid time (expected e.g. \"2025-01-31\" or \"90d\") : {}";
+pub const ERR_VERIFY_FAILED: &str               = "Copied file does not match its source : {}";
+pub const ERR_VERIFY_PROBLEMS: &str             = "Verification found differences";
+pub const ERR_JOB_NOT_FOUND: &str               = "Backup job not found : {}";
+pub const ERR_INVALID_MANIFEST: &str            = "Invalid manifest line : {}";
+pub const ERR_MANIFEST_NOT_FOUND: &str          = "Manifest not found : {}";
+pub const ERR_SCRUB_PROBLEMS: &str              = "Scrub found corrupted or missing files";
+pub const ERR_RESTORE_TARGET_AMBIGUOUS: &str    = "--to requires --job when several jobs are configured";
+pub const ERR_NO_VERSIONS: &str                 = "Destination has no {date} placeholder, so no versions exist : {}";
+pub const ERR_BACKUP_NOT_FOUND: &str            = "Backup not found : {}";
+pub const ERR_WATCHER_STOPPED: &str             = "File watcher stopped unexpectedly";
+pub const ERR_INVALID_SCHEDULE: &str            = "Invalid schedule (expected e.g. \"6h\" or \"0 3 * * *\") : {}";
+pub const ERR_NO_SCHEDULED_JOBS: &str           = "No backup job has a schedule";
+pub const ERR_JOB_PANICKED: &str                = "Job stopped unexpectedly : {}";
+pub const ERR_DAEMON_CHANNEL_CLOSED: &str       = "Job completion channel closed unexpectedly";
+pub const ERR_CANCELLED: &str                   = "Cancelled";
+pub const ERR_API_LISTEN: &str                  = "Failed to listen on {} : {}";
+pub const ERR_API_TOKEN_REQUIRED: &str          = "The control API needs a token (--token-file or FOLDER_SYNC_API_TOKEN) to listen on a non-loopback address : {}";
+pub const ERR_API_TOKEN_EMPTY: &str             = "API token file is empty : {}";
+pub const ERR_API_UNAUTHORIZED: &str            = "Missing or invalid API token";
+pub const ERR_API_FOREIGN_ORIGIN: &str          = "Requests from other hosts or web pages need an API token : {}";
+pub const ERR_API_NOT_FOUND: &str               = "Not found : {}";
+pub const ERR_API_METHOD: &str                  = "Method not allowed : {}";
+pub const ERR_API_NOT_RUNNING: &str             = "Job is not running : {}";
+pub const ERR_UNSUPPORTED_STORAGE: &str         = "Unsupported destination : {}";
+pub const ERR_INVALID_URL: &str                 = "Invalid URL : {}";
+pub const ERR_SSH_HOST_UNKNOWN: &str            = "Host key of {} is not in ~/.ssh/known_hosts (connect once with ssh to add it)";
+pub const ERR_SSH_HOST_MISMATCH: &str           = "Host key of {} does not match ~/.ssh/known_hosts";
+pub const ERR_SSH_AUTH: &str                    = "SSH authentication failed for {}";
+pub const ERR_S3_CREDENTIALS: &str              = "S3 credentials are not set : {}";
+pub const ERR_S3_REQUEST: &str                  = "S3 request failed : {} ({})";
+pub const ERR_WEBDAV_REQUEST: &str              = "WebDAV request failed : {} ({})";
+pub const ERR_ARCHIVE_FORMAT: &str              = "Unsupported archive format : {}";
+pub const ERR_ARCHIVE_NOT_LOCAL: &str           = "Archive destinations must be local files : {}";
+pub const ERR_ARCHIVE_FILE_CHANGED: &str        = "File changed while being archived : {}";
+pub const ERR_ENCRYPTION_SECRET: &str           = "Encryption needs a passphrase, a key file or the FOLDER_SYNC_PASSPHRASE environment variable : {}";
+pub const ERR_ENCRYPTION_KEY_MISMATCH: &str     = "Wrong passphrase or key file for encrypted destination : {}";
+pub const ERR_READ_KEY_FILE: &str               = "Failed to read key file {} : {}";
+pub const ERR_ENCRYPT: &str                     = "Failed to encrypt file";
+pub const ERR_DECRYPT: &str                     = "Failed to decrypt (wrong key, corrupted or modified file) : {}";
+pub const ERR_ENCRYPTED_ARCHIVE: &str           = "Archive destinations cannot be encrypted : {}";
+pub const ERR_ENCRYPTED_UNSUPPORTED: &str       = "Encrypted destinations are not supported by this command : {}";
+pub const ERR_ENCRYPTED_REPOSITORY: &str        = "Repository destinations cannot be encrypted : {}";
+pub const ERR_REPOSITORY_UNSUPPORTED: &str      = "Repository destinations are not supported by this command : {}";
+pub const ERR_REPOSITORY_NOT_FOUND: &str        = "Not a repository : {}";
+pub const ERR_REPOSITORY_LOCKED: &str           = "Repository is in use by a running backup or gc (locks of other machines are not cleared automatically, remove them if nothing is running) : {}";
+pub const ERR_CHUNK_CORRUPTED: &str             = "Chunk is missing or corrupted : {}";
+pub const ERR_SNAPSHOT_NOT_FOUND: &str          = "No snapshot of {} in repository : {}";
+pub const ERR_SNAPSHOT_READ_ONLY: &str          = "Snapshots cannot be modified : {}";
+pub const ERR_SNAPSHOT_JOB_CONFLICT: &str       = "Jobs {} and {} store snapshots under the same name {} in repository {} : set a different name for each job";
+pub const ERR_NO_REPOSITORIES: &str             = "No backup job has a repository destination";
+pub const ERR_DELTA_CHECKSUM: &str              = "Delta-updated file does not match its source : {}";
+pub const ERR_REMOTE_SECRET: &str               = "Remote sync needs a secret file or the FOLDER_SYNC_SECRET environment variable";
+pub const ERR_READ_SECRET_FILE: &str            = "Failed to read secret file {} : {}";
+pub const ERR_REMOTE_AUTH: &str                 = "Remote authentication failed, the secrets do not match : {}";
+pub const ERR_REMOTE_VERSION: &str              = "Unsupported remote protocol version : {}";
+pub const ERR_REMOTE_PROTOCOL: &str             = "Unexpected data from remote peer : {}";
+pub const ERR_REMOTE_PATH: &str                 = "Invalid remote path : {}";
+pub const ERR_FRAME_TOO_LARGE: &str             = "Frame too large : {} bytes";
+pub const ERR_SERVE_ROOT: &str                  = "Folder to serve does not exist : {}";
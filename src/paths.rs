@@ -0,0 +1,202 @@
+//! # Path Template Module
+//!
+//! This module expands path templates written in the configuration file.
+//! It supports the home directory (`~`), environment variables (`${VAR}`)
+//! and the `{date}`, `{date:<format>}` and `{hostname}` placeholders.
+
+// cspell:ignore strftime gethostname USERPROFILE
+
+use crate::messages::*;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+
+/// Default format used by the `{date}` placeholder.
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Values substituted into path templates.
+///
+/// The context is created once per configuration load so that every path
+/// expanded from the same file sees the same date and host name.
+pub struct TemplateContext {
+    /// Date and time used by the `{date}` placeholders.
+    pub now: DateTime<Local>,
+    /// Host name used by the `{hostname}` placeholder.
+    pub hostname: String,
+}
+
+impl TemplateContext {
+    /// Creates a context for the current time and host.
+    pub fn current() -> Self {
+        TemplateContext {
+            now: Local::now(),
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Creates a context for the given time and the current host.
+    pub fn at(now: DateTime<Local>) -> Self {
+        TemplateContext {
+            now,
+            ..TemplateContext::current()
+        }
+    }
+}
+
+/// Returns `true` if the template contains a `{date}` or `{date:<format>}` placeholder,
+/// meaning every run writes to a dated location.
+pub fn has_date_placeholder(template: &str) -> bool {
+    template.contains("{date}") || template.contains("{date:")
+}
+
+/// Expands a path template.
+///
+/// * A leading `~` followed by a separator (or nothing) is replaced with the home directory.
+/// * `${VAR}` is replaced with the value of the environment variable `VAR`.
+/// * `{date}` is replaced with the current date (`%Y-%m-%d`), `{date:<format>}` uses a `strftime` format.
+/// * `{hostname}` is replaced with the host name.
+///
+/// Any other text in braces is left untouched, so folder names such as `{GUID}` stay valid.
+///
+/// # Arguments
+///
+/// * `template` - The path template to expand.
+/// * `context` - A reference to the `TemplateContext` holding the substituted values.
+///
+/// # Returns
+///
+/// Returns `Ok(String)` with the expanded path, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if the home directory cannot be determined.
+/// * Returns an error if a referenced environment variable is not defined.
+/// * Returns an error if a date format is invalid.
+pub fn expand_path(template: &str, context: &TemplateContext) -> Result<String, Box<dyn std::error::Error>> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    // 先頭の ~ をホームディレクトリに置換
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with('/') || after.starts_with('\\') {
+            result.push_str(&home_dir()?);
+            rest = after;
+        }
+    }
+
+    while let Some(pos) = rest.find(['$', '{']) {
+        result.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+
+        if let Some(inner) = tail.strip_prefix("${") {
+            // ${VAR} を環境変数の値に置換
+            let end = inner.find('}').ok_or_else(|| ERR_UNCLOSED_ENV_VAR.replace("{}", template))?;
+            let name = &inner[..end];
+            let value = std::env::var(name).map_err(|_| ERR_UNDEFINED_ENV_VAR.replace("{}", name))?;
+            result.push_str(&value);
+            rest = &inner[end + 1..];
+        } else if let Some((placeholder, after)) = tail.strip_prefix('{').and_then(|s| s.split_once('}')) {
+            match expand_placeholder(placeholder, context)? {
+                Some(value) => result.push_str(&value),
+                None => {
+                    // 未知のプレースホルダはそのまま残す
+                    result.push('{');
+                    result.push_str(placeholder);
+                    result.push('}');
+                }
+            }
+            rest = after;
+        } else {
+            result.push_str(&tail[..1]);
+            rest = &tail[1..];
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Expands a single `{...}` placeholder.
+///
+/// Returns `Ok(None)` when the placeholder is not known so the caller can keep it as written.
+fn expand_placeholder(placeholder: &str, context: &TemplateContext) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if placeholder == "hostname" {
+        return Ok(Some(context.hostname.clone()));
+    }
+
+    let format = match placeholder.strip_prefix("date") {
+        Some("") => DEFAULT_DATE_FORMAT,
+        Some(format) => match format.strip_prefix(':') {
+            Some(format) => format,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    // chrono は不正なフォーマットで表示時にパニックするため、先に検証する
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(ERR_INVALID_DATE_FORMAT.replace("{}", format).into());
+    }
+
+    Ok(Some(context.now.format_with_items(items.into_iter()).to_string()))
+}
+
+/// Returns the home directory of the current user.
+pub fn home_dir() -> Result<String, Box<dyn std::error::Error>> {
+    std::env::var("HOME")
+        .or_else(|_| std::env::var("USERPROFILE"))
+        .map_err(|_| ERR_HOME_NOT_FOUND.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn context() -> TemplateContext {
+        TemplateContext {
+            now: Local.with_ymd_and_hms(2025, 3, 9, 14, 5, 0).unwrap(),
+            hostname: "pc01".to_string(),
+        }
+    }
+
+    #[test]
+    fn expands_home_directory() {
+        let home = home_dir().unwrap();
+        assert_eq!(expand_path("~", &context()).unwrap(), home);
+        assert_eq!(expand_path("~/backup", &context()).unwrap(), format!("{}/backup", home));
+        // ~user や途中の ~ は置換しない
+        assert_eq!(expand_path("~user/backup", &context()).unwrap(), "~user/backup");
+        assert_eq!(expand_path("/a/~/b", &context()).unwrap(), "/a/~/b");
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        std::env::set_var("FOLDER_SYNC_TEST_PATHS_DRIVE", "E:");
+        assert_eq!(expand_path("${FOLDER_SYNC_TEST_PATHS_DRIVE}\\backup", &context()).unwrap(), "E:\\backup");
+        assert!(expand_path("${FOLDER_SYNC_TEST_PATHS_UNDEFINED}", &context()).is_err());
+        assert!(expand_path("${FOLDER_SYNC_TEST_PATHS_DRIVE", &context()).is_err());
+        // $ だけなら置換しない
+        assert_eq!(expand_path("a$b", &context()).unwrap(), "a$b");
+    }
+
+    #[test]
+    fn expands_date_placeholders() {
+        assert_eq!(expand_path("backup-{date}", &context()).unwrap(), "backup-2025-03-09");
+        assert_eq!(expand_path("backup-{date:%Y%m%d_%H%M}", &context()).unwrap(), "backup-20250309_1405");
+        assert!(expand_path("{date:%Q}", &context()).is_err());
+        assert!(has_date_placeholder("a/{date:%Y}"));
+        assert!(!has_date_placeholder("a/{hostname}"));
+    }
+
+    #[test]
+    fn expands_hostname() {
+        assert_eq!(expand_path("D:\\{hostname}\\work", &context()).unwrap(), "D:\\pc01\\work");
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        assert_eq!(expand_path("C:\\{GUID}\\{dates}", &context()).unwrap(), "C:\\{GUID}\\{dates}");
+        assert_eq!(expand_path("a{b", &context()).unwrap(), "a{b");
+    }
+}
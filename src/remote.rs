@@ -0,0 +1,1017 @@
+//! # Remote Module
+//!
+//! This module syncs to a folder on another machine over TCP, without mounting anything.
+//! The other machine runs the `serve` command, and jobs use a destination of the form `remote://host[:port]/path`,
+//! where the path is relative to the folder served.
+//!
+//! Both sides read a shared secret from a file or from the `FOLDER_SYNC_SECRET` environment variable.
+//! When connecting, each side proves that it knows the secret with an HMAC-SHA256 of two random nonces,
+//! so the secret itself is never sent. The traffic is not encrypted: use the protocol on a trusted network
+//! or through an SSH tunnel.
+//!
+//! Each frame is a one-byte kind, a four-byte big-endian length and the payload. A message frame holds a JSON
+//! request or response; data frames carry file contents, and an empty data frame ends them:
+//!
+//! ```text
+//! client                                    server
+//! Hello { version, nonce }            ->
+//!                                     <-    Challenge { version, nonce, proof }
+//! Auth { proof }                      ->
+//!                                     <-    Ok
+//! List / Stat / Hash / ... { path }   ->
+//!                                     <-    Entries / Metadata / Hash / ... or Error
+//! Write { path }                      ->
+//!                                     <-    Ok
+//! data, data, ..., empty data         ->
+//!                                     <-    Ok
+//! ```
+//!
+//! Large files that changed are updated with the blocks that differ (see `crate::delta`): the server sends
+//! the hashes of the blocks of its copy, and the client sends only the blocks whose hashes differ.
+
+use crate::commands::ServeArgs;
+use crate::delta::{block_hashes, read_block, DeltaReport, BLOCK_SIZE};
+use crate::hash::{hash_reader, to_hex};
+use crate::messages::*;
+use crate::storage::{storage_key, LocalStorage, RemoteUrl, Storage, StorageEntry, StorageMetadata, StorageWriter};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Default port of the `serve` command.
+pub const DEFAULT_PORT: u16 = 7879;
+
+/// Environment variable holding the shared secret when no secret file is given.
+const SECRET_VARIABLE: &str = "FOLDER_SYNC_SECRET";
+
+/// Version of the protocol, checked when connecting.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Kind of a frame holding a JSON message.
+const MESSAGE_FRAME: u8 = 0;
+
+/// Kind of a frame holding file contents.
+const DATA_FRAME: u8 = 1;
+
+/// Largest frame accepted before authentication.
+const HANDSHAKE_FRAME_SIZE: usize = 4 * 1024;
+
+/// Largest frame accepted, enough for the block hashes of files of about 200 GB.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of the data frames sent when transferring files.
+const DATA_FRAME_SIZE: usize = 256 * 1024;
+
+/// Size of the random nonces of the handshake.
+const NONCE_SIZE: usize = 32;
+
+/// Time allowed to connect to the server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time the server waits for each message of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time after which the server closes a connection on which nothing was received.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Largest number of connections served at the same time. Further connections are closed at once.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Suffix of files being received.
+const TEMPORARY_SUFFIX: &str = ".folder-sync-tmp";
+
+/// Request sent by the client. Paths are `/`-separated and relative to the folder served.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Hello { version: u32, nonce: String },
+    Auth { proof: String },
+    List { path: String },
+    Stat { path: String },
+    Hash { path: String },
+    CreateDir { path: String },
+    Rename { from: String, to: String },
+    Delete { path: String },
+    SetTimes { path: String, modified: SystemTime },
+    /// Followed by the contents of the file from the server.
+    Read { path: String },
+    /// Followed by the contents of the file from the client.
+    Write { path: String },
+    Signature { path: String },
+    /// Followed by the changed blocks from the client, each prefixed with its offset.
+    Patch { path: String, len: u64 },
+}
+
+impl Request {
+    /// Returns `true` if the request changes nothing on the server, so it can be sent again after a failure.
+    fn is_idempotent(&self) -> bool {
+        matches!(self, Request::List { .. } | Request::Stat { .. } | Request::Hash { .. } | Request::Read { .. } | Request::Signature { .. })
+    }
+}
+
+/// Response sent by the server.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Challenge { version: u32, nonce: String, proof: String },
+    Ok,
+    Entries { entries: Vec<StorageEntry> },
+    Metadata { metadata: Option<StorageMetadata> },
+    Hash { hash: String },
+    Signature { block_size: usize, hashes: Vec<String> },
+    Error { message: String },
+}
+
+/// Reads the shared secret from a file, or from the `FOLDER_SYNC_SECRET` environment variable.
+///
+/// # Errors
+///
+/// * Returns an error if the file cannot be read, or neither a file nor the variable gives a secret.
+pub fn read_secret(file: Option<&Path>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let secret = match file {
+        Some(file) => fs::read(file).map_err(|e| ERR_READ_SECRET_FILE.replacen("{}", &file.display().to_string(), 1).replacen("{}", &e.to_string(), 1))?,
+        None => std::env::var(SECRET_VARIABLE).unwrap_or_default().into_bytes(),
+    };
+    let secret = secret.trim_ascii();
+    if secret.is_empty() {
+        return Err(ERR_REMOTE_SECRET.into());
+    }
+    Ok(secret.to_vec())
+}
+
+/// Returns the proof that a side knows the secret, for the nonces of the client and the server.
+fn proof(secret: &[u8], side: &[u8], client_nonce: &[u8], server_nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(side);
+    mac.update(client_nonce);
+    mac.update(server_nonce);
+    mac
+}
+
+/// Checks a proof received from the other side, in constant time.
+fn check_proof(secret: &[u8], side: &[u8], client_nonce: &[u8], server_nonce: &[u8], received: &str) -> bool {
+    STANDARD.decode(received).is_ok_and(|received| proof(secret, side, client_nonce, server_nonce).verify_slice(&received).is_ok())
+}
+
+/// Decodes a nonce of the handshake, which must be exactly `NONCE_SIZE` bytes.
+fn decode_nonce(nonce: &str) -> io::Result<Vec<u8>> {
+    STANDARD.decode(nonce).ok()
+        .filter(|nonce| nonce.len() == NONCE_SIZE)
+        .ok_or_else(|| protocol_error("nonce"))
+}
+
+fn random_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn protocol_error(detail: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ERR_REMOTE_PROTOCOL.replace("{}", &detail.to_string()))
+}
+
+/// A TCP connection sending and receiving frames.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Connection { reader: BufReader::new(stream.try_clone()?), writer: BufWriter::new(stream) })
+    }
+
+    fn write_frame(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        self.writer.write_all(&[kind])?;
+        self.writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+        self.writer.write_all(payload)
+    }
+
+    fn read_frame(&mut self, max: usize) -> io::Result<(u8, Vec<u8>)> {
+        let mut header = [0; 5];
+        self.reader.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > max {
+            return Err(protocol_error(ERR_FRAME_TOO_LARGE.replace("{}", &len.to_string())));
+        }
+        let mut payload = vec![0; len];
+        self.reader.read_exact(&mut payload)?;
+        Ok((header[0], payload))
+    }
+
+    /// Sends a message and flushes the connection.
+    fn send(&mut self, message: &impl Serialize) -> io::Result<()> {
+        self.write_frame(MESSAGE_FRAME, &serde_json::to_vec(message)?)?;
+        self.writer.flush()
+    }
+
+    fn receive<T: DeserializeOwned>(&mut self, max: usize) -> io::Result<T> {
+        match self.read_frame(max)? {
+            (MESSAGE_FRAME, payload) => serde_json::from_slice(&payload).map_err(protocol_error),
+            (kind, _) => Err(protocol_error(format!("frame kind {}", kind))),
+        }
+    }
+
+    /// Sends a data frame without flushing. An empty frame ends the data.
+    fn send_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(DATA_FRAME, data)
+    }
+
+    fn receive_data(&mut self) -> io::Result<Vec<u8>> {
+        match self.read_frame(MAX_FRAME_SIZE)? {
+            (DATA_FRAME, payload) => Ok(payload),
+            (kind, _) => Err(protocol_error(format!("frame kind {}", kind))),
+        }
+    }
+
+    /// Returns `true` if an idle connection was closed by the server, or received data it should not have.
+    fn is_closed(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+        let stream = self.reader.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        // 受信するものが無ければ開いている。切断されていれば 0 バイトが読める
+        let pending = stream.peek(&mut [0]);
+        let open = matches!(pending, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+        stream.set_nonblocking(false).is_err() || !open
+    }
+}
+
+/// Returns the result of a response, turning an error response into an `io::Error`.
+fn check(response: Response) -> io::Result<Response> {
+    match response {
+        Response::Error { message } => Err(io::Error::other(message)),
+        response => Ok(response),
+    }
+}
+
+/// Storage in a folder served by another machine running the `serve` command.
+///
+/// Idle connections are kept and reused; a new connection is opened when a file is being read or written
+/// while another request is made.
+pub struct RemoteStorage {
+    url: String,
+    address: String,
+    /// Folder below the folder served, as a `/`-separated path without leading or trailing `/`.
+    root: String,
+    secret: Vec<u8>,
+    connections: Mutex<Vec<Connection>>,
+}
+
+impl RemoteStorage {
+    /// Connects to the server of a `remote://host[:port]/path` URL.
+    ///
+    /// # Arguments
+    ///
+    /// * `location` - The `remote://` URL of the destination.
+    /// * `secret_file` - The file holding the shared secret, or `None` to use the `FOLDER_SYNC_SECRET` environment variable.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the URL is invalid or the secret cannot be read.
+    /// * Returns an error if the server cannot be reached, or the secrets do not match.
+    pub fn connect(location: &str, secret_file: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let url = RemoteUrl::parse(location)?;
+        let storage = RemoteStorage {
+            url: location.trim_end_matches('/').to_string(),
+            address: format!("{}:{}", url.host, url.port.unwrap_or(DEFAULT_PORT)),
+            root: url.path.trim_matches('/').to_string(),
+            secret: read_secret(secret_file)?,
+            connections: Mutex::new(vec![]),
+        };
+        let connection = storage.open_connection()?;
+        info!("Connected to {}", storage.address);
+        storage.release(connection);
+        Ok(storage)
+    }
+
+    /// Opens a connection and authenticates to the server.
+    fn open_connection(&self) -> io::Result<Connection> {
+        let address = self.address.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, ERR_INVALID_URL.replace("{}", &self.url)))?;
+        let mut connection = Connection::new(TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?)?;
+
+        let client_nonce = random_nonce();
+        connection.send(&Request::Hello { version: PROTOCOL_VERSION, nonce: STANDARD.encode(client_nonce) })?;
+        let (server_nonce, server_proof) = match check(connection.receive(HANDSHAKE_FRAME_SIZE)?)? {
+            Response::Challenge { version: PROTOCOL_VERSION, nonce, proof } => (decode_nonce(&nonce)?, proof),
+            Response::Challenge { version, .. } => {
+                return Err(io::Error::other(ERR_REMOTE_VERSION.replace("{}", &version.to_string())));
+            }
+            _ => return Err(protocol_error("handshake")),
+        };
+        // 先にサーバーが共有シークレットを知っていることを確かめる
+        if !check_proof(&self.secret, b"server", &client_nonce, &server_nonce, &server_proof) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, ERR_REMOTE_AUTH.replace("{}", &self.address)));
+        }
+        let client_proof = proof(&self.secret, b"client", &client_nonce, &server_nonce).finalize().into_bytes();
+        connection.send(&Request::Auth { proof: STANDARD.encode(client_proof) })?;
+        check(connection.receive(HANDSHAKE_FRAME_SIZE)?)?;
+        Ok(connection)
+    }
+
+    /// Returns an idle connection that is still open and `true`, or opens a new one and returns `false`.
+    fn connection(&self) -> io::Result<(Connection, bool)> {
+        loop {
+            let idle = self.connections.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            match idle {
+                Some(connection) if connection.is_closed() => info!("Connection to {} was closed", self.address),
+                Some(connection) => return Ok((connection, true)),
+                None => return Ok((self.open_connection()?, false)),
+            }
+        }
+    }
+
+    /// Keeps a connection for the next request.
+    fn release(&self, connection: Connection) {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).push(connection);
+    }
+
+    /// Sends a request and returns the connection and the response. A connection that failed is closed.
+    ///
+    /// The server closes connections left idle. Kept connections it closed are skipped, and a request failing
+    /// on a kept connection anyway is sent again on another one if it changes nothing on the server:
+    /// other requests may have been carried out before the connection failed.
+    fn exchange(&self, request: &Request) -> io::Result<(Connection, Response)> {
+        loop {
+            let (mut connection, reused) = self.connection()?;
+            match connection.send(request).and_then(|_| connection.receive(MAX_FRAME_SIZE)) {
+                Ok(response) => return Ok((connection, response)),
+                Err(e) if reused && request.is_idempotent() => info!("Reconnecting to {} : {}", self.address, e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Sends a request and returns its response.
+    fn request(&self, request: &Request) -> io::Result<Response> {
+        let (connection, response) = self.exchange(request)?;
+        self.release(connection);
+        check(response)
+    }
+
+    /// Sends a request followed by a transfer, and returns the connection once the server accepted it.
+    fn start(&self, request: &Request) -> io::Result<Connection> {
+        let (connection, response) = self.exchange(request)?;
+        match response {
+            Response::Error { message } => {
+                self.release(connection);
+                Err(io::Error::other(message))
+            }
+            _ => Ok(connection),
+        }
+    }
+
+    /// Returns the path sent to the server for a relative path.
+    fn key(&self, path: &Path) -> String {
+        match (self.root.is_empty(), storage_key(path)) {
+            (true, key) => key,
+            (false, key) if key.is_empty() => self.root.clone(),
+            (false, key) => format!("{}/{}", self.root, key),
+        }
+    }
+}
+
+/// File being read from the server.
+struct RemoteReader<'a> {
+    storage: &'a RemoteStorage,
+    /// `None` once the whole file is read.
+    connection: Option<Connection>,
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl Read for RemoteReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.data.len() {
+            let Some(connection) = self.connection.as_mut() else {
+                return Ok(0);
+            };
+            self.data = connection.receive_data()?;
+            self.position = 0;
+            if self.data.is_empty() {
+                if let Some(connection) = self.connection.take() {
+                    self.storage.release(connection);
+                }
+            }
+        }
+        let read = buf.len().min(self.data.len() - self.position);
+        buf[..read].copy_from_slice(&self.data[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// File being written to the server. Dropping it without calling `finish` closes the connection,
+/// and the server discards the partial file.
+struct RemoteWriter<'a> {
+    storage: &'a RemoteStorage,
+    connection: Option<Connection>,
+    buffer: Vec<u8>,
+}
+
+impl RemoteWriter<'_> {
+    fn connection(&mut self) -> io::Result<&mut Connection> {
+        self.connection.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for RemoteWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len().min(DATA_FRAME_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..written]);
+        if self.buffer.len() == DATA_FRAME_SIZE {
+            let data = std::mem::take(&mut self.buffer);
+            self.connection()?.send_data(&data)?;
+            self.buffer = data;
+            self.buffer.clear();
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for RemoteWriter<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let mut connection = self.connection.take().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        if !self.buffer.is_empty() {
+            connection.send_data(&self.buffer)?;
+        }
+        connection.send_data(&[])?;
+        connection.writer.flush()?;
+        let response = connection.receive(MAX_FRAME_SIZE)?;
+        self.storage.release(connection);
+        check(response).map(|_| ())
+    }
+}
+
+impl Storage for RemoteStorage {
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        match self.request(&Request::List { path: self.key(path) })? {
+            Response::Entries { entries } => Ok(entries),
+            _ => Err(protocol_error("list")),
+        }
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<StorageMetadata>> {
+        match self.request(&Request::Stat { path: self.key(path) })? {
+            Response::Metadata { metadata } => Ok(metadata),
+            _ => Err(protocol_error("stat")),
+        }
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        let connection = self.start(&Request::Read { path: self.key(path) })?;
+        Ok(Box::new(RemoteReader { storage: self, connection: Some(connection), data: vec![], position: 0 }))
+    }
+
+    fn create_write(&self, path: &Path) -> io::Result<Box<dyn StorageWriter + '_>> {
+        let connection = self.start(&Request::Write { path: self.key(path) })?;
+        Ok(Box::new(RemoteWriter { storage: self, connection: Some(connection), buffer: Vec::with_capacity(DATA_FRAME_SIZE) }))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.request(&Request::CreateDir { path: self.key(path) }).map(|_| ())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.request(&Request::Rename { from: self.key(from), to: self.key(to) }).map(|_| ())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.request(&Request::Delete { path: self.key(path) }).map(|_| ())
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.request(&Request::SetTimes { path: self.key(path), modified }).map(|_| ())
+    }
+
+    fn put_delta(&self, source: &Path, path: &Path) -> io::Result<Option<DeltaReport>> {
+        let key = self.key(path);
+        let (block_size, hashes) = match self.request(&Request::Signature { path: key.clone() })? {
+            Response::Signature { block_size, hashes } => (block_size, hashes),
+            _ => return Err(protocol_error("signature")),
+        };
+        if block_size != BLOCK_SIZE {
+            return Ok(None);
+        }
+
+        let mut source_file = File::open(source)?;
+        let len = source_file.metadata()?.len();
+        let mut connection = self.start(&Request::Patch { path: key, len })?;
+
+        // ハッシュが異なるブロックだけを、位置を付けて送る
+        let mut hasher = Sha256::new();
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut frame = Vec::with_capacity(8 + BLOCK_SIZE);
+        let mut report = DeltaReport::default();
+        let mut offset: u64 = 0;
+        loop {
+            let read = read_block(&mut source_file, &mut block)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&block[..read]);
+            let index = report.blocks as usize;
+            if hashes.get(index).is_none_or(|hash| *hash != to_hex(&Sha256::digest(&block[..read]))) {
+                frame.clear();
+                frame.extend_from_slice(&offset.to_be_bytes());
+                frame.extend_from_slice(&block[..read]);
+                connection.send_data(&frame)?;
+                report.changed_blocks += 1;
+                report.bytes_written += read as u64;
+            }
+            report.blocks += 1;
+            offset += read as u64;
+            if read < BLOCK_SIZE {
+                break;
+            }
+        }
+        connection.send_data(&[])?;
+        connection.writer.flush()?;
+        let response = check(connection.receive(MAX_FRAME_SIZE)?)?;
+        self.release(connection);
+
+        match response {
+            Response::Hash { hash } if hash == to_hex(&hasher.finalize()) => Ok(Some(report)),
+            Response::Hash { .. } => Err(io::Error::new(io::ErrorKind::InvalidData, ERR_DELTA_CHECKSUM.replace("{}", &self.display_path(path)))),
+            _ => Err(protocol_error("patch")),
+        }
+    }
+
+    fn hash(&self, path: &Path) -> io::Result<String> {
+        match self.request(&Request::Hash { path: self.key(path) })? {
+            Response::Hash { hash } => Ok(hash),
+            _ => Err(protocol_error("hash")),
+        }
+    }
+}
+
+/// Serves a folder to the `remote://` destinations of other machines until the program is stopped.
+///
+/// # Arguments
+///
+/// * `args` - A reference to the `ServeArgs` given on the command line.
+///
+/// # Errors
+///
+/// * Returns an error if the folder does not exist or the secret cannot be read.
+/// * Returns an error if the address cannot be listened on.
+pub fn execute_serve(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let root = PathBuf::from(&args.root);
+    if !root.is_dir() {
+        return Err(ERR_SERVE_ROOT.replace("{}", &args.root).into());
+    }
+    let secret = read_secret(args.secret_file.as_deref().map(Path::new))?;
+    let listener = TcpListener::bind(&args.listen)
+        .map_err(|e| ERR_API_LISTEN.replacen("{}", &args.listen, 1).replacen("{}", &e.to_string(), 1))?;
+    println!("{}", MSG_SERVING.replacen("{}", &root.display().to_string(), 1).replacen("{}", &listener.local_addr()?.to_string(), 1));
+    info!("Serving {} on {}", root.display(), args.listen);
+    serve(listener, root, secret);
+    Ok(())
+}
+
+/// Accepts connections on a listener and serves each in its own thread, up to `MAX_CONNECTIONS` at a time.
+fn serve(listener: TcpListener, root: PathBuf, secret: Vec<u8>) {
+    let secret = Arc::new(secret);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection : {}", e);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+        let served = active.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(Arc::clone(&active));
+        if served >= MAX_CONNECTIONS {
+            warn!("Too many connections, closing connection from {}", peer);
+            continue;
+        }
+        let root = root.clone();
+        let secret = Arc::clone(&secret);
+        // 接続ごとにスレッドで処理する
+        thread::spawn(move || {
+            let _slot = slot;
+            match serve_connection(stream, &root, &secret) {
+                Ok(()) => info!("Client disconnected: {}", peer),
+                Err(e) => warn!("Connection from {} closed : {}", peer, e),
+            }
+        });
+    }
+}
+
+/// Counts a connection as served until it is dropped, even if its thread panics.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Authenticates a client, then answers its requests until it disconnects or stays idle for `IDLE_TIMEOUT`.
+fn serve_connection(stream: TcpStream, root: &Path, secret: &[u8]) -> io::Result<()> {
+    // 認証前は短い時間で切断し、接続だけして何も送らないクライアントにスレッドを占有させない
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut connection = Connection::new(stream)?;
+    authenticate_client(&mut connection, secret)?;
+    let stream = connection.writer.get_ref();
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    info!("Client connected: {}", stream.peer_addr()?);
+
+    let storage = LocalStorage::new(root);
+    loop {
+        let request = match connection.receive(MAX_FRAME_SIZE) {
+            Ok(request) => request,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            // 使われていない接続を閉じる。クライアントは次の要求で接続し直す
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        handle_request(&mut connection, root, &storage, request)?;
+    }
+}
+
+/// Runs the server side of the handshake, proving the shared secret and checking the proof of the client.
+fn authenticate_client(connection: &mut Connection, secret: &[u8]) -> io::Result<()> {
+    let client_nonce = match connection.receive(HANDSHAKE_FRAME_SIZE)? {
+        Request::Hello { version: PROTOCOL_VERSION, nonce } => decode_nonce(&nonce)?,
+        Request::Hello { version, .. } => {
+            let message = ERR_REMOTE_VERSION.replace("{}", &version.to_string());
+            connection.send(&Response::Error { message: message.clone() })?;
+            return Err(io::Error::other(message));
+        }
+        _ => return Err(protocol_error("handshake")),
+    };
+    let server_nonce = random_nonce();
+    let server_proof = proof(secret, b"server", &client_nonce, &server_nonce).finalize().into_bytes();
+    connection.send(&Response::Challenge {
+        version: PROTOCOL_VERSION,
+        nonce: STANDARD.encode(server_nonce),
+        proof: STANDARD.encode(server_proof),
+    })?;
+    let authenticated = match connection.receive(HANDSHAKE_FRAME_SIZE)? {
+        Request::Auth { proof } => check_proof(secret, b"client", &client_nonce, &server_nonce, &proof),
+        _ => false,
+    };
+    if !authenticated {
+        let peer = connection.writer.get_ref().peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+        let message = ERR_REMOTE_AUTH.replace("{}", &peer);
+        connection.send(&Response::Error { message: message.clone() })?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+    }
+    connection.send(&Response::Ok)
+}
+
+/// Answers one request. Errors of the request are sent to the client; errors of the connection are returned.
+fn handle_request(connection: &mut Connection, root: &Path, storage: &LocalStorage, request: Request) -> io::Result<()> {
+    let response = match request {
+        Request::List { path } => request_path(&path)
+            .and_then(|path| storage.list(&path))
+            .map(|entries| Response::Entries { entries }),
+        Request::Stat { path } => request_path(&path)
+            .and_then(|path| storage.stat(&path))
+            .map(|metadata| Response::Metadata { metadata }),
+        Request::Hash { path } => request_path(&path)
+            .and_then(|path| storage.hash(&path))
+            .map(|hash| Response::Hash { hash }),
+        Request::CreateDir { path } => request_path(&path)
+            .and_then(|path| storage.create_dir(&path))
+            .map(|_| Response::Ok),
+        Request::Rename { from, to } => request_path(&from)
+            .and_then(|from| storage.rename(&from, &request_path(&to)?))
+            .map(|_| Response::Ok),
+        Request::Delete { path } => request_path(&path)
+            .and_then(|path| storage.delete(&path))
+            .map(|_| Response::Ok),
+        Request::SetTimes { path, modified } => request_path(&path)
+            .and_then(|path| storage.set_times(&path, modified))
+            .map(|_| Response::Ok),
+        Request::Signature { path } => request_path(&path)
+            .and_then(|path| block_hashes(BufReader::new(File::open(root.join(path))?)))
+            .map(|hashes| Response::Signature { block_size: BLOCK_SIZE, hashes }),
+        Request::Read { path } => return send_file(connection, storage, &path),
+        Request::Write { path } => return receive_file(connection, storage, &path),
+        Request::Patch { path, len } => return patch_file(connection, root, &path, len),
+        Request::Hello { .. } | Request::Auth { .. } => Err(protocol_error("handshake")),
+    };
+    connection.send(&response.unwrap_or_else(|e| Response::Error { message: e.to_string() }))
+}
+
+/// Sends the contents of a file to the client.
+fn send_file(connection: &mut Connection, storage: &LocalStorage, path: &str) -> io::Result<()> {
+    let mut file = match request_path(path).and_then(|path| storage.open_read(&path)) {
+        Ok(file) => file,
+        Err(e) => return connection.send(&Response::Error { message: e.to_string() }),
+    };
+    connection.send(&Response::Ok)?;
+    // 送信中に読み込みに失敗した場合は、途中のファイルを使わせないよう接続を閉じる
+    let mut data = vec![0; DATA_FRAME_SIZE];
+    loop {
+        let read = read_block(&mut file, &mut data)?;
+        connection.send_data(&data[..read])?;
+        if read == 0 {
+            break;
+        }
+    }
+    connection.writer.flush()
+}
+
+/// Receives the contents of a file from the client into a temporary file, which replaces the file once complete.
+fn receive_file(connection: &mut Connection, storage: &LocalStorage, path: &str) -> io::Result<()> {
+    let opened = request_path(path).and_then(|path| {
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(TEMPORARY_SUFFIX);
+        let temporary = PathBuf::from(temporary);
+        Ok((storage.create_write(&temporary)?, temporary, path))
+    });
+    let (mut writer, temporary, path) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return connection.send(&Response::Error { message: e.to_string() }),
+    };
+    connection.send(&Response::Ok)?;
+
+    // 書き込みに失敗しても、クライアントが送るデータは最後まで読み捨ててからエラーを返す
+    let mut result = Ok(());
+    loop {
+        let data = match connection.receive_data() {
+            Ok(data) => data,
+            Err(e) => {
+                drop(writer);
+                let _ = storage.delete(&temporary);
+                return Err(e);
+            }
+        };
+        if data.is_empty() {
+            break;
+        }
+        if result.is_ok() {
+            result = writer.write_all(&data);
+        }
+    }
+    let result = result
+        .and_then(|_| writer.finish())
+        .and_then(|_| storage.rename(&temporary, &path));
+    if result.is_err() {
+        let _ = storage.delete(&temporary);
+    }
+    connection.send(&match result {
+        Ok(()) => Response::Ok,
+        Err(e) => Response::Error { message: e.to_string() },
+    })
+}
+
+/// Writes the changed blocks sent by the client into an existing file, then sends the hash of the updated file.
+fn patch_file(connection: &mut Connection, root: &Path, path: &str, len: u64) -> io::Result<()> {
+    let opened = request_path(path)
+        .and_then(|path| OpenOptions::new().read(true).write(true).open(root.join(path)));
+    let mut file = match opened {
+        Ok(file) => file,
+        Err(e) => return connection.send(&Response::Error { message: e.to_string() }),
+    };
+    connection.send(&Response::Ok)?;
+
+    let mut result = Ok(());
+    loop {
+        let data = connection.receive_data()?;
+        if data.is_empty() {
+            break;
+        }
+        if data.len() < 8 {
+            return Err(protocol_error("patch"));
+        }
+        if result.is_ok() {
+            let offset = u64::from_be_bytes(data[..8].try_into().unwrap_or_default());
+            result = file.seek(SeekFrom::Start(offset)).and_then(|_| file.write_all(&data[8..]));
+        }
+    }
+    let result = result
+        .and_then(|_| file.set_len(len))
+        .and_then(|_| file.sync_all())
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| hash_reader(&mut file));
+    connection.send(&match result {
+        Ok(hash) => Response::Hash { hash },
+        Err(e) => Response::Error { message: e.to_string() },
+    })
+}
+
+/// Converts a path of a request into a relative path, rejecting paths that leave the folder served.
+fn request_path(path: &str) -> io::Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        // Windows のドライブ指定や区切り文字も受け付けない
+        if part == "." || part == ".." || part.contains(['\\', ':']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, ERR_REMOTE_PATH.replace("{}", path)));
+        }
+        relative.push(part);
+    }
+    Ok(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{exercise_storage, write_and_read};
+
+    const SECRET: &[u8] = b"correct secret";
+
+    /// Serves a temporary folder on a free port of 127.0.0.1, and returns the folder and the address.
+    fn start_server() -> (tempfile::TempDir, String) {
+        let root = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let served = root.path().to_path_buf();
+        thread::spawn(move || serve(listener, served, SECRET.to_vec()));
+        (root, address)
+    }
+
+    /// Connects to a server with a secret, written to a temporary file.
+    fn connect(address: &str, path: &str, secret: &[u8]) -> Result<RemoteStorage, Box<dyn std::error::Error>> {
+        let folder = tempfile::tempdir().unwrap();
+        let secret_file = folder.path().join("secret");
+        fs::write(&secret_file, secret).unwrap();
+        RemoteStorage::connect(&format!("remote://{}/{}", address, path), Some(&secret_file))
+    }
+
+    /// Starts a handshake by hand and returns the connection and the challenge of the server.
+    fn hello(address: &str, nonce: &[u8]) -> (Connection, io::Result<Response>) {
+        let mut connection = Connection::new(TcpStream::connect(address).unwrap()).unwrap();
+        connection.send(&Request::Hello { version: PROTOCOL_VERSION, nonce: STANDARD.encode(nonce) }).unwrap();
+        let response = connection.receive(HANDSHAKE_FRAME_SIZE);
+        (connection, response)
+    }
+
+    #[test]
+    fn authenticates_with_shared_secret() {
+        let (_root, address) = start_server();
+        connect(&address, "", SECRET).unwrap();
+
+        // クライアントはサーバーの証明を確かめて接続をやめる
+        let error = connect(&address, "", b"wrong secret").err().unwrap().to_string();
+        assert!(error.contains(&address), "{}", error);
+
+        // サーバーもクライアントの証明が合わなければ拒否する
+        let client_nonce = random_nonce();
+        let (mut connection, response) = hello(&address, &client_nonce);
+        let Ok(Response::Challenge { nonce, proof: server_proof, .. }) = response else {
+            panic!("expected a challenge");
+        };
+        assert!(check_proof(SECRET, b"server", &client_nonce, &decode_nonce(&nonce).unwrap(), &server_proof));
+        let wrong = proof(b"wrong secret", b"client", &client_nonce, &decode_nonce(&nonce).unwrap()).finalize().into_bytes();
+        connection.send(&Request::Auth { proof: STANDARD.encode(wrong) }).unwrap();
+        assert!(matches!(connection.receive(HANDSHAKE_FRAME_SIZE), Ok(Response::Error { .. })));
+        connection.send(&Request::List { path: String::new() }).ok();
+        assert!(connection.receive::<Response>(MAX_FRAME_SIZE).is_err());
+    }
+
+    #[test]
+    fn rejects_nonces_of_wrong_size() {
+        let (_root, address) = start_server();
+        for nonce in [&[][..], &[0; NONCE_SIZE - 1], &[0; NONCE_SIZE + 1]] {
+            let (_connection, response) = hello(&address, nonce);
+            assert!(response.is_err(), "{} bytes", nonce.len());
+        }
+        let (_connection, response) = hello(&address, &[0; NONCE_SIZE]);
+        assert!(matches!(response, Ok(Response::Challenge { .. })));
+    }
+
+    /// Serves connections that authenticate and answer each request without doing anything, and returns the address
+    /// and the types of the requests received. A connection is closed instead of answering when `close` returns `true`
+    /// for the number of the connection and the request, or right after the handshake for the first connection.
+    fn start_closing_server(close: fn(usize, &Request) -> bool) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(vec![]));
+        let requests = Arc::clone(&received);
+        thread::spawn(move || {
+            for (number, stream) in listener.incoming().enumerate() {
+                let mut connection = Connection::new(stream.unwrap()).unwrap();
+                authenticate_client(&mut connection, SECRET).unwrap();
+                // 最初の接続は、クライアントが使わずに残している間に閉じる
+                if number == 0 {
+                    continue;
+                }
+                while let Ok(request) = connection.receive::<Request>(MAX_FRAME_SIZE) {
+                    let kind = serde_json::to_value(&request).unwrap()["type"].as_str().unwrap().to_string();
+                    requests.lock().unwrap().push(kind);
+                    if close(number, &request) {
+                        break;
+                    }
+                    let response = match request {
+                        Request::Stat { .. } => Response::Metadata { metadata: None },
+                        _ => Response::Ok,
+                    };
+                    connection.send(&response).unwrap();
+                }
+            }
+        });
+        (address, received)
+    }
+
+    #[test]
+    fn skips_connections_closed_by_server() {
+        let (address, received) = start_closing_server(|_, _| false);
+        let storage = connect(&address, "", SECRET).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // 閉じられた接続には送らないので、変更する要求も一度だけ届く
+        storage.delete(Path::new("a.txt")).unwrap();
+        assert!(storage.stat(Path::new("a.txt")).unwrap().is_none());
+        assert_eq!(*received.lock().unwrap(), ["delete", "stat"]);
+    }
+
+    #[test]
+    fn resends_only_idempotent_requests() {
+        // 2 番目の接続は stat 以外の要求を受け取ると、応答せずに閉じる。失敗した create_dir は送り直さない
+        let (address, received) = start_closing_server(|number, request| number == 1 && !matches!(request, Request::Stat { .. }));
+        let storage = connect(&address, "", SECRET).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(storage.stat(Path::new("a")).unwrap().is_none());
+        storage.create_dir(Path::new("a")).unwrap_err();
+        assert_eq!(*received.lock().unwrap(), ["stat", "create_dir"]);
+
+        // 閉じられた接続で失敗した stat は、新しい接続で送り直す
+        let (address, received) = start_closing_server(|number, request| number == 1 && matches!(request, Request::Stat { .. }));
+        let storage = connect(&address, "", SECRET).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        storage.create_dir(Path::new("a")).unwrap();
+        assert!(storage.stat(Path::new("a")).unwrap().is_none());
+        storage.rename(Path::new("a"), Path::new("b")).unwrap();
+        assert_eq!(*received.lock().unwrap(), ["create_dir", "stat", "stat", "rename"]);
+    }
+
+    #[test]
+    fn writes_and_reads_files() {
+        let (root, address) = start_server();
+        fs::create_dir(root.path().join("backup")).unwrap();
+        let storage = connect(&address, "backup", SECRET).unwrap();
+        let contents: Vec<u8> = (0..DATA_FRAME_SIZE * 2 + 5).map(|i| (i % 251) as u8).collect();
+        storage.create_dir(Path::new("a")).unwrap();
+        assert_eq!(write_and_read(&storage, Path::new("a/b.bin"), &contents), contents);
+        assert_eq!(fs::read(root.path().join("backup/a/b.bin")).unwrap(), contents);
+        assert!(!root.path().join("backup/a/b.bin.folder-sync-tmp").exists());
+
+        exercise_storage(&storage);
+    }
+
+    #[test]
+    fn patches_changed_blocks() {
+        let (root, address) = start_server();
+        let storage = connect(&address, "", SECRET).unwrap();
+        let local = tempfile::tempdir().unwrap();
+        let source = local.path().join("source.bin");
+
+        let mut contents: Vec<u8> = (0..BLOCK_SIZE * 3 + 100).map(|i| (i % 253) as u8).collect();
+        fs::write(root.path().join("file.bin"), &contents).unwrap();
+        contents[BLOCK_SIZE + 10] ^= 0xff;
+        contents.truncate(BLOCK_SIZE * 2 + 50);
+        fs::write(&source, &contents).unwrap();
+
+        let report = storage.put_delta(&source, Path::new("file.bin")).unwrap().expect("delta update");
+        assert_eq!((report.blocks, report.changed_blocks), (3, 2));
+        assert_eq!(fs::read(root.path().join("file.bin")).unwrap(), contents);
+        assert_eq!(storage.hash(Path::new("file.bin")).unwrap(), to_hex(&Sha256::digest(&contents)));
+    }
+
+    #[test]
+    fn rejects_paths_leaving_served_folder() {
+        assert_eq!(request_path("a/b//c.txt").unwrap(), Path::new("a").join("b").join("c.txt"));
+        assert_eq!(request_path("").unwrap(), PathBuf::new());
+        for path in ["..", "a/../../etc", "./a", "C:/Windows", "a\\..\\b", "\\\\server\\share", "a/b:stream"] {
+            let error = request_path(path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,1079 @@
+//! # Repository Module
+//!
+//! This module writes backups into a deduplicating repository instead of a folder tree.
+//! A destination is a repository when it starts with `repo://`, followed by the path of a local folder
+//! (`repo://D:\backup\repo` or `repo:///mnt/ssd/repo`). Several jobs can share one repository.
+//!
+//! Files are split into content-defined chunks (FastCDC), so inserting data into a file only changes the chunks around the insertion.
+//! Each chunk is compressed with Zstandard and stored once under its SHA-256 hash, so identical data across files, jobs and runs
+//! is stored once. Each run writes a snapshot listing the files of the job and their chunks:
+//!
+//! ```text
+//! repository.json                              chunking parameters
+//! chunks/<2 first hex digits>/<sha256>         compressed chunks
+//! snapshots/<job>/<UTC time>.json              snapshots, for example 20250131T093000.000Z.json
+//! locks/                                       lock files of running backups and of gc
+//! ```
+//!
+//! Files with the size and modification time recorded in the previous snapshot of the job are not read again.
+//! Deleting snapshots does not delete chunks; the `gc` command removes old snapshots and the chunks no snapshot refers to.
+
+use crate::commands::GcArgs;
+use crate::config::{BtsConfig, BtsConfigWrapper, TimeSpec};
+use crate::filter::FileFilter;
+use crate::hash::to_hex;
+use crate::messages::*;
+use crate::storage::{storage_key, Storage, StorageEntry, StorageMetadata, StorageWriter};
+use crate::sync::SyncObserver;
+use crate::utils::{CopyOptions, CopyOutcome};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use fastcdc::v2020::StreamCDC;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Prefix of repository destinations.
+const REPOSITORY_SCHEME: &str = "repo://";
+
+/// File holding the chunking parameters of a repository, which must not change once chunks are stored.
+const CONFIG_FILE: &str = "repository.json";
+
+/// Folder of the chunks.
+const CHUNKS_DIR: &str = "chunks";
+
+/// Folder of the snapshots, with one subfolder per job.
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Folder of the lock files.
+const LOCKS_DIR: &str = "locks";
+
+/// Lock file of a running gc.
+const GC_LOCK: &str = "gc.lock";
+
+/// Suffix of files being written.
+const TEMPORARY_SUFFIX: &str = ".folder-sync-tmp";
+
+/// Format of the names of snapshot files (UTC).
+const SNAPSHOT_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// Compression level of the chunks (the default level of the `zstd` command).
+const ZSTD_LEVEL: i32 = 3;
+
+/// Number of temporary files and locks created by this process, to give each a unique name.
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the folder of a repository destination, or `None` if the destination is not a repository.
+pub fn repository_path(location: &str) -> Option<PathBuf> {
+    location.strip_prefix(REPOSITORY_SCHEME).map(PathBuf::from)
+}
+
+/// Returns the name under which the snapshots of a job are stored: its name, or the name of its source folder.
+pub fn snapshot_job(config: &BtsConfig) -> String {
+    let name = config.name.clone().unwrap_or_else(|| {
+        Path::new(&config.source).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    });
+    // Windows のファイル名に使えない文字は置き換える
+    let name: String = name.chars().map(|c| if r#"\/:*?"<>|"#.contains(c) { '_' } else { c }).collect();
+    if name.is_empty() { "default".to_string() } else { name }
+}
+
+/// Checks that no two jobs store their snapshots under the same name in the same repository.
+///
+/// Jobs without a `name` are named after their source folder, so the jobs of `C:\work` and `D:\work` would otherwise
+/// share their snapshots, and `gc --keep` would remove the snapshots of one job as old snapshots of the other.
+///
+/// # Errors
+///
+/// * Returns an error naming both jobs if two jobs share a snapshot name in a repository.
+pub fn check_snapshot_jobs(configs: &[BtsConfig]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut seen: HashMap<(PathBuf, String), &BtsConfig> = HashMap::new();
+    for config in configs {
+        let Some(root) = repository_path(&config.destination) else {
+            continue;
+        };
+        let job = snapshot_job(config);
+        if let Some(other) = seen.insert((root.clone(), job.clone()), config) {
+            let label = |config: &BtsConfig| config.name.clone().unwrap_or_else(|| config.source.clone());
+            return Err(ERR_SNAPSHOT_JOB_CONFLICT
+                .replacen("{}", &label(other), 1)
+                .replacen("{}", &label(config), 1)
+                .replacen("{}", &job, 1)
+                .replacen("{}", &root.display().to_string(), 1)
+                .into());
+        }
+    }
+    Ok(())
+}
+
+/// Chunking parameters of a repository.
+#[derive(Deserialize, Serialize)]
+struct RepositoryConfig {
+    version: u32,
+    min_chunk_size: u32,
+    avg_chunk_size: u32,
+    max_chunk_size: u32,
+}
+
+impl Default for RepositoryConfig {
+    fn default() -> Self {
+        RepositoryConfig {
+            version: 1,
+            min_chunk_size: 256 * 1024,
+            avg_chunk_size: 1024 * 1024,
+            max_chunk_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// A snapshot of the files of a job.
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Name of the job (see [`snapshot_job`]).
+    pub job: String,
+    /// Source folder of the job.
+    pub source: String,
+    /// Time the snapshot was taken.
+    pub time: SystemTime,
+    /// Files and folders, parents before their contents.
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// A file or folder of a snapshot.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SnapshotEntry {
+    /// `/`-separated path relative to the source folder.
+    pub path: String,
+    /// `true` for a folder.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_dir: bool,
+    /// File size in bytes, `0` for folders.
+    #[serde(default)]
+    pub len: u64,
+    /// Modification time.
+    pub modified: Option<SystemTime>,
+    /// SHA-256 hash of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Hashes of the chunks of the file, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+/// A repository folder.
+pub struct Repository {
+    root: PathBuf,
+    config: RepositoryConfig,
+}
+
+impl Repository {
+    /// Opens an existing repository.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the folder is not a repository.
+    pub fn open(root: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(root.join(CONFIG_FILE))
+            .map_err(|_| ERR_REPOSITORY_NOT_FOUND.replace("{}", &root.display().to_string()))?;
+        Ok(Repository { root: root.to_path_buf(), config: serde_json::from_str(&text)? })
+    }
+
+    /// Opens a repository, creating it if the folder does not exist or is empty.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the folder cannot be created, or is neither empty nor a repository.
+    pub fn create(root: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if root.join(CONFIG_FILE).exists() {
+            return Repository::open(root);
+        }
+        // 同じリポジトリを使う別のジョブが作成中のフォルダは許可する
+        let foreign = |entry: io::Result<fs::DirEntry>| entry.is_ok_and(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            ![CHUNKS_DIR, SNAPSHOTS_DIR, LOCKS_DIR].contains(&name.as_str()) && !name.starts_with(CONFIG_FILE)
+        });
+        if fs::read_dir(root).is_ok_and(|mut entries| entries.any(foreign)) {
+            return Err(ERR_REPOSITORY_NOT_FOUND.replace("{}", &root.display().to_string()).into());
+        }
+        for folder in [CHUNKS_DIR, SNAPSHOTS_DIR, LOCKS_DIR] {
+            fs::create_dir_all(root.join(folder))?;
+        }
+        let config = RepositoryConfig::default();
+        write_atomically(&root.join(CONFIG_FILE), serde_json::to_string_pretty(&config)?.as_bytes())?;
+        info!("Created repository: {}", root.display());
+        Ok(Repository { root: root.to_path_buf(), config })
+    }
+
+    /// Returns the description of the repository for messages.
+    pub fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn chunk_path(&self, id: &str) -> PathBuf {
+        self.root.join(CHUNKS_DIR).join(&id[..2]).join(id)
+    }
+
+    fn has_chunk(&self, id: &str) -> bool {
+        self.chunk_path(id).is_file()
+    }
+
+    /// Stores a chunk unless it is already stored. Returns `true` if the chunk was written.
+    fn store_chunk(&self, id: &str, data: &[u8]) -> io::Result<bool> {
+        let path = self.chunk_path(id);
+        if path.is_file() {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomically(&path, &zstd::encode_all(data, ZSTD_LEVEL)?)?;
+        Ok(true)
+    }
+
+    /// Reads a chunk and checks it against its hash.
+    fn read_chunk(&self, id: &str) -> io::Result<Vec<u8>> {
+        let corrupted = || io::Error::new(io::ErrorKind::InvalidData, ERR_CHUNK_CORRUPTED.replace("{}", id));
+        let data = File::open(self.chunk_path(id))
+            .and_then(zstd::decode_all)
+            .map_err(|_| corrupted())?;
+        if to_hex(&Sha256::digest(&data)) != id {
+            return Err(corrupted());
+        }
+        Ok(data)
+    }
+
+    /// Returns the snapshot files of a job, oldest first, with the times they were taken.
+    fn snapshots(&self, job: &str) -> io::Result<Vec<(PathBuf, SystemTime)>> {
+        let folder = self.root.join(SNAPSHOTS_DIR).join(job);
+        let mut snapshots = vec![];
+        let Ok(entries) = fs::read_dir(&folder) else {
+            return Ok(snapshots);
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let time = path.file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".json"))
+                .and_then(|name| NaiveDateTime::parse_from_str(name, SNAPSHOT_TIME_FORMAT).ok());
+            if let Some(time) = time {
+                snapshots.push((path, time.and_utc().into()));
+            }
+        }
+        snapshots.sort_by_key(|(_, time)| *time);
+        Ok(snapshots)
+    }
+
+    /// Returns the names of the jobs with snapshots.
+    fn jobs(&self) -> io::Result<Vec<String>> {
+        let mut jobs = vec![];
+        for entry in fs::read_dir(self.root.join(SNAPSHOTS_DIR))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                jobs.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn read_snapshot(path: &Path) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Creates a lock file recording this process, removed when the returned guard is dropped.
+    ///
+    /// A lock file left by a process of this machine that no longer runs is replaced.
+    fn lock(&self, name: &str) -> Result<RepositoryLock, Box<dyn std::error::Error>> {
+        let folder = self.root.join(LOCKS_DIR);
+        fs::create_dir_all(&folder)?;
+        let path = folder.join(name);
+        let owner = serde_json::to_vec(&LockOwner::current())?;
+        let mut retried = false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let lock = RepositoryLock { path };
+                    file.write_all(&owner)?;
+                    return Ok(lock);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && !retried && remove_stale_lock(&path) => retried = true,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(locked_error(&folder).into()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Returns `true` if a lock file other than `own` exists, removing the lock files of stopped processes.
+    fn is_locked_by_other(&self, own: &RepositoryLock, name: Option<&str>) -> io::Result<bool> {
+        for entry in fs::read_dir(self.root.join(LOCKS_DIR))? {
+            let path = entry?.path();
+            if path != own.path && name.is_none_or(|name| path.file_name().is_some_and(|file| file == name)) && !remove_stale_lock(&path) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Process holding a lock, recorded in the lock file.
+#[derive(Deserialize, Serialize)]
+struct LockOwner {
+    host: String,
+    pid: u32,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner { host: gethostname::gethostname().to_string_lossy().into_owned(), pid: std::process::id() }
+    }
+}
+
+/// Removes a lock file if it was left by a process of this machine that no longer runs. Returns `true` if it was removed.
+///
+/// Lock files of other machines, and lock files without an owner (being created, or written by an older version), are kept.
+fn remove_stale_lock(path: &Path) -> bool {
+    let owner = fs::read(path).ok().and_then(|data| serde_json::from_slice::<LockOwner>(&data).ok());
+    let Some(owner) = owner.filter(|owner| owner.host == LockOwner::current().host && !process_running(owner.pid)) else {
+        return false;
+    };
+    warn!("Removing lock file of stopped process {} : {}", owner.pid, path.display());
+    fs::remove_file(path).is_ok()
+}
+
+/// Returns `true` if a process of this machine with the given ID is running.
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // シグナル 0 は送らずに存在だけを確かめる。権限が無い場合も存在している
+    pid > 0 && (unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+/// Returns `true` if a process of this machine with the given ID is running.
+#[cfg(windows)]
+fn process_running(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION};
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            // 権限が無くて開けないプロセスは存在している
+            return io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED as i32);
+        }
+        let mut code = 0;
+        let queried = GetExitCodeProcess(handle, &mut code);
+        CloseHandle(handle);
+        queried == 0 || code == STILL_ACTIVE as u32
+    }
+}
+
+/// Returns `true`, as the processes of other systems cannot be checked.
+#[cfg(not(any(unix, windows)))]
+fn process_running(_pid: u32) -> bool {
+    true
+}
+
+/// A lock file of a repository, removed when dropped.
+struct RepositoryLock {
+    path: PathBuf,
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove lock file {} : {}", self.path.display(), e);
+        }
+    }
+}
+
+fn locked_error(locks: &Path) -> String {
+    ERR_REPOSITORY_LOCKED.replace("{}", &locks.display().to_string())
+}
+
+/// Writes a file through a temporary file, so an interrupted write does not leave a partial file.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temporary = PathBuf::from(format!(
+        "{}.{}-{}{}",
+        path.display(),
+        std::process::id(),
+        TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed),
+        TEMPORARY_SUFFIX
+    ));
+    let result = fs::write(&temporary, data).and_then(|_| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Backs up a source folder into a new snapshot of a repository.
+///
+/// Files are selected by the filter in the same way as when copying them, and each file is reported to the observer:
+/// files unchanged since the previous snapshot of the job as `SkippedUnchanged`, the others as `Copied`.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the source folder.
+/// * `repository` - A reference to the `Path` of the repository folder, created if it does not exist.
+/// * `job` - The name under which the snapshot is stored (see [`snapshot_job`]).
+/// * `options` - A reference to the `CopyOptions` of the backup job.
+/// * `filter` - A reference to the `FileFilter` deciding which files and folders are stored.
+/// * `observer` - The `SyncObserver` notified of each processed file, for example a progress bar.
+///
+/// # Errors
+///
+/// * Returns an error if the folder is not a repository, or gc is running on it.
+/// * Returns an error if reading the source or writing a chunk or the snapshot fails.
+/// * Returns an error if verification is enabled and a stored file does not match its source.
+/// * Returns an error if the backup is cancelled.
+pub fn write_snapshot(
+    source: &Path,
+    repository: &Path,
+    job: &str,
+    options: &CopyOptions,
+    filter: &FileFilter,
+    observer: &dyn SyncObserver,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (repository, _lock) = if options.dry_run {
+        (Repository::open(repository).ok(), None)
+    } else {
+        let repository = Repository::create(repository)?;
+        let lock = repository.lock(&format!(
+            "backup-{}-{}-{}.lock",
+            gethostname::gethostname().to_string_lossy(),
+            std::process::id(),
+            TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)
+        ))?;
+        // gc と同時に実行すると、使用中のチャンクが削除されることがある
+        if repository.is_locked_by_other(&lock, Some(GC_LOCK))? {
+            return Err(locked_error(&repository.root.join(LOCKS_DIR)).into());
+        }
+        (Some(repository), Some(lock))
+    };
+
+    let previous = match &repository {
+        Some(repository) => match repository.snapshots(job)?.last() {
+            Some((path, _)) => Repository::read_snapshot(path)?.entries,
+            None => vec![],
+        },
+        None => vec![],
+    };
+    let mut writer = SnapshotWriter {
+        repository: repository.as_ref().filter(|_| !options.dry_run),
+        previous: previous.into_iter().map(|entry| (entry.path.clone(), entry)).collect(),
+        entries: vec![],
+        new_chunks: 0,
+        new_bytes: 0,
+        reused_chunks: 0,
+    };
+    store_recursive(&mut writer, source, Path::new(""), options, filter, observer)?;
+
+    let Some(repository) = writer.repository else {
+        return Ok(());
+    };
+    let time = SystemTime::now();
+    let snapshot = Snapshot { job: job.to_string(), source: source.display().to_string(), time, entries: writer.entries };
+    let folder = repository.root.join(SNAPSHOTS_DIR).join(job);
+    fs::create_dir_all(&folder)?;
+    let path = folder.join(format!("{}.json", DateTime::<Utc>::from(time).format(SNAPSHOT_TIME_FORMAT)));
+    write_atomically(&path, &serde_json::to_vec(&snapshot)?)?;
+    info!("Wrote snapshot {} : {} new chunks ({} bytes), {} chunks already stored",
+        path.display(), writer.new_chunks, writer.new_bytes, writer.reused_chunks);
+    Ok(())
+}
+
+/// State of a snapshot being written. `repository` is `None` in a dry run.
+struct SnapshotWriter<'a> {
+    repository: Option<&'a Repository>,
+    /// Entries of the previous snapshot of the job, by path.
+    previous: HashMap<String, SnapshotEntry>,
+    entries: Vec<SnapshotEntry>,
+    new_chunks: u64,
+    new_bytes: u64,
+    reused_chunks: u64,
+}
+
+impl SnapshotWriter<'_> {
+    /// Returns the entry of the previous snapshot if the file has not changed since and its chunks are still stored.
+    fn unchanged(&self, key: &str, len: u64, modified: Option<SystemTime>) -> Option<SnapshotEntry> {
+        let entry = self.previous.get(key)?;
+        let unchanged = !entry.is_dir && entry.len == len && entry.modified.is_some() && entry.modified == modified;
+        let stored = self.repository.is_none_or(|repository| entry.chunks.iter().all(|id| repository.has_chunk(id)));
+        (unchanged && stored).then(|| entry.clone())
+    }
+
+    /// Splits a file into chunks and stores the chunks that are not stored yet.
+    ///
+    /// Returns the hash of the file and the hashes of its chunks.
+    fn store_file(&mut self, repository: &Repository, source: &Path) -> io::Result<(String, Vec<String>)> {
+        let config = &repository.config;
+        let chunker = StreamCDC::new(
+            BufReader::new(File::open(source)?),
+            config.min_chunk_size as usize,
+            config.avg_chunk_size as usize,
+            config.max_chunk_size as usize,
+        );
+        let mut hasher = Sha256::new();
+        let mut chunks = vec![];
+        for chunk in chunker {
+            let chunk = chunk?;
+            hasher.update(&chunk.data);
+            let id = to_hex(&Sha256::digest(&chunk.data));
+            if repository.store_chunk(&id, &chunk.data)? {
+                self.new_chunks += 1;
+                self.new_bytes += chunk.length as u64;
+            } else {
+                self.reused_chunks += 1;
+            }
+            chunks.push(id);
+        }
+        Ok((to_hex(&hasher.finalize()), chunks))
+    }
+}
+
+/// Adds a file or folder and everything below it to the snapshot.
+fn store_recursive(
+    writer: &mut SnapshotWriter,
+    source: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    filter: &FileFilter,
+    observer: &dyn SyncObserver,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if options.is_cancelled() {
+        return Err(ERR_CANCELLED.into());
+    }
+    let Ok(metadata) = fs::metadata(source) else {
+        info!("Source path does not exist: {}", source.display());
+        return Ok(());
+    };
+    let key = storage_key(relative);
+
+    if metadata.is_dir() {
+        if filter.skips_dir(source, &metadata) {
+            info!("Skipping excluded path: {}", source.display());
+            return Ok(());
+        }
+        if !key.is_empty() {
+            writer.entries.push(SnapshotEntry {
+                path: key,
+                is_dir: true,
+                len: 0,
+                modified: metadata.modified().ok(),
+                hash: None,
+                chunks: vec![],
+            });
+        }
+        let mut entries = fs::read_dir(source)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            store_recursive(writer, &entry.path(), &relative.join(entry.file_name()), options, filter, observer)?;
+        }
+        return Ok(());
+    }
+
+    if !filter.accepts_file(source, &metadata) {
+        info!("Skipping excluded path: {}", source.display());
+        return Ok(());
+    }
+    if filter.skips_extension(source) {
+        info!("Skipping shortcut file : {}", source.display());
+        observer.file_processed(source, CopyOutcome::SkippedPlaceholder, metadata.len());
+        return Ok(());
+    }
+
+    let modified = metadata.modified().ok();
+    if let Some(entry) = writer.unchanged(&key, metadata.len(), modified) {
+        info!("Skipping unchanged file: {}", source.display());
+        writer.entries.push(entry);
+        observer.file_processed(source, CopyOutcome::SkippedUnchanged, metadata.len());
+        return Ok(());
+    }
+
+    if let Some(repository) = writer.repository {
+        let (hash, chunks) = writer.store_file(repository, source)?;
+        if options.verify {
+            let stored = crate::hash::hash_reader(ChunkReader::new(repository, &chunks))?;
+            if stored != hash {
+                return Err(ERR_VERIFY_FAILED.replace("{}", &source.display().to_string()).into());
+            }
+            info!("Verified: {}", source.display());
+        }
+        info!("Stored: {} ({} chunks)", source.display(), chunks.len());
+        writer.entries.push(SnapshotEntry { path: key, is_dir: false, len: metadata.len(), modified, hash: Some(hash), chunks });
+    }
+    observer.file_processed(source, CopyOutcome::Copied, metadata.len());
+    Ok(())
+}
+
+/// Reader of the contents of a file stored as chunks.
+struct ChunkReader<'a> {
+    repository: &'a Repository,
+    chunks: std::slice::Iter<'a, String>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn new(repository: &'a Repository, chunks: &'a [String]) -> Self {
+        ChunkReader { repository, chunks: chunks.iter(), current: Cursor::new(vec![]) }
+    }
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.next() {
+                Some(id) => self.current = Cursor::new(self.repository.read_chunk(id)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A snapshot opened for reading, seen as a read-only storage so it can be listed, restored and verified like other backups.
+pub struct SnapshotStorage {
+    repository: Repository,
+    /// Path of the snapshot file.
+    path: PathBuf,
+    entries: Vec<SnapshotEntry>,
+    /// Index of each entry by path.
+    by_path: HashMap<String, usize>,
+    /// Indexes of the entries of each folder, by the path of the folder (`""` for the root).
+    children: BTreeMap<String, Vec<usize>>,
+}
+
+impl SnapshotStorage {
+    fn new(repository: Repository, path: PathBuf, snapshot: Snapshot) -> Self {
+        let mut by_path = HashMap::new();
+        let mut children: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (i, entry) in snapshot.entries.iter().enumerate() {
+            by_path.insert(entry.path.clone(), i);
+            let parent = entry.path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or_default();
+            children.entry(parent.to_string()).or_default().push(i);
+        }
+        SnapshotStorage { repository, path, entries: snapshot.entries, by_path, children }
+    }
+
+    fn entry(&self, path: &Path) -> Option<&SnapshotEntry> {
+        self.by_path.get(&storage_key(path)).map(|&i| &self.entries[i])
+    }
+
+    fn read_only(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, ERR_SNAPSHOT_READ_ONLY.replace("{}", &self.describe()))
+    }
+}
+
+/// Converts a snapshot entry into storage metadata.
+fn snapshot_metadata(entry: &SnapshotEntry) -> StorageMetadata {
+    StorageMetadata { is_dir: entry.is_dir, len: entry.len, modified: entry.modified, hash: entry.hash.clone() }
+}
+
+impl Storage for SnapshotStorage {
+    fn describe(&self) -> String {
+        self.path.with_extension("").display().to_string()
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        let key = storage_key(path);
+        if !key.is_empty() && !self.entry(path).is_some_and(|entry| entry.is_dir) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, self.display_path(path)));
+        }
+        Ok(self.children.get(&key).into_iter().flatten()
+            .map(|&i| {
+                let entry = &self.entries[i];
+                StorageEntry {
+                    name: entry.path.rsplit('/').next().unwrap_or_default().to_string(),
+                    metadata: snapshot_metadata(entry),
+                }
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<StorageMetadata>> {
+        if storage_key(path).is_empty() {
+            return Ok(Some(StorageMetadata { is_dir: true, len: 0, modified: None, hash: None }));
+        }
+        Ok(self.entry(path).map(snapshot_metadata))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        match self.entry(path).filter(|entry| !entry.is_dir) {
+            Some(entry) => Ok(Box::new(ChunkReader::new(&self.repository, &entry.chunks))),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, self.display_path(path))),
+        }
+    }
+
+    fn create_write(&self, _path: &Path) -> io::Result<Box<dyn StorageWriter + '_>> {
+        Err(self.read_only())
+    }
+
+    fn create_dir(&self, _path: &Path) -> io::Result<()> {
+        Err(self.read_only())
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(self.read_only())
+    }
+
+    fn delete(&self, _path: &Path) -> io::Result<()> {
+        Err(self.read_only())
+    }
+
+    fn set_times(&self, _path: &Path, _modified: SystemTime) -> io::Result<()> {
+        Err(self.read_only())
+    }
+}
+
+/// Opens the latest snapshot of a job, or the latest one taken at or before a given time.
+///
+/// # Arguments
+///
+/// * `config` - A reference to the `BtsConfig` of the job, whose destination is a repository.
+/// * `at` - A date or time given on the command line. A date alone selects the last snapshot of that day.
+///
+/// # Errors
+///
+/// * Returns an error if the destination is not a repository, or the time is invalid.
+/// * Returns an error if no snapshot matches.
+pub fn open_snapshot(config: &BtsConfig, at: Option<&str>) -> Result<SnapshotStorage, Box<dyn std::error::Error>> {
+    let root = repository_path(&config.destination)
+        .ok_or_else(|| ERR_REPOSITORY_NOT_FOUND.replace("{}", &config.destination))?;
+    let repository = Repository::open(&root)?;
+    let job = snapshot_job(config);
+
+    let limit = at.map(|at| -> Result<SystemTime, String> {
+        let time = TimeSpec::parse_absolute(at)?;
+        let date_only = NaiveDate::parse_from_str(at.trim(), "%Y-%m-%d").is_ok();
+        Ok(if date_only { time + Duration::from_secs(24 * 60 * 60) - Duration::from_nanos(1) } else { time })
+    }).transpose()?;
+    let path = repository.snapshots(&job)?
+        .into_iter()
+        .rev()
+        .find(|(_, time)| limit.is_none_or(|limit| *time <= limit))
+        .map(|(path, _)| path)
+        .ok_or_else(|| ERR_SNAPSHOT_NOT_FOUND.replacen("{}", &job, 1).replacen("{}", &repository.describe(), 1))?;
+    let snapshot = Repository::read_snapshot(&path)?;
+    Ok(SnapshotStorage::new(repository, path, snapshot))
+}
+
+/// Removes old snapshots and the chunks no snapshot refers to from the repositories of the selected jobs.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `args` - A reference to the `GcArgs` given on the command line.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found, or no selected job has a repository destination.
+/// * Returns an error if a repository is in use by a running backup.
+/// * Returns an error if reading snapshots or removing files fails.
+pub fn execute_gc(bts_config_wrapper: &BtsConfigWrapper, args: &GcArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // 同じリポジトリを使うジョブをまとめる
+    let mut repositories: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for config in bts_config_wrapper.select(args.job.as_deref())? {
+        if let Some(root) = repository_path(&config.destination) {
+            repositories.entry(root).or_default().push(snapshot_job(config));
+        }
+    }
+    if repositories.is_empty() {
+        return Err(ERR_NO_REPOSITORIES.into());
+    }
+
+    for (root, jobs) in repositories {
+        println!("{}", MSG_GC.replace("{}", &root.display().to_string()));
+        gc_repository(&Repository::open(&root)?, &jobs, args)?;
+    }
+    Ok(())
+}
+
+/// Runs gc on one repository.
+fn gc_repository(repository: &Repository, jobs: &[String], args: &GcArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let lock = repository.lock(GC_LOCK)?;
+    if repository.is_locked_by_other(&lock, None)? {
+        return Err(locked_error(&repository.root.join(LOCKS_DIR)).into());
+    }
+
+    // 古いスナップショットを削除する
+    let mut removed_snapshots = 0;
+    let mut removed = BTreeSet::new();
+    if let Some(keep) = args.keep {
+        for job in jobs {
+            let snapshots = repository.snapshots(job)?;
+            for (path, _) in &snapshots[..snapshots.len().saturating_sub(keep)] {
+                println!("  REMOVE {}", path.with_extension("").display());
+                if !args.dry_run {
+                    fs::remove_file(path)?;
+                }
+                removed.insert(path.clone());
+                removed_snapshots += 1;
+            }
+        }
+    }
+
+    // 残るスナップショットのどれからも参照されないチャンクを削除する
+    let mut referenced = BTreeSet::new();
+    for job in repository.jobs()? {
+        for (path, _) in repository.snapshots(&job)? {
+            if removed.contains(&path) {
+                continue;
+            }
+            for entry in Repository::read_snapshot(&path)?.entries {
+                referenced.extend(entry.chunks);
+            }
+        }
+    }
+    let (mut removed_chunks, mut freed) = (0, 0);
+    for folder in fs::read_dir(repository.root.join(CHUNKS_DIR))? {
+        let folder = folder?.path();
+        if !folder.is_dir() {
+            continue;
+        }
+        for chunk in fs::read_dir(&folder)? {
+            let chunk = chunk?;
+            let name = chunk.file_name().to_string_lossy().into_owned();
+            // 中断した書き込みの一時ファイルも削除する
+            if referenced.contains(&name) {
+                continue;
+            }
+            freed += chunk.metadata()?.len();
+            removed_chunks += 1;
+            if !args.dry_run {
+                fs::remove_file(chunk.path())?;
+            }
+        }
+    }
+
+    let summary = MSG_GC_SUMMARY
+        .replacen("{}", &removed_snapshots.to_string(), 1)
+        .replacen("{}", &removed_chunks.to_string(), 1)
+        .replacen("{}", &freed.to_string(), 1);
+    println!("  {}", summary);
+    info!("Gc {} : {}", repository.describe(), summary);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::ProgressBar;
+
+    fn job(mut value: serde_json::Value) -> BtsConfig {
+        value["overwrite"] = true.into();
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Returns a job backing up `source` into the repository at `repository`.
+    fn repository_job(source: &Path, repository: &Path) -> BtsConfig {
+        job(serde_json::json!({
+            "source": source.display().to_string(),
+            "destination": format!("{}{}", REPOSITORY_SCHEME, repository.display()),
+        }))
+    }
+
+    /// Takes a snapshot of the job.
+    fn backup(config: &BtsConfig) {
+        // スナップショットの名前はミリ秒単位なので、続けて取ると衝突する
+        std::thread::sleep(Duration::from_millis(10));
+        let filter = FileFilter::new(config, &BtsConfigWrapper::default());
+        let repository = repository_path(&config.destination).unwrap();
+        write_snapshot(Path::new(&config.source), &repository, &snapshot_job(config), &CopyOptions::new(config), &filter, &ProgressBar::hidden())
+            .unwrap();
+    }
+
+    /// Returns the names of the stored chunk files.
+    fn chunk_files(repository: &Path) -> BTreeSet<String> {
+        let mut chunks = BTreeSet::new();
+        for folder in fs::read_dir(repository.join(CHUNKS_DIR)).unwrap() {
+            for chunk in fs::read_dir(folder.unwrap().path()).unwrap() {
+                chunks.insert(chunk.unwrap().file_name().to_string_lossy().into_owned());
+            }
+        }
+        chunks
+    }
+
+    /// Returns data of the given length that does not repeat within a chunk.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        }).collect()
+    }
+
+    /// Returns the ID of a file stored as a single chunk.
+    fn chunk_id(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    /// Returns the process ID of a process that has finished.
+    fn finished_pid() -> u32 {
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[test]
+    fn restores_files_from_snapshot() {
+        let folder = tempfile::tempdir().unwrap();
+        let source = folder.path().join("work");
+        let large = data(3 * 1024 * 1024, 1);
+        fs::create_dir_all(source.join("sub/empty")).unwrap();
+        fs::write(source.join("small.txt"), b"small").unwrap();
+        fs::write(source.join("sub/large.bin"), &large).unwrap();
+        let config = repository_job(&source, &folder.path().join("repository"));
+        backup(&config);
+
+        let snapshot = open_snapshot(&config, None).unwrap();
+        let read = |path: &str| {
+            let mut data = vec![];
+            snapshot.open_read(Path::new(path)).unwrap().read_to_end(&mut data).unwrap();
+            data
+        };
+        assert_eq!(read("small.txt"), b"small");
+        assert!(read("sub/large.bin") == large);
+        assert!(snapshot.stat(Path::new("sub/empty")).unwrap().is_some_and(|metadata| metadata.is_dir));
+        assert!(snapshot.stat(Path::new("missing.txt")).unwrap().is_none());
+    }
+
+    #[test]
+    fn stores_same_chunks_once() {
+        let folder = tempfile::tempdir().unwrap();
+        let (source, repository) = (folder.path().join("work"), folder.path().join("repository"));
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("a.bin"), data(3 * 1024 * 1024, 1)).unwrap();
+        let config = repository_job(&source, &repository);
+        backup(&config);
+        let chunks = chunk_files(&repository);
+        assert!(chunks.len() > 1, "{:?}", chunks);
+
+        // 別の名前の同じ内容のファイルは、チャンクを追加しない
+        fs::copy(source.join("a.bin"), source.join("b.bin")).unwrap();
+        backup(&config);
+        assert_eq!(chunk_files(&repository), chunks);
+        let repo = Repository::open(&repository).unwrap();
+        let snapshots = repo.snapshots("work").unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let entries = Repository::read_snapshot(&snapshots[1].0).unwrap().entries;
+        let chunks_of = |name: &str| entries.iter().find(|entry| entry.path == name).unwrap().chunks.clone();
+        assert_eq!(chunks_of("a.bin"), chunks_of("b.bin"));
+    }
+
+    #[test]
+    fn gc_removes_only_unreferenced_chunks() {
+        let folder = tempfile::tempdir().unwrap();
+        let (source, root) = (folder.path().join("work"), folder.path().join("repository"));
+        let (kept, replaced, changed) = (data(1000, 1), data(1000, 2), data(1000, 3));
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("kept.bin"), &kept).unwrap();
+        fs::write(source.join("changed.bin"), &replaced).unwrap();
+        let config = repository_job(&source, &root);
+        backup(&config);
+        fs::write(source.join("changed.bin"), &changed).unwrap();
+        backup(&config);
+        let all = BTreeSet::from([chunk_id(&kept), chunk_id(&replaced), chunk_id(&changed)]);
+        assert_eq!(chunk_files(&root), all);
+
+        // dry run では何も削除しない
+        let repository = Repository::open(&root).unwrap();
+        gc_repository(&repository, &["work".to_string()], &GcArgs { keep: Some(1), dry_run: true, ..Default::default() }).unwrap();
+        assert_eq!(chunk_files(&root), all);
+        assert_eq!(repository.snapshots("work").unwrap().len(), 2);
+
+        gc_repository(&repository, &["work".to_string()], &GcArgs { keep: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(chunk_files(&root), BTreeSet::from([chunk_id(&kept), chunk_id(&changed)]));
+        assert_eq!(repository.snapshots("work").unwrap().len(), 1);
+        let snapshot = open_snapshot(&config, None).unwrap();
+        let mut read = vec![];
+        snapshot.open_read(Path::new("changed.bin")).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == changed);
+        assert!(fs::read_dir(root.join(LOCKS_DIR)).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn locks_repository() {
+        let folder = tempfile::tempdir().unwrap();
+        let repository = Repository::create(folder.path()).unwrap();
+        let backup = repository.lock("backup-1.lock").unwrap();
+        assert!(!repository.is_locked_by_other(&backup, None).unwrap());
+        let error = repository.lock("backup-1.lock").map(drop).unwrap_err().to_string();
+        assert!(error.contains(LOCKS_DIR), "{}", error);
+
+        let other = repository.lock("backup-2.lock").unwrap();
+        assert!(repository.is_locked_by_other(&backup, None).unwrap());
+        assert!(!repository.is_locked_by_other(&backup, Some(GC_LOCK)).unwrap());
+        assert!(repository.lock(GC_LOCK).is_ok_and(|gc| repository.is_locked_by_other(&gc, None).unwrap()));
+
+        // ガードを破棄するとロックファイルが削除される
+        drop(other);
+        drop(backup);
+        assert!(fs::read_dir(folder.path().join(LOCKS_DIR)).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn replaces_locks_of_stopped_processes() {
+        let folder = tempfile::tempdir().unwrap();
+        let repository = Repository::create(folder.path()).unwrap();
+        let locks = folder.path().join(LOCKS_DIR);
+        let owner = |host: &str, pid: u32| serde_json::to_vec(&LockOwner { host: host.to_string(), pid }).unwrap();
+        let host = LockOwner::current().host;
+
+        // 終了したプロセスのロックは、gc とバックアップのどちらも妨げない
+        fs::write(locks.join(GC_LOCK), owner(&host, finished_pid())).unwrap();
+        fs::write(locks.join("backup-stopped.lock"), owner(&host, finished_pid())).unwrap();
+        let gc = repository.lock(GC_LOCK).unwrap();
+        assert!(!repository.is_locked_by_other(&gc, None).unwrap());
+        assert!(!locks.join("backup-stopped.lock").exists());
+        drop(gc);
+
+        // 実行中のプロセス、別のマシン、所有者の無いロックは残す
+        for (name, contents) in [
+            ("backup-running.lock", owner(&host, std::process::id())),
+            ("backup-other-host.lock", owner("other-host", finished_pid())),
+            ("backup-legacy.lock", vec![]),
+        ] {
+            fs::write(locks.join(name), contents).unwrap();
+            assert!(repository.lock(GC_LOCK).is_ok_and(|gc| repository.is_locked_by_other(&gc, None).unwrap()), "{}", name);
+            assert!(repository.lock(name).is_err(), "{}", name);
+            fs::remove_file(locks.join(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn names_snapshots_after_job_or_source() {
+        assert_eq!(snapshot_job(&job(serde_json::json!({"source": "/home/me/work", "destination": "repo:///backup"}))), "work");
+        assert_eq!(snapshot_job(&job(serde_json::json!({"source": "/home/me/work", "destination": "repo:///backup", "name": "a:b"}))), "a_b");
+        assert_eq!(snapshot_job(&job(serde_json::json!({"source": "/", "destination": "repo:///backup"}))), "default");
+    }
+
+    #[test]
+    fn rejects_jobs_sharing_snapshot_name() {
+        let work = job(serde_json::json!({"source": "/home/me/work", "destination": "repo:///backup"}));
+        let other_work = job(serde_json::json!({"source": "/mnt/data/work", "destination": "repo:///backup"}));
+        let error = check_snapshot_jobs(&[work.clone(), other_work.clone()]).unwrap_err().to_string();
+        assert!(error.contains("/home/me/work") && error.contains("/mnt/data/work"), "{}", error);
+
+        // 別のリポジトリや、名前を付けたジョブは衝突しない
+        let elsewhere = job(serde_json::json!({"source": "/mnt/data/work", "destination": "repo:///other"}));
+        let named = job(serde_json::json!({"source": "/mnt/data/work", "destination": "repo:///backup", "name": "data"}));
+        let folder = job(serde_json::json!({"source": "/mnt/data/work", "destination": "/backup/work"}));
+        check_snapshot_jobs(&[work.clone(), elsewhere, named, folder]).unwrap();
+    }
+}
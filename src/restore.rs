@@ -0,0 +1,330 @@
+//! # Restore Module
+//!
+//! This module copies files from the destination of a backup job back to its source, or to another folder.
+//! A subset of paths can be selected, and for jobs whose destination contains a `{date}` placeholder,
+//! the version written on a given date can be restored. Backups written into an archive file are extracted from it,
+//! encrypted backups are decrypted, and backups in a repository are restored from the latest snapshot or the snapshot of a given date.
+
+use crate::archive::{read_archive, ArchiveFormat};
+use crate::commands::RestoreArgs;
+use crate::config::{BtsConfig, BtsConfigWrapper, TimeSpec};
+use crate::filter::FileFilter;
+use crate::messages::*;
+use crate::paths::{expand_path, has_date_placeholder, TemplateContext};
+use crate::repository::{open_snapshot, repository_path};
+use crate::storage::{open_storage, same_time, walk_storage, LocalStorage, Storage};
+use crate::utils::{copy_file, walk_tree, CopyOptions, CopyOutcome};
+use chrono::{DateTime, Local};
+use log::info;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Restores the selected backup jobs.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `args` - A reference to the `RestoreArgs` given on the command line.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the restore is successful, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found, or `--to` is given for several jobs.
+/// * Returns an error if the requested version or the destination does not exist.
+/// * Returns an error if the backup is encrypted and its key does not match.
+/// * Returns an error if the destination is a repository without a matching snapshot.
+/// * Returns an error if copying a file fails.
+pub fn execute_restore(bts_config_wrapper: &BtsConfigWrapper, args: &RestoreArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = bts_config_wrapper.select(args.job.as_deref())?;
+    if args.to.is_some() && jobs.len() > 1 {
+        return Err(ERR_RESTORE_TARGET_AMBIGUOUS.into());
+    }
+
+    let options = CopyOptions {
+        overwrite: !args.no_overwrite,
+        verify: false,
+        delta_threshold: None,
+        dry_run: args.dry_run,
+        cancel: None,
+    };
+    let subset: Vec<PathBuf> = args.paths.iter().map(|p| normalize(Path::new(p))).collect();
+
+    for config in jobs {
+        let target = PathBuf::from(args.to.as_deref().unwrap_or(&config.source));
+        if repository_path(&config.destination).is_some() {
+            let snapshot = open_snapshot(config, args.at.as_deref())?;
+            println!("{}", MSG_RESTORING.replace("{}", &format!("{} -> {}", snapshot.describe(), target.display())));
+            restore_storage(&snapshot, &target, &subset, &options)?;
+            continue;
+        }
+
+        let backup = backup_location(config, args.at.as_deref())?;
+        println!("{}", MSG_RESTORING.replace("{}", &format!("{} -> {}", backup.display(), target.display())));
+        match ArchiveFormat::of(&backup.to_string_lossy()) {
+            Some(format) => restore_archive(&backup, format, &target, &subset, &options)?,
+            None if config.encryption.is_some() => {
+                restore_storage(&*open_encrypted_backup(config, &backup)?, &target, &subset, &options)?
+            }
+            None => restore_tree(&backup, &target, &subset, &options)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the folder or archive file holding the backup of a job, optionally the version of a given date.
+///
+/// # Errors
+///
+/// * Returns an error if a version is requested but the destination has no `{date}` placeholder.
+/// * Returns an error if the date is invalid or the backup does not exist.
+pub fn backup_location(config: &BtsConfig, at: Option<&str>) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let location = match at {
+        None => PathBuf::from(&config.destination),
+        Some(at) => {
+            if !has_date_placeholder(&config.destination_template) {
+                return Err(ERR_NO_VERSIONS.replace("{}", &config.destination).into());
+            }
+            let time: DateTime<Local> = TimeSpec::parse_absolute(at)?.into();
+            PathBuf::from(expand_path(&config.destination_template, &TemplateContext::at(time))?)
+        }
+    };
+
+    let exists = match ArchiveFormat::of(&location.to_string_lossy()) {
+        Some(_) => location.is_file(),
+        None => location.is_dir(),
+    };
+    if !exists {
+        return Err(ERR_BACKUP_NOT_FOUND.replace("{}", &location.display().to_string()).into());
+    }
+    Ok(location)
+}
+
+/// Copies the files of a backup folder into the target folder.
+///
+/// # Arguments
+///
+/// * `backup` - A reference to the `Path` of the backup folder.
+/// * `target` - A reference to the `Path` of the folder to restore into.
+/// * `subset` - Relative paths to restore; everything is restored when empty.
+/// * `options` - A reference to the `CopyOptions` controlling the copy.
+///
+/// # Errors
+///
+/// * Returns an error if the backup folder cannot be walked.
+/// * Returns an error if creating a folder or copying a file fails.
+fn restore_tree(
+    backup: &Path,
+    target: &Path,
+    subset: &[PathBuf],
+    options: &CopyOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut restored, mut skipped) = (0, 0);
+    let storage = LocalStorage::new(target);
+
+    walk_tree(backup, &FileFilter::all(backup), &mut |relative, metadata| {
+        if !subset.is_empty() && !subset.iter().any(|p| relative.starts_with(p)) {
+            return Ok(());
+        }
+        if metadata.is_dir() {
+            if !options.dry_run {
+                storage.create_dir(relative)?;
+            }
+            return Ok(());
+        }
+
+        if !options.dry_run {
+            if let Some(parent) = relative.parent() {
+                storage.create_dir(parent)?;
+            }
+        }
+        match copy_file(&backup.join(relative), metadata, &storage, relative, options)? {
+            CopyOutcome::Copied => {
+                if options.dry_run {
+                    println!("  RESTORE {}", relative.display());
+                }
+                restored += 1;
+            }
+            CopyOutcome::SkippedExisting | CopyOutcome::SkippedUnchanged | CopyOutcome::SkippedPlaceholder => skipped += 1,
+        }
+        Ok(())
+    })?;
+
+    print_summary(target, restored, skipped);
+    Ok(())
+}
+
+/// Extracts the files of a backup archive into the target folder.
+///
+/// Existing files are handled as by `restore_tree`: they are skipped when overwriting is disabled,
+/// or when they have the size and modification time of the archived file.
+///
+/// # Arguments
+///
+/// * `archive` - A reference to the `Path` of the backup archive.
+/// * `format` - The `ArchiveFormat` of the archive.
+/// * `target` - A reference to the `Path` of the folder to restore into.
+/// * `subset` - Relative paths to restore; everything is restored when empty.
+/// * `options` - A reference to the `CopyOptions` controlling the copy.
+///
+/// # Errors
+///
+/// * Returns an error if the archive cannot be read.
+/// * Returns an error if creating a folder or writing a file fails.
+fn restore_archive(
+    archive: &Path,
+    format: ArchiveFormat,
+    target: &Path,
+    subset: &[PathBuf],
+    options: &CopyOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut restored, mut skipped) = (0, 0);
+    let storage = LocalStorage::new(target);
+
+    read_archive(archive, &mut |entry, reader| {
+        let relative = entry.path.as_path();
+        if !subset.is_empty() && !subset.iter().any(|p| relative.starts_with(p)) {
+            return Ok(());
+        }
+        if entry.is_dir {
+            if !options.dry_run {
+                storage.create_dir(relative)?;
+            }
+            return Ok(());
+        }
+
+        if restore_file(&storage, relative, entry.len, entry.modified, format.time_precision(), reader, options)? {
+            restored += 1;
+        } else {
+            skipped += 1;
+        }
+        Ok(())
+    })?;
+
+    print_summary(target, restored, skipped);
+    Ok(())
+}
+
+/// Copies the files of an encrypted backup or a snapshot into the target folder.
+///
+/// Existing files are handled as by `restore_archive`, with the sizes and modification times recorded in the index of the backup
+/// or in the snapshot.
+///
+/// # Arguments
+///
+/// * `backup` - The `Storage` of the encrypted backup (see [`crate::crypt`]) or the snapshot (see [`crate::repository`]).
+/// * `target` - A reference to the `Path` of the folder to restore into.
+/// * `subset` - Relative paths to restore; everything is restored when empty.
+/// * `options` - A reference to the `CopyOptions` controlling the copy.
+///
+/// # Errors
+///
+/// * Returns an error if the backup cannot be listed, or a file cannot be decrypted or its chunks are corrupted.
+/// * Returns an error if creating a folder or writing a file fails.
+fn restore_storage(
+    backup: &dyn Storage,
+    target: &Path,
+    subset: &[PathBuf],
+    options: &CopyOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut restored, mut skipped) = (0, 0);
+    let storage = LocalStorage::new(target);
+
+    walk_storage(backup, Path::new(""), &mut |relative, metadata| {
+        if !subset.is_empty() && !subset.iter().any(|p| relative.starts_with(p)) {
+            return Ok(());
+        }
+        if metadata.is_dir {
+            if !options.dry_run {
+                storage.create_dir(relative)?;
+            }
+            return Ok(());
+        }
+
+        let mut reader = backup.open_read(relative)?;
+        if restore_file(&storage, relative, metadata.len, metadata.modified, Duration::ZERO, &mut reader, options)? {
+            restored += 1;
+        } else {
+            skipped += 1;
+        }
+        Ok(())
+    })?;
+
+    print_summary(target, restored, skipped);
+    Ok(())
+}
+
+/// Writes a file read from an archive, an encrypted backup or a snapshot into the target folder.
+///
+/// Existing files are skipped when overwriting is disabled, or when they have the size and modification time of the backed up file.
+///
+/// # Returns
+///
+/// Returns `Ok(true)` if the file is restored (or would be restored in a dry run), `Ok(false)` if it is skipped.
+///
+/// # Errors
+///
+/// * Returns an error if reading the file or writing it into the target folder fails.
+fn restore_file(
+    storage: &LocalStorage,
+    relative: &Path,
+    len: u64,
+    modified: Option<SystemTime>,
+    precision: Duration,
+    reader: &mut dyn Read,
+    options: &CopyOptions,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if let Some(existing) = storage.stat(relative)? {
+        let unchanged = existing.len == len
+            && existing.modified.zip(modified).is_some_and(|(a, b)| same_time(a, b, precision));
+        if !options.overwrite || unchanged {
+            info!("Skipping existing file: {}", storage.display_path(relative));
+            return Ok(false);
+        }
+    }
+
+    if options.dry_run {
+        println!("  RESTORE {}", relative.display());
+        return Ok(true);
+    }
+    if let Some(parent) = relative.parent() {
+        storage.create_dir(parent)?;
+    }
+    let mut writer = storage.create_write(relative)?;
+    io::copy(reader, &mut writer)?;
+    writer.finish()?;
+    if let Some(modified) = modified {
+        storage.set_times(relative, modified)?;
+    }
+    info!("Restored: {}", storage.display_path(relative));
+    Ok(true)
+}
+
+/// Opens the storage of an encrypted backup folder, such as a folder returned by `backup_location`.
+///
+/// # Errors
+///
+/// * Returns an error if the key cannot be derived or does not match the backup.
+pub fn open_encrypted_backup(config: &BtsConfig, backup: &Path) -> Result<Box<dyn Storage>, Box<dyn std::error::Error>> {
+    open_storage(&BtsConfig { destination: backup.to_string_lossy().into_owned(), ..config.clone() })
+}
+
+/// Prints and logs the number of restored and skipped files.
+fn print_summary(target: &Path, restored: u64, skipped: u64) {
+    let summary = MSG_RESTORE_SUMMARY
+        .replacen("{}", &restored.to_string(), 1)
+        .replacen("{}", &skipped.to_string(), 1);
+    println!("  {}", summary);
+    info!("Restored {} : {}", target.display(), summary);
+}
+
+/// Normalizes a relative path given on the command line (drops `.`, leading separators and prefixes).
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_) | Component::ParentDir))
+        .collect()
+}
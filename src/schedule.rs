@@ -0,0 +1,264 @@
+//! # Schedule Module
+//!
+//! This module parses the `schedule` of backup jobs run by the daemon (see [`crate::daemon`]).
+//! A schedule is either an interval (`"30m"`, `"6h"`, `"1d"`; units `s`, `m`, `h`, `d`, `w`)
+//! or a five-field cron expression (`"minute hour day-of-month month day-of-week"`, for example `"0 3 * * MON-FRI"`).
+//!
+//! Cron fields accept `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps (`*/15`, `0-30/10`).
+//! Months and days of the week also accept English three-letter names, and Sunday is `0` or `7`.
+//! As in cron, when both the day of the month and the day of the week are restricted (neither field starts with `*`,
+//! so `*/2` is not restricted), a day matching either one is used; otherwise a day must match both.
+
+// cspell:ignore datelike timelike
+
+use crate::config::parse_duration;
+use crate::messages::*;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Month names accepted in cron expressions.
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// Day-of-week names accepted in cron expressions, starting with Sunday (`0`).
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Minutes searched for the next cron occurrence before giving up (about five years, covering 29 February).
+const MAX_SEARCH_MINUTES: u32 = 5 * 366 * 24 * 60;
+
+/// Schedule of a backup job.
+#[derive(Deserialize,Serialize,Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// The value as written in the configuration file.
+    raw : String,
+    /// The parsed value.
+    kind : ScheduleKind,
+}
+
+#[derive(Clone)]
+enum ScheduleKind {
+    Interval(Duration),
+    Cron(CronExpression),
+}
+
+/// Parsed cron expression. Each field is a bit set of the allowed values.
+#[derive(Clone)]
+struct CronExpression {
+    minutes : u64,
+    hours : u64,
+    days : u64,
+    months : u64,
+    weekdays : u64,
+    /// `false` if the day-of-month field starts with `*` (`*` or `*/N`).
+    days_restricted : bool,
+    /// `false` if the day-of-week field starts with `*` (`*` or `*/N`).
+    weekdays_restricted : bool,
+}
+
+impl Schedule {
+    /// Returns the first run time strictly after `last`, or `None` if the cron expression never matches.
+    pub fn next_after(&self, last : DateTime<Local>) -> Option<DateTime<Local>> {
+        match &self.kind {
+            ScheduleKind::Interval(interval) => Some(last + ChronoDuration::from_std(*interval).ok()?),
+            ScheduleKind::Cron(cron) => cron.next_after(last),
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(raw : String) -> Result<Self, Self::Error> {
+        let invalid = || ERR_INVALID_SCHEDULE.replace("{}", &raw);
+
+        let kind = match parse_duration(raw.trim()) {
+            Some(interval) if interval.is_zero() => return Err(invalid()),
+            Some(interval) => ScheduleKind::Interval(interval),
+            None => ScheduleKind::Cron(CronExpression::parse(&raw).ok_or_else(invalid)?),
+        };
+        Ok(Schedule { raw, kind })
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule : Schedule) -> Self {
+        schedule.raw
+    }
+}
+
+impl CronExpression {
+    /// Parses a five-field cron expression.
+    fn parse(text : &str) -> Option<Self> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return None;
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7, &WEEKDAY_NAMES)?;
+        // 7 は日曜日 (0) と同じ
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+
+        Some(CronExpression {
+            minutes : parse_field(minutes, 0, 59, &[])?,
+            hours : parse_field(hours, 0, 23, &[])?,
+            days : parse_field(days, 1, 31, &[])?,
+            months : parse_field(months, 1, 12, &MONTH_NAMES)?,
+            weekdays : weekday_bits,
+            // cron と同様に、* で始まるフィールド (*/2 など) は制限とみなさない
+            days_restricted : !days.starts_with('*'),
+            weekdays_restricted : !weekdays.starts_with('*'),
+        })
+    }
+
+    /// Returns the first matching minute strictly after `last`.
+    fn next_after(&self, last : DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut time = last.naive_local().with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+
+        for _ in 0..MAX_SEARCH_MINUTES {
+            if !has(self.months, time.month()) {
+                // 翌月の1日 0:00 へ
+                let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.matches_day(time) {
+                time = (time.date() + ChronoDuration::days(1)).and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !has(self.hours, time.hour()) {
+                time = time.with_minute(0)? + ChronoDuration::hours(1);
+                continue;
+            }
+            if !has(self.minutes, time.minute()) {
+                time += ChronoDuration::minutes(1);
+                continue;
+            }
+            // 夏時間の切り替えで存在しない時刻は飛ばす
+            match Local.from_local_datetime(&time).earliest() {
+                Some(next) if next > last => return Some(next),
+                _ => time += ChronoDuration::minutes(1),
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if the day of `time` matches the day-of-month and day-of-week fields.
+    fn matches_day(&self, time : NaiveDateTime) -> bool {
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+/// Returns `true` if `value` is in the bit set.
+fn has(bits : u64, value : u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+/// Parses a cron field into a bit set of the allowed values.
+///
+/// `names` are the names of the values starting at `min`.
+fn parse_field(field : &str, min : u32, max : u32, names : &[&str]) -> Option<u64> {
+    let mut bits = 0u64;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&step| step > 0)?),
+            None => (item, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, min, names)?, parse_value(end, min, names)?)
+        } else {
+            let value = parse_value(range, min, names)?;
+            // 10/5 のような指定は 10 から最大値までの範囲として扱う
+            (value, if item.contains('/') { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Some(bits)
+}
+
+/// Parses a single cron value, either a number or a name.
+fn parse_value(text : &str, min : u32, names : &[&str]) -> Option<u32> {
+    if let Ok(value) = text.parse() {
+        return Some(value);
+    }
+    let lower = text.to_ascii_lowercase();
+    names.iter().position(|name| *name == lower).map(|index| index as u32 + min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(text : &str) -> Schedule {
+        Schedule::try_from(text.to_string()).unwrap()
+    }
+
+    fn at(year : i32, month : u32, day : u32, hour : u32, minute : u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn interval_is_added_to_last_run() {
+        assert_eq!(schedule("90m").next_after(at(2025, 1, 6, 23, 0)), Some(at(2025, 1, 7, 0, 30)));
+        assert!(Schedule::try_from("0m".to_string()).is_err());
+    }
+
+    #[test]
+    fn cron_finds_next_minute_strictly_after() {
+        assert_eq!(schedule("0 3 * * *").next_after(at(2025, 1, 6, 2, 59)), Some(at(2025, 1, 6, 3, 0)));
+        assert_eq!(schedule("0 3 * * *").next_after(at(2025, 1, 6, 3, 0)), Some(at(2025, 1, 7, 3, 0)));
+        assert_eq!(schedule("*/15 * * * *").next_after(at(2025, 1, 6, 10, 7)), Some(at(2025, 1, 6, 10, 15)));
+        assert_eq!(schedule("30 9 * dec *").next_after(at(2025, 1, 6, 0, 0)), Some(at(2025, 12, 1, 9, 30)));
+        assert_eq!(schedule("0 0 29 2 *").next_after(at(2025, 3, 1, 0, 0)), Some(at(2028, 2, 29, 0, 0)));
+        assert_eq!(schedule("0 0 31 2 *").next_after(at(2025, 1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn cron_weekdays() {
+        // 2025-01-04 は土曜日
+        assert_eq!(schedule("0 3 * * MON-FRI").next_after(at(2025, 1, 4, 12, 0)), Some(at(2025, 1, 6, 3, 0)));
+        assert_eq!(schedule("0 3 * * 7").next_after(at(2025, 1, 4, 12, 0)), Some(at(2025, 1, 5, 3, 0)));
+    }
+
+    #[test]
+    fn cron_uses_either_day_when_both_are_restricted() {
+        // 15日 または 月曜日
+        let both = schedule("0 0 15 * 1");
+        assert_eq!(both.next_after(at(2025, 1, 7, 0, 0)), Some(at(2025, 1, 13, 0, 0)));
+        assert_eq!(both.next_after(at(2025, 1, 13, 0, 0)), Some(at(2025, 1, 15, 0, 0)));
+    }
+
+    #[test]
+    fn cron_step_from_star_does_not_restrict_day() {
+        // 奇数日 かつ 月曜日 (*/2 は制限とみなさない)
+        let odd_mondays = schedule("0 0 */2 * 1");
+        assert_eq!(odd_mondays.next_after(at(2025, 1, 1, 0, 0)), Some(at(2025, 1, 13, 0, 0)));
+        // 10日 かつ 日・火・木・土曜日
+        let tenth = schedule("0 0 10 * */2");
+        assert_eq!(tenth.next_after(at(2025, 1, 1, 0, 0)), Some(at(2025, 4, 10, 0, 0)));
+    }
+
+    #[test]
+    fn rejects_invalid_cron_expressions() {
+        for text in ["0 3 * *", "60 * * * *", "0 24 * * *", "0 0 0 * *", "0 0 * 13 *", "*/0 * * * *", "5-1 * * * *", "0 0 * * funday"] {
+            assert!(Schedule::try_from(text.to_string()).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! # SFTP Storage Module
+//!
+//! This module implements the [`Storage`] trait over SFTP, for destinations such as `sftp://user@nas/volume1/backup`.
+//!
+//! The host key is checked against `~/.ssh/known_hosts`, so the server must have been connected to once with `ssh`.
+//! Authentication uses the key file of the job's `ssh_key`, or else the SSH agent and the default keys
+//! (`~/.ssh/id_ed25519`, `~/.ssh/id_ecdsa`, `~/.ssh/id_rsa`).
+//!
+//! Files are uploaded under a temporary name and renamed when complete, so an interrupted upload never leaves a partial file.
+//! SFTP stores modification times in whole seconds.
+
+use crate::messages::*;
+use crate::paths::home_dir;
+use crate::storage::{storage_key, RemoteUrl, Storage, StorageEntry, StorageMetadata, StorageWriter};
+use log::info;
+use ssh2::{CheckResult, ErrorCode, FileStat, KnownHostFileKind, RenameFlags, Session, Sftp};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default SSH port.
+const DEFAULT_PORT: u16 = 22;
+
+/// Key files tried when no key is configured, in `~/.ssh`.
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// SFTP status code of a missing file.
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+/// Suffix of files being uploaded.
+const TEMPORARY_SUFFIX: &str = ".folder-sync-tmp";
+
+/// Storage on an SFTP server.
+pub struct SftpStorage {
+    url: String,
+    /// Folder on the server; empty for the login folder.
+    root: String,
+    sftp: Sftp,
+    // セッションは SFTP チャンネルより後に破棄する
+    _session: Session,
+}
+
+impl SftpStorage {
+    /// Connects to the server of an `sftp://[user@]host[:port]/path` URL.
+    ///
+    /// A path starting with `/~/` is relative to the login folder of the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `location` - The `sftp://` URL of the destination.
+    /// * `key` - The private key file to authenticate with, or `None` to use the SSH agent and the default keys.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the URL is invalid or the server cannot be reached.
+    /// * Returns an error if the host key is unknown or does not match `~/.ssh/known_hosts`.
+    /// * Returns an error if authentication fails.
+    pub fn connect(location: &str, key: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let url = RemoteUrl::parse(location)?;
+        let port = url.port.unwrap_or(DEFAULT_PORT);
+        let user = match url.user {
+            Some(user) => user,
+            None => std::env::var("USER").or_else(|_| std::env::var("USERNAME")).map_err(|_| ERR_INVALID_URL.replace("{}", location))?,
+        };
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect((url.host.as_str(), port))?);
+        session.handshake()?;
+        check_host_key(&session, &url.host, port)?;
+        authenticate(&session, &user, key)?;
+        if !session.authenticated() {
+            return Err(ERR_SSH_AUTH.replace("{}", &format!("{}@{}", user, url.host)).into());
+        }
+        info!("Connected to {}@{}:{}", user, url.host, port);
+
+        Ok(SftpStorage {
+            url: location.to_string(),
+            root: root_folder(&url.path),
+            sftp: session.sftp()?,
+            _session: session,
+        })
+    }
+
+    /// Returns the path on the server of a relative path.
+    fn remote_path(&self, path: &Path) -> PathBuf {
+        remote_path(&self.root, path)
+    }
+
+    fn stat_remote(&self, path: &Path) -> io::Result<Option<FileStat>> {
+        match self.sftp.stat(path) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Renames a file, replacing the target if the server refuses to overwrite it.
+    fn rename_remote(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if self.sftp.rename(from, to, Some(flags)).is_ok() {
+            return Ok(());
+        }
+        // SFTP v3 の rename は既存ファイルを上書きしないサーバーがあるため、削除してから名前を変更する
+        if self.stat_remote(to)?.is_some() {
+            self.sftp.unlink(to)?;
+        }
+        Ok(self.sftp.rename(from, to, None)?)
+    }
+
+    fn delete_remote(&self, path: &Path, stat: &FileStat) -> io::Result<()> {
+        if !stat.is_dir() {
+            return Ok(self.sftp.unlink(path)?);
+        }
+        for (child, child_stat) in self.sftp.readdir(path)? {
+            self.delete_remote(&child, &child_stat)?;
+        }
+        Ok(self.sftp.rmdir(path)?)
+    }
+}
+
+/// Returns the folder on the server of the path of an `sftp://` URL.
+///
+/// A path starting with `/~/` is relative to the login folder, which is returned as an empty string.
+fn root_folder(url_path: &str) -> String {
+    let root = match url_path.strip_prefix("/~") {
+        Some(relative) if relative.is_empty() || relative.starts_with('/') => relative.trim_start_matches('/'),
+        _ => url_path,
+    };
+    root.trim_end_matches('/').to_string()
+}
+
+/// Returns the path on the server of a relative path below `root` (empty for the login folder).
+fn remote_path(root: &str, path: &Path) -> PathBuf {
+    let key = storage_key(path);
+    PathBuf::from(match (root.is_empty(), key.is_empty()) {
+        (true, true) => ".".to_string(),
+        (true, false) => key,
+        (false, true) => root.to_string(),
+        (false, false) => format!("{}/{}", root, key),
+    })
+}
+
+/// Checks the host key of the server against `~/.ssh/known_hosts`.
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let (key, _) = session.host_key().ok_or_else(|| ERR_SSH_HOST_UNKNOWN.replace("{}", host))?;
+    let mut known_hosts = session.known_hosts()?;
+    let path = Path::new(&home_dir()?).join(".ssh").join("known_hosts");
+    if path.exists() {
+        known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)?;
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(ERR_SSH_HOST_MISMATCH.replace("{}", host).into()),
+        CheckResult::NotFound | CheckResult::Failure => Err(ERR_SSH_HOST_UNKNOWN.replace("{}", host).into()),
+    }
+}
+
+/// Authenticates with the configured key, or with the SSH agent and the default keys.
+fn authenticate(session: &Session, user: &str, key: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(key) = key {
+        session.userauth_pubkey_file(user, None, key, None)?;
+        return Ok(());
+    }
+
+    if session.userauth_agent(user).is_ok() {
+        return Ok(());
+    }
+    let ssh_dir = Path::new(&home_dir()?).join(".ssh");
+    for name in DEFAULT_KEYS {
+        let key = ssh_dir.join(name);
+        if key.exists() && session.userauth_pubkey_file(user, None, &key, None).is_ok() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Converts SFTP attributes.
+fn sftp_metadata(stat: &FileStat) -> StorageMetadata {
+    StorageMetadata {
+        is_dir: stat.is_dir(),
+        len: if stat.is_dir() { 0 } else { stat.size.unwrap_or(0) },
+        modified: stat.mtime.map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime)),
+        hash: None,
+    }
+}
+
+/// A file being uploaded under a temporary name.
+struct SftpWriter<'a> {
+    storage: &'a SftpStorage,
+    file: ssh2::File,
+    temporary: PathBuf,
+    path: PathBuf,
+}
+
+impl Write for SftpWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl StorageWriter for SftpWriter<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.close()?;
+        self.storage.rename_remote(&self.temporary, &self.path)
+    }
+}
+
+impl Storage for SftpStorage {
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        Ok(self.sftp.readdir(self.remote_path(path))?
+            .into_iter()
+            .filter_map(|(child, stat)| {
+                let name = child.file_name()?.to_string_lossy().into_owned();
+                (name != "." && name != "..").then(|| StorageEntry { name, metadata: sftp_metadata(&stat) })
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<StorageMetadata>> {
+        Ok(self.stat_remote(&self.remote_path(path))?.as_ref().map(sftp_metadata))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        Ok(Box::new(self.sftp.open(self.remote_path(path))?))
+    }
+
+    fn create_write(&self, path: &Path) -> io::Result<Box<dyn StorageWriter + '_>> {
+        let path = self.remote_path(path);
+        let temporary = PathBuf::from(format!("{}{}", path.display(), TEMPORARY_SUFFIX));
+        let file = self.sftp.create(&temporary)?;
+        Ok(Box::new(SftpWriter { storage: self, file, temporary, path }))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        // 存在するフォルダまで遡り、そこから順に作成する
+        let remote = self.remote_path(path);
+        let mut missing = vec![];
+        for ancestor in remote.ancestors().filter(|ancestor| !ancestor.as_os_str().is_empty()) {
+            if self.stat_remote(ancestor)?.is_some() {
+                break;
+            }
+            missing.push(ancestor);
+        }
+        for folder in missing.iter().rev() {
+            self.sftp.mkdir(folder, 0o755)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.rename_remote(&self.remote_path(from), &self.remote_path(to))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let path = self.remote_path(path);
+        let stat = self.stat_remote(&path)?.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.delete_remote(&path, &stat)
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let seconds = modified.duration_since(UNIX_EPOCH).map_err(io::Error::other)?.as_secs();
+        let stat = FileStat { size: None, uid: None, gid: None, perm: None, atime: Some(seconds), mtime: Some(seconds) };
+        Ok(self.sftp.setstat(&self.remote_path(path), stat)?)
+    }
+
+    fn time_precision(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::exercise_storage;
+
+    #[test]
+    fn root_folder_handles_login_folder() {
+        assert_eq!(root_folder("/volume1/backup/"), "/volume1/backup");
+        assert_eq!(root_folder("/~/backup/work"), "backup/work");
+        assert_eq!(root_folder("/~"), "");
+        assert_eq!(root_folder("/~/"), "");
+        assert_eq!(root_folder(""), "");
+        // ~user は他のユーザーのフォルダとして展開しない
+        assert_eq!(root_folder("/~user/backup"), "/~user/backup");
+    }
+
+    #[test]
+    fn remote_path_joins_root_and_relative_path() {
+        assert_eq!(remote_path("", Path::new("")), PathBuf::from("."));
+        assert_eq!(remote_path("", Path::new("a/b.txt")), PathBuf::from("a/b.txt"));
+        assert_eq!(remote_path("/volume1/backup", Path::new("")), PathBuf::from("/volume1/backup"));
+        assert_eq!(remote_path("/volume1/backup", &Path::new("a").join("b.txt")), PathBuf::from("/volume1/backup/a/b.txt"));
+        assert_eq!(remote_path("backup", Path::new("./a/b.txt")), PathBuf::from("backup/a/b.txt"));
+    }
+
+    /// Runs against the server of `FOLDER_SYNC_TEST_SFTP_URL` (for example `sftp://me@localhost/~/folder-sync-test`),
+    /// authenticating with `FOLDER_SYNC_TEST_SSH_KEY` or the SSH agent and default keys. The folder is deleted afterwards.
+    #[test]
+    #[ignore = "needs an OpenSSH server: set FOLDER_SYNC_TEST_SFTP_URL"]
+    fn round_trip_with_openssh_server() {
+        let Ok(url) = std::env::var("FOLDER_SYNC_TEST_SFTP_URL") else {
+            return;
+        };
+        let key = std::env::var("FOLDER_SYNC_TEST_SSH_KEY").ok();
+        let storage = SftpStorage::connect(&url, key.as_deref().map(Path::new)).unwrap();
+        exercise_storage(&storage);
+    }
+}
@@ -0,0 +1,222 @@
+//! # Shortcut Index Module
+//!
+//! This module writes an index of the Google Drive shortcut files (`.gdoc`, `.gsheet`, ...) of a backup job.
+//! The shortcut files only reference documents stored in Google Drive, so the index records the name, type,
+//! URL and document ID of each document as HTML and CSV files in the destination.
+
+// cspell:ignore gdoc gsheet gslides gdraw gform gmap gsite
+
+use crate::config::ShortcutIndexMode;
+use crate::filter::FileFilter;
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the HTML index.
+pub const INDEX_HTML: &str = "_google_shortcuts.html";
+/// File name of the CSV index.
+pub const INDEX_CSV: &str = "_google_shortcuts.csv";
+
+/// Google Drive shortcut types: extension, type name and URL prefix of the document.
+const SHORTCUT_TYPES: [(&str, &str, &str); 7] = [
+    ("gdoc", "Document", "https://docs.google.com/document/d/"),
+    ("gsheet", "Spreadsheet", "https://docs.google.com/spreadsheets/d/"),
+    ("gslides", "Presentation", "https://docs.google.com/presentation/d/"),
+    ("gdraw", "Drawing", "https://docs.google.com/drawings/d/"),
+    ("gform", "Form", "https://docs.google.com/forms/d/"),
+    ("gmap", "My Map", "https://www.google.com/maps/d/edit?mid="),
+    ("gsite", "Site", "https://sites.google.com/d/"),
+];
+
+/// A document referenced by a shortcut file.
+struct ShortcutEntry {
+    /// Path of the shortcut file relative to the job's source folder.
+    relative: PathBuf,
+    /// Document name (file name without the extension).
+    name: String,
+    /// Document type.
+    kind: &'static str,
+    /// Document URL, empty if unknown.
+    url: String,
+    /// Document ID, empty if unknown.
+    doc_id: String,
+}
+
+/// Writes the shortcut index of a backup job.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the job's source folder.
+/// * `destination` - A reference to the `Path` of the job's destination folder.
+/// * `filter` - A reference to the `FileFilter` of the backup job.
+/// * `mode` - Whether to write one index per directory or one index for the whole job.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if the index is written, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if reading a source directory fails.
+/// * Returns an error if writing an index file fails.
+pub fn write_shortcut_index(
+    source: &Path,
+    destination: &Path,
+    filter: &FileFilter,
+    mode: ShortcutIndexMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries = vec![];
+    collect_shortcuts(source, source, filter, &mut entries)?;
+
+    match mode {
+        ShortcutIndexMode::Job => {
+            if !entries.is_empty() {
+                write_index_files(destination, &entries, true)?;
+            }
+        }
+        ShortcutIndexMode::Directory => {
+            let mut by_directory: BTreeMap<PathBuf, Vec<ShortcutEntry>> = BTreeMap::new();
+            for entry in entries {
+                let directory = entry.relative.parent().map(Path::to_path_buf).unwrap_or_default();
+                by_directory.entry(directory).or_default().push(entry);
+            }
+            for (directory, entries) in by_directory {
+                write_index_files(&destination.join(directory), &entries, false)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively collects the shortcut files accepted by the filter.
+fn collect_shortcuts(
+    root: &Path,
+    path: &Path,
+    filter: &FileFilter,
+    entries: &mut Vec<ShortcutEntry>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(());
+    };
+
+    if metadata.is_dir() {
+        if filter.skips_dir(path, &metadata) {
+            return Ok(());
+        }
+        for entry in fs::read_dir(path)? {
+            collect_shortcuts(root, &entry?.path(), filter, entries)?;
+        }
+    } else if filter.accepts_file(path, &metadata) {
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if let Some(&(_, kind, url_prefix)) = SHORTCUT_TYPES.iter().find(|(e, _, _)| *e == extension) {
+            entries.push(read_shortcut(root, path, kind, url_prefix));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a shortcut file.
+///
+/// Shortcut files are JSON with `url`, `doc_id` and/or `resource_id` (`"document:<id>"`) members.
+/// If the file cannot be read (for example an online-only file), the entry is kept with an empty URL and ID.
+fn read_shortcut(root: &Path, path: &Path, kind: &'static str, url_prefix: &str) -> ShortcutEntry {
+    let json: Option<Value> = fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok());
+    if json.is_none() {
+        warn!("Failed to read shortcut file : {}", path.display());
+    }
+    let member = |key: &str| {
+        json.as_ref()
+            .and_then(|json| json.get(key))
+            .and_then(Value::as_str)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+
+    let doc_id = member("doc_id")
+        .or_else(|| member("resource_id").and_then(|id| id.split_once(':').map(|(_, id)| id.to_string())))
+        .unwrap_or_default();
+    let url = member("url").unwrap_or_else(|| {
+        if doc_id.is_empty() {
+            String::new()
+        } else {
+            format!("{}{}", url_prefix, doc_id)
+        }
+    });
+
+    ShortcutEntry {
+        relative: path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+        name: path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default(),
+        kind,
+        url,
+        doc_id,
+    }
+}
+
+/// Writes the HTML and CSV index files into a directory.
+///
+/// `with_path` adds the relative path of each shortcut, used when one index covers the whole job.
+fn write_index_files(directory: &Path, entries: &[ShortcutEntry], with_path: bool) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(directory)?;
+
+    // CSV (Excelで文字化けしないようにBOMを付与)
+    let mut csv = String::from("\u{feff}");
+    csv.push_str(if with_path { "path,name,type,url,doc_id\r\n" } else { "name,type,url,doc_id\r\n" });
+    for entry in entries {
+        let mut fields = vec![];
+        if with_path {
+            fields.push(entry.relative.to_string_lossy().into_owned());
+        }
+        fields.extend([entry.name.clone(), entry.kind.to_string(), entry.url.clone(), entry.doc_id.clone()]);
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&line.join(","));
+        csv.push_str("\r\n");
+    }
+    fs::write(directory.join(INDEX_CSV), csv)?;
+
+    // HTML
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Google Drive shortcuts</title>\n</head>\n<body>\n<table border=\"1\">\n<tr>");
+    if with_path {
+        html.push_str("<th>Path</th>");
+    }
+    html.push_str("<th>Name</th><th>Type</th><th>URL</th><th>Doc ID</th></tr>\n");
+    for entry in entries {
+        html.push_str("<tr>");
+        if with_path {
+            html.push_str(&format!("<td>{}</td>", html_escape(&entry.relative.to_string_lossy())));
+        }
+        let url = html_escape(&entry.url);
+        html.push_str(&format!(
+            "<td>{}</td><td>{}</td><td><a href=\"{}\">{}</a></td><td>{}</td></tr>\n",
+            html_escape(&entry.name), entry.kind, url, url, html_escape(&entry.doc_id)
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    fs::write(directory.join(INDEX_HTML), html)?;
+
+    info!("Wrote shortcut index: {}", directory.join(INDEX_HTML).display());
+    Ok(())
+}
+
+/// Quotes a CSV field when needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escapes text for HTML.
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
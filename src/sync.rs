@@ -0,0 +1,312 @@
+//! # Sync API Module
+//!
+//! This module is the library interface for running a backup from other programs.
+//! A [`SyncJob`] is built from a source, a destination and [`SyncOptions`], and [`SyncJob::run`]
+//! copies the files with the same rules as the `--backup-to-ssd` command and returns a [`SyncReport`].
+//! Progress is reported to an optional [`SyncObserver`].
+//!
+//! ```no_run
+//! use folder_sync_rs::sync::{SyncJob, SyncOptions};
+//!
+//! let report = SyncJob::new("C:\\work", "D:\\backup\\work")
+//!     .options(SyncOptions::new().exclude("node_modules").verify(true))
+//!     .run()?;
+//! println!("{} files copied", report.copied);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::backup::backup_to_ssd;
+use crate::config::{BtsConfig, BtsConfigWrapper, ByteSize, EncryptionConfig};
+use crate::filter::FileFilter;
+use crate::utils::{count_files_recursive, CopyOptions, CopyOutcome};
+use indicatif::ProgressBar;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Receives the progress of a sync.
+///
+/// Every method has an empty default implementation, so observers only implement what they need.
+pub trait SyncObserver: Send + Sync {
+    /// Called before copying with the number of files that will be processed.
+    fn started(&self, _total_files: u64) {}
+    /// Called after each file with what was done and the size of the source file.
+    fn file_processed(&self, _source: &Path, _outcome: CopyOutcome, _size: u64) {}
+    /// Called when the sync finishes successfully.
+    fn finished(&self, _report: &SyncReport) {}
+}
+
+/// A progress bar advances by one for each processed file.
+///
+/// Its length is not changed, so one bar can be shared by several jobs.
+impl SyncObserver for ProgressBar {
+    fn file_processed(&self, _source: &Path, _outcome: CopyOutcome, _size: u64) {
+        self.inc(1);
+    }
+}
+
+/// Result of a sync.
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// Number of files processed.
+    pub total: u64,
+    /// Number of files copied (or that would be copied in a dry run).
+    pub copied: u64,
+    /// Number of files skipped because they exist and overwriting is disabled.
+    pub skipped_existing: u64,
+    /// Number of files skipped because the destination has the same size and modification time.
+    pub skipped_unchanged: u64,
+    /// Number of placeholder files skipped because of their extension.
+    pub skipped_placeholders: u64,
+    /// Total size of the copied files in bytes.
+    pub bytes_copied: u64,
+    /// Time taken by the sync.
+    pub elapsed: Duration,
+}
+
+/// Options of a sync.
+///
+/// By default existing files are overwritten, nothing is excluded and Google Drive shortcut files are skipped,
+/// as for a backup job in the configuration file.
+#[derive(Clone)]
+pub struct SyncOptions {
+    config: BtsConfig,
+    skip_extensions: Vec<String>,
+    dry_run: bool,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            config: BtsConfig { overwrite: true, ..Default::default() },
+            skip_extensions: BtsConfigWrapper::default().skip_extensions,
+            dry_run: false,
+            cancel: None,
+        }
+    }
+}
+
+impl SyncOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether existing files are overwritten.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.config.overwrite = overwrite;
+        self
+    }
+
+    /// Sets whether each copied file is hashed and compared with its source.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.config.verify = verify;
+        self
+    }
+
+    /// Updates existing files of at least the given size in bytes in place, rewriting only the changed blocks (see `crate::delta`).
+    pub fn delta_threshold(mut self, bytes: u64) -> Self {
+        self.config.delta_threshold = Some(ByteSize(bytes));
+        self
+    }
+
+    /// Sets whether a manifest is written into the destination (see [`crate::manifest`]).
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.config.manifest = manifest;
+        self
+    }
+
+    /// Sets whether files are only counted in the report instead of being copied.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Adds an exclude pattern (see `crate::filter`).
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.config.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Adds an include pattern. When include patterns are given, only matching files are copied.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.config.include.push(pattern.to_string());
+        self
+    }
+
+    /// Sets whether hidden files and directories are skipped.
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.config.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Skips files smaller than the given size in bytes.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.config.min_size = Some(ByteSize(bytes));
+        self
+    }
+
+    /// Skips files larger than the given size in bytes.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.config.max_size = Some(ByteSize(bytes));
+        self
+    }
+
+    /// Replaces the extensions (without the dot) of skipped placeholder files.
+    pub fn skip_extensions(mut self, extensions: &[&str]) -> Self {
+        self.skip_extensions = extensions.iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    /// Sets a flag that stops the sync before the next file when set from another thread.
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+}
+
+/// A backup from a source folder to a destination folder.
+pub struct SyncJob {
+    config: BtsConfig,
+    skip_extensions: Vec<String>,
+    dry_run: bool,
+    cancel: Option<Arc<AtomicBool>>,
+    observer: Option<Arc<dyn SyncObserver>>,
+}
+
+impl SyncJob {
+    /// Creates a job copying `source` into `destination` with the default options.
+    pub fn new(source: impl AsRef<Path>, destination: impl AsRef<Path>) -> Self {
+        let options = SyncOptions::default();
+        SyncJob {
+            config: BtsConfig {
+                source: source.as_ref().to_string_lossy().into_owned(),
+                destination: destination.as_ref().to_string_lossy().into_owned(),
+                ..options.config
+            },
+            skip_extensions: options.skip_extensions,
+            dry_run: false,
+            cancel: None,
+            observer: None,
+        }
+    }
+
+    /// Creates a job from a backup job of the configuration file, with the global lists of the configuration.
+    pub fn from_config(config: &BtsConfig, bts_config_wrapper: &BtsConfigWrapper) -> Self {
+        let mut config = config.clone();
+        config.exclude = bts_config_wrapper.exclude.iter().chain(&config.exclude).cloned().collect();
+        SyncJob {
+            config,
+            skip_extensions: bts_config_wrapper.skip_extensions.clone(),
+            dry_run: false,
+            cancel: None,
+            observer: None,
+        }
+    }
+
+    /// Replaces the options of the job, keeping its source, destination, destination credentials and encryption.
+    pub fn options(mut self, options: SyncOptions) -> Self {
+        self.config = BtsConfig {
+            name: self.config.name,
+            source: self.config.source,
+            destination: self.config.destination,
+            destination_template: self.config.destination_template,
+            ssh_key: self.config.ssh_key,
+            s3_endpoint: self.config.s3_endpoint,
+            s3_region: self.config.s3_region,
+            remote_secret_file: self.config.remote_secret_file,
+            encryption: self.config.encryption,
+            ..options.config
+        };
+        self.skip_extensions = options.skip_extensions;
+        self.dry_run = options.dry_run;
+        self.cancel = options.cancel;
+        self
+    }
+
+    /// Encrypts the files written into the destination (see `crate::crypt`).
+    pub fn encryption(mut self, encryption: EncryptionConfig) -> Self {
+        self.config.encryption = Some(encryption);
+        self
+    }
+
+    /// Sets the observer notified of the progress.
+    pub fn observer(mut self, observer: Arc<dyn SyncObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Runs the job.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(SyncReport)` with the number of processed files, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if the source folder does not exist.
+    /// * Returns an error if a file cannot be copied or verification fails.
+    /// * Returns an error if the job is cancelled.
+    /// * Returns an error if writing the shortcut index or the manifest fails.
+    pub fn run(&self) -> Result<SyncReport, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let wrapper = BtsConfigWrapper {
+            configs: vec![],
+            exclude: vec![],
+            skip_extensions: self.skip_extensions.clone(),
+        };
+        let filter = FileFilter::new(&self.config, &wrapper);
+        let options = CopyOptions {
+            dry_run: self.dry_run,
+            cancel: self.cancel.clone(),
+            ..CopyOptions::new(&self.config)
+        };
+
+        if let Some(observer) = &self.observer {
+            let total = count_files_recursive(Path::new(&self.config.source), &filter).unwrap_or(0);
+            observer.started(total);
+        }
+
+        let recorder = Recorder {
+            report: Mutex::new(SyncReport::default()),
+            observer: self.observer.as_deref(),
+        };
+        backup_to_ssd(&self.config, &filter, &options, &recorder)?;
+
+        let mut report = recorder.report.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+        report.elapsed = start.elapsed();
+        if let Some(observer) = &self.observer {
+            observer.finished(&report);
+        }
+        Ok(report)
+    }
+}
+
+/// Observer collecting the report and forwarding the progress to the job's observer.
+struct Recorder<'a> {
+    report: Mutex<SyncReport>,
+    observer: Option<&'a dyn SyncObserver>,
+}
+
+impl SyncObserver for Recorder<'_> {
+    fn file_processed(&self, source: &Path, outcome: CopyOutcome, size: u64) {
+        {
+            let mut report = self.report.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            report.total += 1;
+            match outcome {
+                CopyOutcome::Copied => {
+                    report.copied += 1;
+                    report.bytes_copied += size;
+                }
+                CopyOutcome::SkippedExisting => report.skipped_existing += 1,
+                CopyOutcome::SkippedUnchanged => report.skipped_unchanged += 1,
+                CopyOutcome::SkippedPlaceholder => report.skipped_placeholders += 1,
+            }
+        }
+        if let Some(observer) = self.observer {
+            observer.file_processed(source, outcome, size);
+        }
+    }
+}
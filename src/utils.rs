@@ -1,24 +1,64 @@
 //! # File Utility Module
 //!
-//! This module provides utility functions for counting files and recursively copying files and directories.
+//! This module provides utility functions for counting files, walking directory trees and recursively copying files and directories.
 //! It utilizes multi-threading for efficient file counting and provides progress tracking during copying.
 
-use indicatif::ProgressBar;
-use log::info;
+use log::{info, warn};
+use std::fs::Metadata;
 use std::path::{Path,PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
 
-use crate::config::BtsConfig;
+use crate::config::{BtsConfig, BtsConfigWrapper};
+use crate::filter::{is_artifact, FileFilter};
+use crate::hash::hash_file;
+use crate::messages::*;
+use crate::storage::{same_time, Storage};
+use crate::sync::SyncObserver;
+
+/// Options controlling how files are copied.
+#[derive(Clone, Default)]
+pub struct CopyOptions {
+    /// Flag indicating whether to overwrite existing files.
+    pub overwrite: bool,
+    /// Flag indicating whether to hash the source and the destination after each copy and compare them.
+    pub verify: bool,
+    /// Size from which existing files are updated in place instead of being copied again, if the storage supports it.
+    pub delta_threshold: Option<u64>,
+    /// Flag indicating whether to only report what would be copied.
+    pub dry_run: bool,
+    /// Flag set by another thread to stop the copy before the next file.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl CopyOptions {
+    /// Creates the copy options of a backup job.
+    pub fn new(config: &BtsConfig) -> Self {
+        CopyOptions {
+            overwrite: config.overwrite,
+            verify: config.verify,
+            delta_threshold: config.delta_threshold.map(u64::from),
+            dry_run: false,
+            cancel: None,
+        }
+    }
+
+    /// Returns `true` if the copy was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+}
 
 /// Counts the total number of files in the specified configurations.
 ///
-/// This function spawns multiple threads to recursively count files in each source directory specified in the `bts_configs`.
+/// This function spawns multiple threads to recursively count files in each source directory specified in the `bts_config_wrapper`.
+/// Files are filtered in the same way as when copying them, so the count matches the progress of the backup.
 /// It uses a channel to collect the counts from each thread and returns the total count.
 ///
 /// # Arguments
 ///
-/// * `bts_configs` - A slice of `BtsConfig` structs containing source directory configurations.
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
 ///
 /// # Returns
 ///
@@ -27,15 +67,16 @@ use crate::config::BtsConfig;
 /// # Errors
 ///
 /// * Returns an error if any of the file counting threads fail.
-pub fn count_files(bts_configs: &[BtsConfig]) -> Result<u64, Box<dyn std::error::Error>> {
+pub fn count_files(bts_config_wrapper: &BtsConfigWrapper) -> Result<u64, Box<dyn std::error::Error>> {
     let (tx, rx) = mpsc::channel();
     let mut handles = vec![];
 
-    for config in bts_configs {
+    for config in &bts_config_wrapper.configs {
         let tx = tx.clone();
         let source_path = PathBuf::from(&config.source);
+        let filter = FileFilter::new(config, bts_config_wrapper);
         let handle = thread::spawn(move || {
-            let count = count_files_recursive(&source_path).unwrap_or(0);
+            let count = count_files_recursive(&source_path, &filter).unwrap_or(0);
             tx.send(count).unwrap();
         });
         handles.push(handle);
@@ -57,11 +98,12 @@ pub fn count_files(bts_configs: &[BtsConfig]) -> Result<u64, Box<dyn std::error:
 
 /// Recursively counts the number of files in a given path.
 ///
-/// This function recursively traverses the directory structure and counts the number of files.
+/// This function recursively traverses the directory structure and counts the number of files accepted by the filter.
 ///
 /// # Arguments
 ///
 /// * `path` - A reference to the `Path` to count files in.
+/// * `filter` - A reference to the `FileFilter` of the backup job.
 ///
 /// # Returns
 ///
@@ -70,15 +112,22 @@ pub fn count_files(bts_configs: &[BtsConfig]) -> Result<u64, Box<dyn std::error:
 /// # Errors
 ///
 /// * Returns an error if reading the directory fails.
-fn count_files_recursive(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+pub fn count_files_recursive(path: &Path, filter: &FileFilter) -> Result<u64, Box<dyn std::error::Error>> {
     let mut count = 0;
-    if path.is_dir() {
+    // リンク切れなど、コピー時にもスキップされるパスは数えない
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Ok(0);
+    };
+    if metadata.is_dir() {
+        if filter.skips_dir(path, &metadata) {
+            return Ok(0);
+        }
         for entry in std::fs::read_dir(path)? {
             let entry = entry?;
             let path = entry.path();
-            count += count_files_recursive(&path)?;
+            count += count_files_recursive(&path, filter)?;
         }
-    } else {
+    } else if filter.accepts_file(path, &metadata) {
         count += 1;
     }
     Ok(count)
@@ -86,16 +135,18 @@ fn count_files_recursive(path: &Path) -> Result<u64, Box<dyn std::error::Error>>
 
 /// Recursively copies files and directories from source to destination.
 ///
-/// This function recursively copies files and directories from the source path to the destination path.
-/// It supports excluding specified files or directories, overwriting existing files, and tracking progress using a progress bar.
+/// This function recursively copies files and directories from the source path to the destination path in a storage.
+/// It supports filtering files and directories, overwriting existing files, and reports each processed file to an observer.
+/// In a dry run, no directory is created.
 ///
 /// # Arguments
 ///
 /// * `source` - A reference to the `Path` of the source.
-/// * `destination` - A reference to the `Path` of the destination.
-/// * `overwrite` - A boolean indicating whether to overwrite existing files.
-/// * `exclude` - A slice of strings representing files or directories to exclude.
-/// * `progress_bar` - An `Arc<ProgressBar>` for tracking the copy progress.
+/// * `storage` - The `Storage` of the destination.
+/// * `destination` - A reference to the `Path` of the destination, relative to the root of the storage.
+/// * `options` - A reference to the `CopyOptions` of the backup job.
+/// * `filter` - A reference to the `FileFilter` deciding which files and directories are copied.
+/// * `observer` - The `SyncObserver` notified of each processed file, for example a progress bar.
 ///
 /// # Returns
 ///
@@ -107,36 +158,40 @@ fn count_files_recursive(path: &Path) -> Result<u64, Box<dyn std::error::Error>>
 /// * Returns an error if reading the directory fails.
 /// * Returns an error if file copying fails.
 /// * Returns an error if file metadata retrieval fails.
+/// * Returns an error if verification is enabled and the copy does not match the source.
+/// * Returns an error if the copy is cancelled.
 pub fn copy_recursive(
     source: &Path,
+    storage: &dyn Storage,
     destination: &Path,
-    overwrite: bool,
-    exclude: &[String],
-    progress_bar: &Arc<ProgressBar>
+    options: &CopyOptions,
+    filter: &FileFilter,
+    observer: &dyn SyncObserver,
 ) -> Result<(), Box<dyn std::error::Error>> {
 
 
+    if options.is_cancelled() {
+        return Err(ERR_CANCELLED.into());
+    }
+
     // デバッグログを追加
     info!("Processing: {}", source.display());
 
-    // Google ドキュメント,スプレッドシート,スライドのショートカットをスキップ
-    if let Some(extension) = source.extension().and_then(|s| s.to_str()){
-        match extension {
-            "gdoc" | "gsheet" | "gslides" => {
-                info!("Skipping Google shortcut file : {}", source.display());
-                progress_bar.inc(1);
-                return Ok(());
-            }
-            _ => {}
-        }
-    }
-
+    let Ok(source_metadata) = std::fs::metadata(source) else {
+        info!("Source path does not exist: {}", source.display());
+        return Ok(());
+    };
 
     // sourceがディレクトリの場合に先にdestinationディレクトリを作成
-    if source.is_dir() {
+    if source_metadata.is_dir() {
+        if filter.skips_dir(source, &source_metadata) {
+            info!("Skipping excluded path: {}", source.display());
+            return Ok(());
+        }
+
         // destinationディレクトリが存在しない場合は作成
-        if !destination.exists() {
-            std::fs::create_dir_all(destination)?;
+        if !options.dry_run {
+            storage.create_dir(destination)?;
         }
 
         // 子要素を再帰的に処理
@@ -144,44 +199,286 @@ pub fn copy_recursive(
             let entry = entry?;
             let path = entry.path();
             let destination = destination.join(entry.file_name());
-            copy_recursive(&path, &destination, overwrite, exclude, progress_bar)?;
+            copy_recursive(&path, storage, &destination, options, filter, observer)?;
         }
     } else { // sourceがファイルの場合
-        if exclude.iter().any(|e| source.ends_with(e)) {
+        if !filter.accepts_file(source, &source_metadata) {
             info!("Skipping excluded path: {}", source.display());
             return Ok(());
         }
 
-        if !source.exists() {
-            info!("Source path does not exist: {}", source.display());
+        // Google ドキュメント,スプレッドシート,スライドなどのショートカットをスキップ
+        if filter.skips_extension(source) {
+            info!("Skipping shortcut file : {}", source.display());
+            observer.file_processed(source, CopyOutcome::SkippedPlaceholder, source_metadata.len());
             return Ok(());
         }
 
-        if destination.exists() {
-            if !overwrite {
-                info!("Skipping existing file: {}", destination.display());
-                progress_bar.inc(1);
-                return Ok(());
-            }
+        let outcome = copy_file(source, &source_metadata, storage, destination, options)?;
+        observer.file_processed(source, outcome, source_metadata.len());
+    }
+    
+    Ok(())
+}
+
+/// Result of copying a single file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CopyOutcome {
+    /// The file was copied (or would be copied in a dry run).
+    Copied,
+    /// The destination exists and overwriting is disabled.
+    SkippedExisting,
+    /// The destination has the same size and modification time as the source.
+    SkippedUnchanged,
+    /// The file is a placeholder whose extension is skipped, such as a Google Drive shortcut.
+    SkippedPlaceholder,
+}
+
+/// Copies a single file unless the destination is up to date.
+///
+/// The modification time of the source is set on the copy, so unchanged files are detected on the next run.
+/// Existing files at least `delta_threshold` in size are updated in place if the storage supports it (see [`crate::delta`]),
+/// falling back to a full copy if the updated file does not match the source. With `verify`, both kinds of copy are verified.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the source file.
+/// * `source_metadata` - A reference to the metadata of the source file.
+/// * `storage` - The `Storage` of the destination.
+/// * `destination` - A reference to the `Path` of the destination file, relative to the root of the storage.
+/// * `options` - A reference to the `CopyOptions` controlling the copy.
+///
+/// # Returns
+///
+/// Returns `Ok(CopyOutcome)` describing what was done, or `Err(Box<dyn std::error::Error>)` if an error occurs.
+///
+/// # Errors
+///
+/// * Returns an error if file copying fails.
+/// * Returns an error if file metadata retrieval fails.
+/// * Returns an error if verification is enabled and the copy does not match the source.
+pub fn copy_file(
+    source: &Path,
+    source_metadata: &Metadata,
+    storage: &dyn Storage,
+    destination: &Path,
+    options: &CopyOptions,
+) -> Result<CopyOutcome, Box<dyn std::error::Error>> {
+    let mut delta = false;
+    if let Some(destination_metadata) = storage.stat(destination)? {
+        if !options.overwrite {
+            info!("Skipping existing file: {}", storage.display_path(destination));
+            return Ok(CopyOutcome::SkippedExisting);
+        }
+
+        let source_modified = source_metadata.modified()?;
+        if source_metadata.len() == destination_metadata.len
+            && destination_metadata.modified.is_some_and(|modified| same_time(modified, source_modified, storage.time_precision()))
+            && source.file_name() == destination.file_name()
+        {
+            info!("Skipping unchanged file: {}", storage.display_path(destination));
+            return Ok(CopyOutcome::SkippedUnchanged);
+        }
 
-            if let Ok(source_metadata) = std::fs::metadata(source) {
-                if let Ok(destination_metadata) = std::fs::metadata(&destination) {
-                    if source_metadata.len() == destination_metadata.len()
-                        && source_metadata.modified()? == destination_metadata.modified()?
-                        && source.file_name() == destination.file_name()
-                    {
-                        info!("Skipping unchanged file: {}", destination.display());
-                        progress_bar.inc(1);
-                        return Ok(());
-                    }
-                }
+        // 保存先がハッシュを記録していれば、更新日時だけが変わったファイルは内容を送らない
+        if source_metadata.len() == destination_metadata.len
+            && destination_metadata.hash.is_some_and(|hash| hash_file(source).is_ok_and(|source_hash| source_hash == hash))
+        {
+            if !options.dry_run {
+                storage.set_times(destination, source_modified)?;
             }
+            info!("Skipping unchanged contents: {}", storage.display_path(destination));
+            return Ok(CopyOutcome::SkippedUnchanged);
         }
-        
-        std::fs::copy(source, destination)?;
-        info!("Copied: {} to {}", source.display(), destination.display());
-        progress_bar.inc(1);
+
+        // 大きなファイルは既存のファイルの変更されたブロックだけを書き換える
+        delta = !destination_metadata.is_dir && options.delta_threshold.is_some_and(|threshold| source_metadata.len() >= threshold);
     }
-    
+
+    if options.dry_run {
+        return Ok(CopyOutcome::Copied);
+    }
+
+    let updated = delta && match storage.put_delta(source, destination) {
+        Ok(Some(report)) => {
+            info!("Updated: {} to {} ({} of {} blocks, {} bytes written)",
+                source.display(), storage.display_path(destination), report.changed_blocks, report.blocks, report.bytes_written);
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            warn!("Delta update failed, copying the whole file : {} : {}", storage.display_path(destination), e);
+            false
+        }
+    };
+    if !updated {
+        storage.put_file(source, destination)?;
+        info!("Copied: {} to {}", source.display(), storage.display_path(destination));
+    }
+    // 更新日時を合わせ、次回以降の変更なし判定に使う
+    storage.set_times(destination, source_metadata.modified()?)?;
+
+    if options.verify {
+        verify_copy(source, storage, destination)?;
+    }
+
+    Ok(CopyOutcome::Copied)
+}
+
+/// Verifies that a copied file has the same contents as its source by hashing both.
+///
+/// # Errors
+///
+/// * Returns an error if either file cannot be read.
+/// * Returns an error if the hashes differ.
+fn verify_copy(source: &Path, storage: &dyn Storage, destination: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if hash_file(source)? != storage.hash(destination)? {
+        return Err(ERR_VERIFY_FAILED.replace("{}", &storage.display_path(destination)).into());
+    }
+    info!("Verified: {}", storage.display_path(destination));
+    Ok(())
+}
+
+/// Visitor called by `walk_tree` with the relative path and metadata of each entry.
+pub type TreeVisitor<'a> = dyn FnMut(&Path, &Metadata) -> Result<(), Box<dyn std::error::Error>> + 'a;
+
+/// Recursively walks a directory tree with the same filtering as `copy_recursive`.
+///
+/// The visitor is called with the path relative to `root` and the metadata of every directory and file that would be copied.
+/// Directories are visited before their contents. Skipped placeholder files and files written by this tool are not visited.
+///
+/// # Arguments
+///
+/// * `root` - A reference to the `Path` of the tree to walk.
+/// * `filter` - A reference to the `FileFilter` whose root is `root`.
+/// * `visit` - The visitor called for each directory and file.
+///
+/// # Errors
+///
+/// * Returns an error if reading a directory fails.
+/// * Returns an error if the visitor returns an error.
+pub fn walk_tree(
+    root: &Path,
+    filter: &FileFilter,
+    visit: &mut TreeVisitor,
+) -> Result<(), Box<dyn std::error::Error>> {
+    walk_tree_recursive(root, root, filter, visit)
+}
+
+fn walk_tree_recursive(
+    root: &Path,
+    path: &Path,
+    filter: &FileFilter,
+    visit: &mut TreeVisitor,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Ok(());
+    };
+    let relative = path.strip_prefix(root).unwrap_or(path);
+
+    if metadata.is_dir() {
+        if filter.skips_dir(path, &metadata) {
+            return Ok(());
+        }
+        if !relative.as_os_str().is_empty() {
+            visit(relative, &metadata)?;
+        }
+        let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            walk_tree_recursive(root, &entry.path(), filter, visit)?;
+        }
+    } else if filter.accepts_file(path, &metadata) && !filter.skips_extension(path) && !is_artifact(path) {
+        visit(relative, &metadata)?;
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::DeltaReport;
+    use crate::storage::{LocalStorage, StorageEntry, StorageMetadata, StorageWriter};
+    use std::fs;
+    use std::io::{self, Read};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::SystemTime;
+
+    /// Local storage whose delta updates fail, counting the full copies.
+    struct FailingDelta {
+        inner: LocalStorage,
+        copies: AtomicUsize,
+    }
+
+    impl Storage for FailingDelta {
+        fn describe(&self) -> String {
+            self.inner.describe()
+        }
+        fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+            self.inner.list(path)
+        }
+        fn stat(&self, path: &Path) -> io::Result<Option<StorageMetadata>> {
+            self.inner.stat(path)
+        }
+        fn open_read(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+            self.inner.open_read(path)
+        }
+        fn create_write(&self, path: &Path) -> io::Result<Box<dyn StorageWriter + '_>> {
+            self.inner.create_write(path)
+        }
+        fn create_dir(&self, path: &Path) -> io::Result<()> {
+            self.inner.create_dir(path)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(from, to)
+        }
+        fn delete(&self, path: &Path) -> io::Result<()> {
+            self.inner.delete(path)
+        }
+        fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+            self.inner.set_times(path, modified)
+        }
+        fn put_file(&self, source: &Path, path: &Path) -> io::Result<()> {
+            self.copies.fetch_add(1, Ordering::SeqCst);
+            self.inner.put_file(source, path)
+        }
+        fn put_delta(&self, _source: &Path, _path: &Path) -> io::Result<Option<DeltaReport>> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "checksum mismatch"))
+        }
+    }
+
+    fn delta_options() -> CopyOptions {
+        CopyOptions { overwrite: true, verify: true, delta_threshold: Some(0), ..Default::default() }
+    }
+
+    /// Writes a source and an older, different destination, and returns the folder, the source and its metadata.
+    fn changed_file() -> (tempfile::TempDir, PathBuf, Metadata) {
+        let folder = tempfile::tempdir().unwrap();
+        fs::create_dir(folder.path().join("destination")).unwrap();
+        let source = folder.path().join("file.bin");
+        fs::write(&source, vec![1u8; 300_000]).unwrap();
+        fs::write(folder.path().join("destination/file.bin"), vec![2u8; 200_000]).unwrap();
+        filetime::set_file_mtime(folder.path().join("destination/file.bin"), filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        (folder, source, metadata)
+    }
+
+    #[test]
+    fn updates_large_files_in_place() {
+        let (folder, source, metadata) = changed_file();
+        let storage = LocalStorage::new(&folder.path().join("destination"));
+        let outcome = copy_file(&source, &metadata, &storage, Path::new("file.bin"), &delta_options()).unwrap();
+        assert!(matches!(outcome, CopyOutcome::Copied));
+        assert!(fs::read(folder.path().join("destination/file.bin")).unwrap() == fs::read(&source).unwrap());
+    }
+
+    #[test]
+    fn copies_whole_file_when_delta_update_fails() {
+        let (folder, source, metadata) = changed_file();
+        let storage = FailingDelta { inner: LocalStorage::new(&folder.path().join("destination")), copies: AtomicUsize::new(0) };
+        copy_file(&source, &metadata, &storage, Path::new("file.bin"), &delta_options()).unwrap();
+        assert_eq!(storage.copies.load(Ordering::SeqCst), 1);
+        assert!(fs::read(folder.path().join("destination/file.bin")).unwrap() == fs::read(&source).unwrap());
+    }
+}
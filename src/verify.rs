@@ -0,0 +1,238 @@
+//! # Verify Module
+//!
+//! This module compares the source and destination trees of backup jobs by hashing every file,
+//! and reports files whose contents differ, files missing from the destination and extra files in the destination.
+//! Encrypted destinations (see `crate::crypt`) are decrypted to be compared, and repository destinations
+//! (see [`crate::repository`]) are compared with their latest snapshot, reading every chunk.
+
+use crate::config::BtsConfigWrapper;
+use crate::filter::FileFilter;
+use crate::hash::hash_file;
+use crate::messages::*;
+use crate::repository::{open_snapshot, repository_path};
+use crate::storage::{open_storage, redact_url, walk_storage, Storage};
+use crate::utils::walk_tree;
+use log::{info, warn};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Differences found between a source and a destination tree.
+#[derive(Default)]
+pub struct VerifyReport {
+    /// Files present on both sides whose contents differ.
+    pub mismatched: Vec<PathBuf>,
+    /// Files present in the source but not in the destination.
+    pub missing: Vec<PathBuf>,
+    /// Files present in the destination but not in the source.
+    pub extra: Vec<PathBuf>,
+    /// Number of files whose contents match.
+    pub matched: u64,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no difference was found.
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Verifies the backup jobs and prints the differences.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `job` - The name of the job to verify, or `None` for every job.
+///
+/// # Returns
+///
+/// Returns `Ok(())` if every destination matches its source, or `Err(Box<dyn std::error::Error>)` otherwise.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found.
+/// * Returns an error if a tree cannot be read.
+/// * Returns an error if the destination is encrypted and its key does not match.
+/// * Returns an error if the destination is a repository without a snapshot of the job.
+/// * Returns an error if differences are found.
+pub fn execute_verify(bts_config_wrapper: &BtsConfigWrapper, job: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut clean = true;
+
+    for config in bts_config_wrapper.select(job)? {
+        let source = Path::new(&config.source);
+        let destination = Path::new(&config.destination);
+        println!("{}", MSG_VERIFYING.replace("{}", &format!("{} -> {}", config.source, redact_url(&config.destination))));
+
+        if !source.exists() {
+            return Err(ERR_SOURCE_FOLDER_NOT_EXIST.replace("{}", &config.source).into());
+        }
+
+        let filter = FileFilter::new(config, bts_config_wrapper);
+        let report = if repository_path(&config.destination).is_some() {
+            verify_storage(source, &open_snapshot(config, None)?, &filter)?
+        } else if config.encryption.is_some() {
+            verify_storage(source, &*open_storage(config)?, &filter)?
+        } else {
+            verify_trees(source, destination, &filter)?
+        };
+        print_report(&report);
+        clean &= report.is_clean();
+    }
+
+    if clean {
+        Ok(())
+    } else {
+        Err(ERR_VERIFY_PROBLEMS.into())
+    }
+}
+
+/// Compares a source tree with a destination tree by hashing every file.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the source tree.
+/// * `destination` - A reference to the `Path` of the destination tree.
+/// * `filter` - A reference to the `FileFilter` of the job, applied to the source tree and, without its size and time filters, to the destination tree.
+///
+/// # Errors
+///
+/// * Returns an error if a tree cannot be walked.
+pub fn verify_trees(source: &Path, destination: &Path, filter: &FileFilter) -> Result<VerifyReport, Box<dyn std::error::Error>> {
+    let source_files = list_files(source, filter)?;
+    let destination_files = if destination.exists() {
+        list_files(destination, &filter.for_destination(destination))?
+    } else {
+        BTreeSet::new()
+    };
+
+    let report = compare_files(&source_files, &destination_files, |relative| {
+        Ok((hash_file(&source.join(relative))?, hash_file(&destination.join(relative))?))
+    });
+    info!("Verified {} : {} matched, {} mismatched, {} missing, {} extra",
+        destination.display(), report.matched, report.mismatched.len(), report.missing.len(), report.extra.len());
+    Ok(report)
+}
+
+/// Compares a source tree with the files of a storage, such as an encrypted destination, by hashing every file.
+///
+/// Files of the storage are read back in full, so an encrypted destination is also checked to decrypt.
+///
+/// # Arguments
+///
+/// * `source` - A reference to the `Path` of the source tree.
+/// * `storage` - The `Storage` of the destination.
+/// * `filter` - A reference to the `FileFilter` of the job, applied to the source tree.
+///
+/// # Errors
+///
+/// * Returns an error if the source tree or the storage cannot be walked.
+pub fn verify_storage(source: &Path, storage: &dyn Storage, filter: &FileFilter) -> Result<VerifyReport, Box<dyn std::error::Error>> {
+    let source_files = list_files(source, filter)?;
+    let mut destination_files = BTreeSet::new();
+    if storage.stat(Path::new(""))?.is_some() {
+        walk_storage(storage, Path::new(""), &mut |relative, metadata| {
+            if !metadata.is_dir {
+                destination_files.insert(relative.to_path_buf());
+            }
+            Ok(())
+        })?;
+    }
+
+    let report = compare_files(&source_files, &destination_files, |relative| {
+        Ok((hash_file(&source.join(relative))?, storage.hash(relative)?))
+    });
+    info!("Verified {} : {} matched, {} mismatched, {} missing, {} extra",
+        storage.describe(), report.matched, report.mismatched.len(), report.missing.len(), report.extra.len());
+    Ok(report)
+}
+
+/// Compares the files present on both sides with `hash`, which returns the source and destination hashes of a file.
+fn compare_files(
+    source_files: &BTreeSet<PathBuf>,
+    destination_files: &BTreeSet<PathBuf>,
+    hash: impl Fn(&Path) -> io::Result<(String, String)>,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for relative in source_files {
+        if !destination_files.contains(relative) {
+            report.missing.push(relative.clone());
+            continue;
+        }
+        match hash(relative) {
+            Ok((a, b)) if a == b => report.matched += 1,
+            Ok(_) => report.mismatched.push(relative.clone()),
+            Err(e) => {
+                warn!("Failed to hash {} : {}", relative.display(), e);
+                report.mismatched.push(relative.clone());
+            }
+        }
+    }
+    report.extra = destination_files.difference(source_files).cloned().collect();
+    report
+}
+
+/// Lists the relative paths of the files of a tree.
+fn list_files(root: &Path, filter: &FileFilter) -> Result<BTreeSet<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = BTreeSet::new();
+    walk_tree(root, filter, &mut |relative, metadata| {
+        if metadata.is_file() {
+            files.insert(relative.to_path_buf());
+        }
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+/// Prints the differences of a report.
+fn print_report(report: &VerifyReport) {
+    for path in &report.mismatched {
+        println!("  MISMATCH {}", path.display());
+    }
+    for path in &report.missing {
+        println!("  MISSING  {}", path.display());
+    }
+    for path in &report.extra {
+        println!("  EXTRA    {}", path.display());
+    }
+    if report.is_clean() {
+        println!("  {}", MSG_VERIFY_OK.replace("{}", &report.matched.to_string()));
+    } else {
+        let summary = MSG_VERIFY_SUMMARY
+            .replacen("{}", &report.mismatched.len().to_string(), 1)
+            .replacen("{}", &report.missing.len().to_string(), 1)
+            .replacen("{}", &report.extra.len().to_string(), 1);
+        println!("  {}", summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BtsConfig;
+    use filetime::FileTime;
+    use std::fs;
+
+    #[test]
+    fn destination_is_filtered_by_patterns_only() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("a.txt"), "a").unwrap();
+        fs::write(destination.path().join("a.txt"), "a").unwrap();
+        fs::write(destination.path().join("b.tmp"), "b").unwrap();
+        // 更新日時を引き継がないコピーでも、バックアップ先は時刻で絞り込まない
+        filetime::set_file_mtime(destination.path().join("a.txt"), FileTime::from_unix_time(946_684_800, 0)).unwrap();
+
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": source.path(),
+            "destination": destination.path(),
+            "overwrite": true,
+            "exclude": ["*.tmp"],
+            "modified_after": "7d",
+        })).unwrap();
+        let filter = FileFilter::new(&config, &BtsConfigWrapper::default());
+        let report = verify_trees(source.path(), destination.path(), &filter).unwrap();
+
+        assert!(report.is_clean(), "missing {:?}, extra {:?}", report.missing, report.extra);
+        assert_eq!(report.matched, 1);
+    }
+}
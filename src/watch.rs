@@ -0,0 +1,363 @@
+//! # Watch Module
+//!
+//! This module keeps backup destinations up to date while the program is running.
+//! After an initial backup, changes in the source folders are received as filesystem notifications
+//! (see the `notify` crate), debounced, and only the changed files and folders are copied.
+//! Jobs that cannot be watched, for example because the OS watch limit is reached, are rescanned periodically instead.
+//! Jobs writing into an archive are only backed up once at the start.
+//!
+//! Like the backup, watching never deletes files from destinations.
+
+use crate::archive::ArchiveFormat;
+use crate::backup::{execute_backup, write_job_indexes};
+use crate::commands::WatchArgs;
+use crate::config::{BtsConfig, BtsConfigWrapper};
+use crate::filter::FileFilter;
+use crate::messages::*;
+use crate::repository::repository_path;
+use crate::storage::{open_storage, Storage};
+use crate::utils::{copy_recursive, CopyOptions};
+use indicatif::ProgressBar;
+use log::{error, info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Interval at which the event loop checks the debounce and rescan timers.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Pending changes are synced at the latest after this many debounce periods, even if events keep arriving.
+const MAX_DEBOUNCE_PERIODS: u32 = 10;
+
+/// A backup job being watched.
+struct WatchedJob {
+    /// Configuration of the job.
+    config: BtsConfig,
+    /// Storage of the destination.
+    storage: Box<dyn Storage>,
+    /// Canonical source folder, the prefix of the paths reported by the watcher.
+    root: PathBuf,
+    /// Filter of the job, rebased onto `root`.
+    filter: FileFilter,
+    /// Filter of the job relative to the configured source, used for the indexes.
+    job_filter: FileFilter,
+    /// `true` if the job is rescanned periodically instead of relying on notifications.
+    polling: bool,
+    /// Time of the last full scan.
+    last_scan: Instant,
+}
+
+/// Runs an initial backup, then copies changed files of the selected jobs until the program is stopped.
+///
+/// # Arguments
+///
+/// * `bts_config_wrapper` - A reference to the `BtsConfigWrapper` struct containing backup configurations.
+/// * `args` - A reference to the `WatchArgs` given on the command line.
+///
+/// # Errors
+///
+/// * Returns an error if the job is not found.
+/// * Returns an error if the initial backup fails.
+/// * Returns an error if the watcher cannot be created or stops unexpectedly.
+pub fn execute_watch(bts_config_wrapper: &BtsConfigWrapper, args: &WatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut selected = bts_config_wrapper.clone();
+    selected.configs = bts_config_wrapper.select(args.job.as_deref())?.into_iter().cloned().collect();
+    execute_backup(&selected)?;
+
+    let debounce = Duration::from_secs(args.debounce);
+    let rescan = Duration::from_secs(args.rescan);
+
+    let mut jobs: Vec<WatchedJob> = selected.configs.iter()
+        .filter(|config| {
+            // アーカイブは毎回全体を書き込むため、変更されたファイルだけを追加できない
+            let archive = ArchiveFormat::of(&config.destination).is_some();
+            if archive {
+                warn!("Archive destinations are not watched : {}", config.destination);
+            }
+            // リポジトリはスナップショット単位で書き込むため、変更ごとに追記できない
+            let repository = repository_path(&config.destination).is_some();
+            if repository {
+                warn!("Repository destinations are not watched : {}", config.destination);
+            }
+            !archive && !repository
+        })
+        .map(|config| -> Result<WatchedJob, Box<dyn std::error::Error>> {
+            let source = PathBuf::from(&config.source);
+            let root = fs::canonicalize(&source).unwrap_or(source);
+            let job_filter = FileFilter::new(config, &selected);
+            Ok(WatchedJob {
+                config: config.clone(),
+                storage: open_storage(config)?,
+                filter: job_filter.rebase(&root),
+                root,
+                job_filter,
+                polling: false,
+                last_scan: Instant::now(),
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for job in &mut jobs {
+        match watcher.watch(&job.root, RecursiveMode::Recursive) {
+            Ok(()) => println!("{}", MSG_WATCHING.replace("{}", &job.config.source)),
+            Err(e) => {
+                warn!("Failed to watch {} : {}", job.root.display(), e);
+                job.polling = true;
+                println!("{}", MSG_WATCH_POLLING.replace("{}", &job.config.source));
+            }
+        }
+    }
+
+    let mut pending = PendingChanges::default();
+
+    loop {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => {
+                if event.need_rescan() {
+                    // イベントが溢れた場合は変更を取りこぼしているため全体を再走査する
+                    warn!("Watcher requested a rescan");
+                    for job in &mut jobs {
+                        scan_job(job);
+                    }
+                    continue;
+                }
+                pending.add(event, Instant::now());
+            }
+            Ok(Err(e)) => {
+                if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) {
+                    // 監視数の上限に達したジョブは定期的な再走査に切り替える
+                    for job in &mut jobs {
+                        let affected = e.paths.is_empty() || e.paths.iter().any(|path| path.starts_with(&job.root));
+                        if affected && !job.polling {
+                            job.polling = true;
+                            println!("{}", MSG_WATCH_POLLING.replace("{}", &job.config.source));
+                        }
+                    }
+                }
+                warn!("Watch error: {}", e);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err(ERR_WATCHER_STOPPED.into()),
+        }
+
+        if let Some(paths) = pending.take_due(Instant::now(), debounce) {
+            sync_changes(&jobs, &paths);
+        }
+
+        for job in jobs.iter_mut().filter(|job| job.polling && job.last_scan.elapsed() >= rescan) {
+            scan_job(job);
+        }
+    }
+}
+
+/// Changed paths received from the watcher and not synced yet.
+#[derive(Default)]
+struct PendingChanges {
+    paths: BTreeSet<PathBuf>,
+    /// Times of the first and the last event since the last sync, `None` if nothing is pending.
+    events: Option<(Instant, Instant)>,
+}
+
+impl PendingChanges {
+    /// Adds the paths of an event received at `now`.
+    fn add(&mut self, event: notify::Event, now: Instant) {
+        // コピー時の読み込みでも通知されるため、アクセスのイベントは無視する
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        self.paths.extend(event.paths);
+        let first = self.events.map_or(now, |(first, _)| first);
+        self.events = Some((first, now));
+    }
+
+    /// Returns the pending paths once no event arrived for `debounce`,
+    /// or `MAX_DEBOUNCE_PERIODS` debounce periods after the first event if events keep arriving.
+    fn take_due(&mut self, now: Instant, debounce: Duration) -> Option<BTreeSet<PathBuf>> {
+        let (first, last) = self.events?;
+        let quiet = now.duration_since(last) >= debounce;
+        let overdue = now.duration_since(first) >= debounce * MAX_DEBOUNCE_PERIODS;
+        if !(quiet || overdue) {
+            return None;
+        }
+        self.events = None;
+        Some(std::mem::take(&mut self.paths))
+    }
+}
+
+/// Copies the changed paths into the destinations of their jobs.
+///
+/// Paths inside another changed folder are copied with that folder. Paths that no longer exist are ignored.
+fn sync_changes(jobs: &[WatchedJob], paths: &BTreeSet<PathBuf>) {
+    let progress_bar = Arc::new(ProgressBar::hidden());
+
+    for job in jobs {
+        // 整列済みなので、親フォルダが含まれていれば子より先に現れる
+        let mut synced: Vec<&Path> = vec![];
+        for path in paths.iter().filter(|path| path.starts_with(&job.root)) {
+            if synced.iter().any(|parent| path.starts_with(parent)) {
+                continue;
+            }
+            match sync_path(job, path, &progress_bar) {
+                Ok(true) => synced.push(path),
+                Ok(false) => {}
+                Err(e) => error!("Failed to sync {} : {}", path.display(), e),
+            }
+        }
+        if synced.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = job.storage.flush() {
+            error!("Failed to flush {} : {}", job.config.destination, e);
+        }
+        if let Err(e) = write_job_indexes(&job.config, &job.job_filter) {
+            error!("Failed to write indexes of {} : {}", job.config.source, e);
+        }
+        let message = MSG_WATCH_SYNCED
+            .replacen("{}", &job.config.source, 1)
+            .replacen("{}", &synced.len().to_string(), 1);
+        info!("{}", message);
+        println!("{}", message);
+    }
+}
+
+/// Copies a changed file or folder into the destination of its job.
+///
+/// Returns `Ok(false)` if the path no longer exists or is skipped by the job's filter.
+fn sync_path(job: &WatchedJob, path: &Path, progress_bar: &Arc<ProgressBar>) -> Result<bool, Box<dyn std::error::Error>> {
+    let Ok(relative) = path.strip_prefix(&job.root) else {
+        return Ok(false);
+    };
+    if relative.as_os_str().is_empty() || !path.exists() {
+        return Ok(false);
+    }
+
+    // 除外されたフォルダの中の変更は無視する
+    for ancestor in path.ancestors().skip(1).take_while(|ancestor| ancestor.starts_with(&job.root)) {
+        if let Ok(metadata) = fs::metadata(ancestor) {
+            if job.filter.skips_dir(ancestor, &metadata) {
+                return Ok(false);
+            }
+        }
+    }
+
+    if let Some(parent) = relative.parent() {
+        job.storage.create_dir(parent)?;
+    }
+    copy_recursive(path, &*job.storage, relative, &CopyOptions::new(&job.config), &job.filter, &**progress_bar)?;
+    Ok(true)
+}
+
+/// Copies every changed file of a job, as the backup does.
+fn scan_job(job: &mut WatchedJob) {
+    info!("Rescanning {}", job.config.source);
+    let progress_bar = Arc::new(ProgressBar::hidden());
+    let result = copy_recursive(&job.root, &*job.storage, Path::new(""), &CopyOptions::new(&job.config), &job.filter, &*progress_bar)
+        .and_then(|_| Ok(job.storage.flush()?))
+        .and_then(|_| write_job_indexes(&job.config, &job.job_filter));
+    if let Err(e) = result {
+        error!("Failed to rescan {} : {}", job.config.source, e);
+    }
+    job.last_scan = Instant::now();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, ModifyKind};
+    use notify::Event;
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| event.add_path(PathBuf::from(path)))
+    }
+
+    fn paths(paths: &[&str]) -> BTreeSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn debounces_events() {
+        let debounce = Duration::from_secs(2);
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut pending = PendingChanges::default();
+        assert_eq!(pending.take_due(at(0), debounce), None);
+
+        // アクセスだけでは何も同期しない
+        pending.add(event(EventKind::Access(AccessKind::Any), &["/src/read.txt"]), at(0));
+        assert_eq!(pending.take_due(at(5000), debounce), None);
+
+        // 変更が途切れてから debounce 後に、まとめて返す
+        pending.add(event(EventKind::Create(CreateKind::File), &["/src/a.txt"]), at(0));
+        pending.add(event(EventKind::Modify(ModifyKind::Any), &["/src/a.txt", "/src/b.txt"]), at(1500));
+        assert_eq!(pending.take_due(at(3000), debounce), None);
+        assert_eq!(pending.take_due(at(3500), debounce), Some(paths(&["/src/a.txt", "/src/b.txt"])));
+        assert_eq!(pending.take_due(at(10000), debounce), None);
+
+        // 変更が続いても、最初の変更から MAX_DEBOUNCE_PERIODS 回分の時間が経てば返す
+        let mut time = 20000;
+        pending.add(event(EventKind::Modify(ModifyKind::Any), &["/src/log.txt"]), at(time));
+        while pending.take_due(at(time), debounce).is_none() {
+            time += 1000;
+            pending.add(event(EventKind::Modify(ModifyKind::Any), &["/src/log.txt"]), at(time));
+        }
+        assert_eq!(Duration::from_millis(time - 20000), debounce * MAX_DEBOUNCE_PERIODS);
+    }
+
+    #[test]
+    fn syncs_changed_paths_through_filter() {
+        let folder = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(folder.path()).unwrap();
+        let (source, destination) = (root.join("source"), root.join("destination"));
+        for path in ["new/a.txt", "new/sub/b.txt", "changed.txt", "skip.tmp", "cache/c.txt", "untouched.txt"] {
+            fs::create_dir_all(source.join(path).parent().unwrap()).unwrap();
+            fs::write(source.join(path), path).unwrap();
+        }
+        fs::create_dir(&destination).unwrap();
+        let config: BtsConfig = serde_json::from_value(serde_json::json!({
+            "source": source.display().to_string(),
+            "destination": destination.display().to_string(),
+            "overwrite": true,
+            "exclude": ["*.tmp", "cache/"],
+        })).unwrap();
+        let job_filter = FileFilter::new(&config, &BtsConfigWrapper::default());
+        let job = WatchedJob {
+            storage: open_storage(&config).unwrap(),
+            filter: job_filter.rebase(&source),
+            root: source.clone(),
+            job_filter,
+            config,
+            polling: false,
+            last_scan: Instant::now(),
+        };
+
+        let mut pending = PendingChanges::default();
+        let now = Instant::now();
+        for path in ["new", "new/sub/b.txt", "changed.txt", "skip.tmp", "cache/c.txt", "deleted.txt"] {
+            pending.add(event(EventKind::Modify(ModifyKind::Any), &[&source.join(path).display().to_string()]), now);
+        }
+        // 他のフォルダの変更は無視する
+        pending.add(event(EventKind::Modify(ModifyKind::Any), &[&root.join("elsewhere.txt").display().to_string()]), now);
+        let changes = pending.take_due(now + Duration::from_secs(1), Duration::from_secs(1)).unwrap();
+        sync_changes(std::slice::from_ref(&job), &changes);
+
+        for (path, copied) in [
+            ("new/a.txt", true),
+            ("new/sub/b.txt", true),
+            ("changed.txt", true),
+            ("skip.tmp", false),
+            ("cache", false),
+            ("untouched.txt", false),
+            ("deleted.txt", false),
+        ] {
+            assert_eq!(destination.join(path).exists(), copied, "{}", path);
+        }
+        assert_eq!(fs::read_to_string(destination.join("new/sub/b.txt")).unwrap(), "new/sub/b.txt");
+    }
+}
@@ -0,0 +1,101 @@
+//! # XML Module
+//!
+//! This module provides the few XML helpers needed to read the responses of the S3 and WebDAV APIs,
+//! which avoids depending on an XML parser.
+//!
+//! Elements are matched by their local name, so `<D:href>` and `<href xmlns="DAV:">` are both found as `href`.
+
+/// Returns the contents of every `tag` element of an XML document.
+///
+/// Text contents are returned with entities decoded, and contents with child elements as they are,
+/// so they can be searched again. Self-closing elements have empty contents.
+/// Elements of the same name must not be nested.
+///
+/// # Arguments
+///
+/// * `xml` - The XML document or fragment.
+/// * `tag` - The local name of the elements, without a namespace prefix.
+pub fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let mut values = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('>') else {
+            break;
+        };
+        let element = &after[..end];
+        rest = &after[end + 1..];
+        // 終了タグ、宣言、コメントは読み飛ばす
+        if element.starts_with(['/', '?', '!']) || local_name(element) != tag {
+            continue;
+        }
+        if element.ends_with('/') {
+            values.push(String::new());
+            continue;
+        }
+        let Some((contents, after_close)) = split_at_close(rest, tag) else {
+            break;
+        };
+        values.push(if contents.contains('<') { contents.to_string() } else { xml_unescape(contents) });
+        rest = after_close;
+    }
+    values
+}
+
+/// Returns the local name of the element of a tag (the text between `<` and `>`).
+fn local_name(element: &str) -> &str {
+    let name = element.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// Splits a document at the first closing tag of `tag`, returning the text before it and the text after it.
+fn split_at_close<'a>(xml: &'a str, tag: &str) -> Option<(&'a str, &'a str)> {
+    let mut offset = 0;
+    while let Some(start) = xml[offset..].find("</") {
+        let start = offset + start;
+        let end = start + xml[start..].find('>')?;
+        if local_name(&xml[start + 2..end]) == tag {
+            return Some((&xml[..start], &xml[end + 1..]));
+        }
+        offset = end;
+    }
+    None
+}
+
+/// Escapes the characters of a text that have a meaning in XML.
+pub fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Decodes the predefined XML entities and numeric character references.
+pub fn xml_unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        result.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let Some(end) = after.find(';') else {
+            result.push_str(&rest[pos..]);
+            return result;
+        };
+        let entity = &after[..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x").map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => result.push(c),
+            None => result.push_str(&rest[pos..pos + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}